@@ -0,0 +1,194 @@
+use thiserror::Error;
+
+/// Errors returned when data doesn't fit where it is being packed
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum PackError {
+    /// A value needs more bits than its field has
+    #[error("{name}: value {value} does not fit in {width} bits")]
+    ValueTooWide {
+        name: String,
+        value: u64,
+        width: usize,
+    },
+    /// A single field wider than the 64 bits a value can hold
+    #[error("{name}: {width} bit fields are not supported, the limit is 64")]
+    FieldTooWide { name: String, width: usize },
+    /// More bits were written than the section or message has room for
+    #[error("{name}: needs {needed} bits but only {available} are available")]
+    Overflow {
+        name: String,
+        needed: usize,
+        available: usize,
+    },
+    /// Byte order was set on a field that isn't a whole number of bytes
+    #[error("{name}: byte order needs a multiple of 8 bits, field is {width} bits")]
+    UnalignedByteOrder { name: String, width: usize },
+    /// Byte order was set on a field that doesn't start on a byte boundary
+    #[error(
+        "{name}: byte order needs the field to start on a byte boundary, it starts at bit {offset}"
+    )]
+    MisalignedByteOrder { name: String, offset: usize },
+    /// Constraints and holes of a field leave no value to generate
+    #[error("{name}: no value can be generated, {reason}")]
+    NoValues { name: String, reason: String },
+    /// A message was packed from a different number of values than it has fields
+    #[error("{name}: has {expected} fields but {given} values were given")]
+    ValueCount {
+        name: String,
+        expected: usize,
+        given: usize,
+    },
+}
+
+/// Bit vector written most significant bit first
+///
+/// Fields of any width are appended one after another, so a 12-bit field
+/// followed by a 4-bit field fills exactly two bytes. The writer has a fixed
+/// capacity and returns an error instead of silently dropping bits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BitWriter {
+    name: String,
+    bytes: Vec<u8>,
+    len: usize,
+    capacity: usize,
+}
+
+impl BitWriter {
+    /// Returns an empty writer able to hold `capacity` bits
+    /// `name` is used to say where the data didn't fit in errors
+    pub fn new(name: &str, capacity: usize) -> Self {
+        Self {
+            name: name.to_owned(),
+            bytes: Vec::with_capacity(capacity.div_ceil(8)),
+            len: 0,
+            capacity,
+        }
+    }
+
+    /// Number of bits written so far
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Bits left before the writer is full
+    pub fn remaining(&self) -> usize {
+        self.capacity - self.len
+    }
+
+    /// Append the low `width` bits of `value`, most significant bit first
+    pub fn push(&mut self, name: &str, value: u64, width: usize) -> Result<(), PackError> {
+        if width > 64 {
+            return Err(PackError::FieldTooWide {
+                name: name.to_owned(),
+                width,
+            });
+        }
+        if width < 64 && value >> width != 0 {
+            return Err(PackError::ValueTooWide {
+                name: name.to_owned(),
+                value,
+                width,
+            });
+        }
+        self.reserve(width)?;
+        for bit in (0..width).rev() {
+            self.push_bit((value >> bit) & 1 == 1);
+        }
+        Ok(())
+    }
+
+    /// Append whole bytes, they don't need to start on a byte boundary
+    pub fn push_bytes(&mut self, bytes: &[u8]) -> Result<(), PackError> {
+        self.reserve(bytes.len() * 8)?;
+        for byte in bytes {
+            for bit in (0..8).rev() {
+                self.push_bit((byte >> bit) & 1 == 1);
+            }
+        }
+        Ok(())
+    }
+
+    /// Append `width` zero bits
+    pub fn pad(&mut self, width: usize) -> Result<(), PackError> {
+        self.reserve(width)?;
+        for _ in 0..width {
+            self.push_bit(false);
+        }
+        Ok(())
+    }
+
+    /// Returns the written bits, the last byte is zero padded if needed
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    fn reserve(&self, width: usize) -> Result<(), PackError> {
+        if width > self.remaining() {
+            return Err(PackError::Overflow {
+                name: self.name.clone(),
+                needed: self.len + width,
+                available: self.capacity,
+            });
+        }
+        Ok(())
+    }
+
+    fn push_bit(&mut self, bit: bool) {
+        if self.len.is_multiple_of(8) {
+            self.bytes.push(0);
+        }
+        if bit {
+            let last = self.bytes.len() - 1;
+            self.bytes[last] |= 0x80 >> (self.len % 8);
+        }
+        self.len += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_packs_fields_across_byte_boundaries() {
+        let mut writer = BitWriter::new("msg", 64);
+        writer.push("a", 0xABC, 12).unwrap();
+        writer.push("b", 0x5, 4).unwrap();
+        writer.push("c", 0xDEAD_BEEF, 32).unwrap();
+        writer.push_bytes(&[0x12]).unwrap();
+        writer.pad(8).unwrap();
+        assert_eq!(writer.remaining(), 0);
+        assert_eq!(
+            writer.into_bytes(),
+            vec![0xAB, 0xC5, 0xDE, 0xAD, 0xBE, 0xEF, 0x12, 0x00]
+        );
+    }
+
+    #[test]
+    fn it_errors_instead_of_overflowing() {
+        let mut writer = BitWriter::new("sec", 8);
+        assert!(matches!(
+            writer.push("a", 0x10, 4),
+            Err(PackError::ValueTooWide { .. })
+        ));
+        assert!(matches!(
+            writer.push("a", 0, 65),
+            Err(PackError::FieldTooWide { .. })
+        ));
+        writer.push("a", 0x1, 4).unwrap();
+        assert_eq!(
+            writer.push("b", 0x1, 5),
+            Err(PackError::Overflow {
+                name: String::from("sec"),
+                needed: 9,
+                available: 8
+            })
+        );
+        writer.push("b", 0xF, 4).unwrap();
+        assert_eq!(writer.into_bytes(), vec![0x1F]);
+    }
+}
@@ -1,56 +1,369 @@
-use std::io::{self, Write};
-use std::process::Command;
-
-/// Create a vcan bus using the following commands:
-/// sudo ip link add dev <name> type vcan
-/// sudo ip link set up <name>
-/// This function will panic if errors are returned
-pub fn create_bus(name: &str) {
-    let output = Command::new("sudo")
-        .arg("ip")
-        .arg("link")
-        .arg("add")
-        .arg("dev")
-        .arg(name)
-        .arg("type")
-        .arg("vcan")
-        .output()
-        .expect("failed to execute process");
-
-    if !output.stderr.is_empty() {
-        io::stderr().write_all(&output.stderr).unwrap();
-    }
-
-    let output = Command::new("sudo")
-        .arg("ip")
-        .arg("link")
-        .arg("set")
-        .arg(name)
-        .arg("up")
-        .output()
-        .expect("failed to execute process");
-
-    if !output.stderr.is_empty() {
-        io::stderr().write_all(&output.stderr).unwrap();
-        panic!("Unable to bring up bus {}", name)
-    }
-}
-
-/// Destroy a vcan bus using the following commands:
-/// sudo ip link del dev <name>
-/// This function will panic if errors are returned
-pub fn destroy_bus(name: &str) {
-    let output = Command::new("sudo")
-        .arg("ip")
-        .arg("link")
-        .arg("del")
-        .arg("dev")
-        .arg(name)
-        .output()
-        .expect("failed to execute process");
-
-    if !output.stderr.is_empty() {
-        io::stderr().write_all(&output.stderr).unwrap();
-        panic!("Unable to destroy bus {}", name)
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::mem;
+use thiserror::Error;
+
+// Netlink/rtnetlink constants from linux/netlink.h, linux/rtnetlink.h and linux/if_link.h
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+const RTM_NEWLINK: u16 = 16;
+const RTM_DELLINK: u16 = 17;
+const IFLA_IFNAME: u16 = 3;
+const IFLA_MTU: u16 = 4;
+const IFLA_LINKINFO: u16 = 18;
+const IFLA_INFO_KIND: u16 = 1;
+const IFF_UP: u32 = 0x1;
+// Interface type of CAN devices in /sys/class/net/<name>/type, from linux/if_arp.h
+const ARPHRD_CAN: u32 = 280;
+const NLMSG_HDRLEN: usize = 16;
+const IFINFOMSG_LEN: usize = 16;
+// Longest interface name the kernel accepts, IFNAMSIZ minus the NUL
+const MAX_IFNAME_LEN: usize = 15;
+/// MTU a CAN interface needs to carry CAN FD frames
+pub const CANFD_MTU: u32 = 72;
+
+/// Errors returned while managing a CAN interface
+#[derive(Debug, Error)]
+pub enum BusError {
+    /// Name is empty, too long or contains invalid characters
+    #[error("invalid interface name {0:?}")]
+    InvalidName(String),
+    /// Missing CAP_NET_ADMIN
+    #[error("permission denied managing {0}, CAP_NET_ADMIN is required")]
+    PermissionDenied(String),
+    /// No interface with the given name exists
+    #[error("interface {0} does not exist")]
+    NotFound(String),
+    /// The kernel can't create vcan devices, usually the vcan module isn't loaded
+    #[error("unable to create vcan interface {0}, is the vcan kernel module loaded?")]
+    Unsupported(String),
+    /// An existing interface that isn't vcan has an MTU too small for CAN FD
+    #[error("interface {0} is not CAN FD capable, only the MTU of vcan interfaces is raised")]
+    NotFdCapable(String),
+    /// Any other failure talking to the kernel
+    #[error("netlink request for {name} failed: {source}")]
+    Netlink { name: String, source: io::Error },
+}
+
+/// Outcome of a successful create_bus call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusState {
+    /// The interface was created by this call
+    Created,
+    /// The interface already existed and was reused
+    Existing,
+}
+
+/// Create a vcan bus and bring it up using rtnetlink, the equivalent of:
+/// ip link add dev <name> type vcan [mtu 72]
+/// ip link set up <name>
+/// An interface that already exists is reused, only brought up if needed
+/// With `fd` set the interface MTU is raised so it can carry CAN FD frames, existing
+/// interfaces other than vcan are never changed and fail with NotFdCapable instead
+pub fn create_bus(name: &str, fd: bool) -> Result<BusState, BusError> {
+    validate_name(name)?;
+    let socket = NetlinkSocket::open().map_err(|e| map_errno(name, e))?;
+    let request = |msg: Vec<u8>| socket.request(&msg).map_err(|e| map_errno(name, e));
+
+    let mtu = if fd { Some(CANFD_MTU) } else { None };
+    let state = match socket.request(&new_vcan_request(name, mtu)) {
+        Ok(()) => BusState::Created,
+        Err(e) if e.raw_os_error() == Some(libc::EEXIST) => BusState::Existing,
+        Err(e) => return Err(map_errno(name, e)),
+    };
+    if state == BusState::Created {
+        // The caller only tears down interfaces it got back, so don't leave this one behind
+        if let Err(e) = request(set_link_request(name, true, None)) {
+            let _ = destroy_bus(name);
+            return Err(e);
+        }
+        return Ok(state);
+    }
+
+    let needs_mtu = fd && sys_value(name, "mtu").is_none_or(|mtu| mtu < CANFD_MTU);
+    // Taking real hardware down to change its MTU could leave it down
+    if needs_mtu && !is_virtual_can(name) {
+        return Err(BusError::NotFdCapable(name.to_owned()));
+    }
+    let up = sys_value(name, "flags").is_some_and(|flags| flags & IFF_UP != 0);
+    if needs_mtu && up {
+        // vcan refuses MTU changes while the interface is up
+        request(set_link_request(name, false, None))?;
+    }
+    if needs_mtu || !up {
+        request(set_link_request(name, true, mtu.filter(|_| needs_mtu)))?;
+    }
+    Ok(state)
+}
+
+/// Destroy a bus using rtnetlink, the equivalent of:
+/// ip link del dev <name>
+pub fn destroy_bus(name: &str) -> Result<(), BusError> {
+    validate_name(name)?;
+    let socket = NetlinkSocket::open().map_err(|e| map_errno(name, e))?;
+    socket
+        .request(&del_link_request(name))
+        .map_err(|e| map_errno(name, e))
+}
+
+fn validate_name(name: &str) -> Result<(), BusError> {
+    if name.is_empty()
+        || name.len() > MAX_IFNAME_LEN
+        || name.contains(|c: char| c == '/' || c == '\0' || c.is_whitespace())
+    {
+        return Err(BusError::InvalidName(name.to_owned()));
+    }
+    Ok(())
+}
+
+/// Read a numeric interface attribute (flags, mtu, ...) exposed in sysfs
+fn sys_value(name: &str, attr: &str) -> Option<u32> {
+    let value = fs::read_to_string(format!("/sys/class/net/{}/{}", name, attr)).ok()?;
+    let value = value.trim();
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// Whether an interface is a virtual CAN device such as vcan, hardware has a device link
+fn is_virtual_can(name: &str) -> bool {
+    sys_value(name, "type") == Some(ARPHRD_CAN)
+        && fs::symlink_metadata(format!("/sys/class/net/{}/device", name)).is_err()
+}
+
+fn map_errno(name: &str, err: io::Error) -> BusError {
+    match err.raw_os_error() {
+        Some(libc::EPERM) | Some(libc::EACCES) => BusError::PermissionDenied(name.to_owned()),
+        Some(libc::ENODEV) => BusError::NotFound(name.to_owned()),
+        Some(libc::EOPNOTSUPP) => BusError::Unsupported(name.to_owned()),
+        _ => BusError::Netlink {
+            name: name.to_owned(),
+            source: err,
+        },
+    }
+}
+
+/// RTM_NEWLINK request creating a vcan device, fails with EEXIST if name is taken
+fn new_vcan_request(name: &str, mtu: Option<u32>) -> Vec<u8> {
+    let mut link_info = Vec::new();
+    push_attr(&mut link_info, IFLA_INFO_KIND, b"vcan");
+
+    let mut msg = link_msg(
+        RTM_NEWLINK,
+        NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL,
+        0,
+        0,
+    );
+    push_attr(&mut msg, IFLA_IFNAME, &ifname_bytes(name));
+    push_attr(&mut msg, IFLA_LINKINFO, &link_info);
+    if let Some(mtu) = mtu {
+        push_attr(&mut msg, IFLA_MTU, &mtu.to_ne_bytes());
+    }
+    finish_msg(msg)
+}
+
+/// RTM_NEWLINK request bringing an existing device up or down, optionally changing its MTU
+fn set_link_request(name: &str, up: bool, mtu: Option<u32>) -> Vec<u8> {
+    let if_flags = if up { IFF_UP } else { 0 };
+    let mut msg = link_msg(RTM_NEWLINK, NLM_F_REQUEST | NLM_F_ACK, if_flags, IFF_UP);
+    push_attr(&mut msg, IFLA_IFNAME, &ifname_bytes(name));
+    if let Some(mtu) = mtu {
+        push_attr(&mut msg, IFLA_MTU, &mtu.to_ne_bytes());
+    }
+    finish_msg(msg)
+}
+
+/// RTM_DELLINK request removing a device
+fn del_link_request(name: &str) -> Vec<u8> {
+    let mut msg = link_msg(RTM_DELLINK, NLM_F_REQUEST | NLM_F_ACK, 0, 0);
+    push_attr(&mut msg, IFLA_IFNAME, &ifname_bytes(name));
+    finish_msg(msg)
+}
+
+fn ifname_bytes(name: &str) -> Vec<u8> {
+    CString::new(name).unwrap().into_bytes_with_nul()
+}
+
+/// nlmsghdr followed by an ifinfomsg, length is filled in by finish_msg
+/// Devices are looked up by IFLA_IFNAME so the ifinfomsg index is left at 0
+fn link_msg(msg_type: u16, flags: u16, if_flags: u32, if_change: u32) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(64);
+    msg.extend_from_slice(&0u32.to_ne_bytes()); // nlmsg_len
+    msg.extend_from_slice(&msg_type.to_ne_bytes());
+    msg.extend_from_slice(&flags.to_ne_bytes());
+    msg.extend_from_slice(&1u32.to_ne_bytes()); // nlmsg_seq
+    msg.extend_from_slice(&0u32.to_ne_bytes()); // nlmsg_pid, 0 is the kernel
+    msg.push(libc::AF_UNSPEC as u8); // ifi_family
+    msg.push(0); // padding
+    msg.extend_from_slice(&0u16.to_ne_bytes()); // ifi_type
+    msg.extend_from_slice(&0i32.to_ne_bytes()); // ifi_index
+    msg.extend_from_slice(&if_flags.to_ne_bytes()); // ifi_flags
+    msg.extend_from_slice(&if_change.to_ne_bytes()); // ifi_change, only touch these flags
+    debug_assert_eq!(msg.len(), NLMSG_HDRLEN + IFINFOMSG_LEN);
+    msg
+}
+
+fn finish_msg(mut msg: Vec<u8>) -> Vec<u8> {
+    let len = msg.len() as u32;
+    msg[0..4].copy_from_slice(&len.to_ne_bytes());
+    msg
+}
+
+/// Append a rtattr, padded to the 4 byte netlink alignment
+fn push_attr(buf: &mut Vec<u8>, attr_type: u16, data: &[u8]) {
+    let len = 4 + data.len();
+    buf.extend_from_slice(&(len as u16).to_ne_bytes());
+    buf.extend_from_slice(&attr_type.to_ne_bytes());
+    buf.extend_from_slice(data);
+    buf.resize(buf.len() + (4 - len % 4) % 4, 0);
+}
+
+/// Read the status out of a NLMSG_ERROR reply, 0 is an ACK
+/// Returns None if the buffer isn't an error/ack message
+fn parse_ack(reply: &[u8]) -> Option<i32> {
+    if reply.len() < NLMSG_HDRLEN + 4 {
+        return None;
+    }
+    let msg_type = u16::from_ne_bytes([reply[4], reply[5]]);
+    if msg_type != NLMSG_ERROR {
+        return None;
+    }
+    let mut error = [0u8; 4];
+    error.copy_from_slice(&reply[NLMSG_HDRLEN..NLMSG_HDRLEN + 4]);
+    Some(i32::from_ne_bytes(error))
+}
+
+/// NETLINK_ROUTE socket, closed on drop
+struct NetlinkSocket {
+    fd: libc::c_int,
+}
+
+impl NetlinkSocket {
+    fn open() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = Self { fd };
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        let rv = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if rv < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(socket)
+    }
+
+    /// Send a request and wait for the kernel's ACK
+    fn request(&self, msg: &[u8]) -> io::Result<()> {
+        let sent =
+            unsafe { libc::send(self.fd, msg.as_ptr() as *const libc::c_void, msg.len(), 0) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut reply = [0u8; 4096];
+        loop {
+            let len = unsafe {
+                libc::recv(
+                    self.fd,
+                    reply.as_mut_ptr() as *mut libc::c_void,
+                    reply.len(),
+                    0,
+                )
+            };
+            if len < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            match parse_ack(&reply[..len as usize]) {
+                Some(0) => return Ok(()),
+                Some(errno) => return Err(io::Error::from_raw_os_error(-errno)),
+                // Not the ACK, e.g. a notification, keep waiting
+                None => continue,
+            }
+        }
+    }
+}
+
+impl Drop for NetlinkSocket {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_encodes_new_vcan_request() {
+        let msg = new_vcan_request("vcan0", None);
+        assert_eq!(msg.len() % 4, 0);
+        assert_eq!(
+            u32::from_ne_bytes([msg[0], msg[1], msg[2], msg[3]]) as usize,
+            msg.len()
+        );
+        assert_eq!(u16::from_ne_bytes([msg[4], msg[5]]), RTM_NEWLINK);
+
+        // IFLA_IFNAME "vcan0\0" padded to 8, then IFLA_LINKINFO { IFLA_INFO_KIND "vcan" }
+        let attrs = &msg[NLMSG_HDRLEN + IFINFOMSG_LEN..];
+        assert_eq!(&attrs[0..2], &10u16.to_ne_bytes());
+        assert_eq!(&attrs[2..4], &IFLA_IFNAME.to_ne_bytes());
+        assert_eq!(&attrs[4..10], b"vcan0\0");
+        assert_eq!(&attrs[12..14], &12u16.to_ne_bytes());
+        assert_eq!(&attrs[14..16], &IFLA_LINKINFO.to_ne_bytes());
+        assert_eq!(&attrs[18..20], &IFLA_INFO_KIND.to_ne_bytes());
+        assert_eq!(&attrs[20..24], b"vcan");
+
+        // FD capable interfaces also carry IFLA_MTU
+        let msg = new_vcan_request("vcan0", Some(CANFD_MTU));
+        let mtu_attr = &msg[msg.len() - 8..];
+        assert_eq!(&mtu_attr[2..4], &IFLA_MTU.to_ne_bytes());
+        assert_eq!(&mtu_attr[4..8], &CANFD_MTU.to_ne_bytes());
+    }
+
+    #[test]
+    fn it_maps_kernel_errors() {
+        let mut reply = vec![0u8; NLMSG_HDRLEN + 4];
+        reply[4..6].copy_from_slice(&NLMSG_ERROR.to_ne_bytes());
+        assert_eq!(parse_ack(&reply), Some(0));
+        reply[NLMSG_HDRLEN..].copy_from_slice(&(-libc::EPERM).to_ne_bytes());
+        assert_eq!(parse_ack(&reply), Some(-libc::EPERM));
+
+        let err = map_errno("vcan0", io::Error::from_raw_os_error(libc::EPERM));
+        assert!(matches!(err, BusError::PermissionDenied(_)));
+        let err = map_errno("vcan0", io::Error::from_raw_os_error(libc::ENODEV));
+        assert!(matches!(err, BusError::NotFound(_)));
+        assert!(matches!(
+            create_bus("this-name-is-too-long", false),
+            Err(BusError::InvalidName(_))
+        ));
+    }
+
+    #[test]
+    fn it_only_changes_virtual_can_interfaces() {
+        // The loopback interface is virtual but not CAN, missing interfaces are neither
+        assert!(!is_virtual_can("lo"));
+        assert!(!is_virtual_can("nonexistent0"));
     }
 }
@@ -0,0 +1,242 @@
+use crate::frame::{CanFrame, EFF_MASK};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Number of CANopen node ids a `+n` matcher covers, node 0 included
+const NODE_IDS: u32 = 0x80;
+
+/// Says which response id answers a request, for a block of consecutive request ids
+/// A request `offset` ids above `request` is answered by `response + offset`, so
+/// the CANopen SDO matcher maps a request on 0x600+n to its response on 0x580+n
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Matcher {
+    /// Lowest request id
+    pub request: u32,
+    /// Number of request ids covered
+    pub count: u32,
+    /// Response id to the lowest request id
+    pub response: u32,
+}
+
+impl Matcher {
+    /// CANopen SDO, server responses on 0x580+n answer client requests on 0x600+n
+    pub const SDO: Matcher = Matcher {
+        request: 0x600,
+        count: NODE_IDS,
+        response: 0x580,
+    };
+
+    /// CANopen node guarding, a remote request on 0x700+n is answered on the same id
+    pub const GUARD: Matcher = Matcher {
+        request: 0x700,
+        count: NODE_IDS,
+        response: 0x700,
+    };
+
+    /// Response id expected for a request on `id`, None when the matcher doesn't cover it
+    /// or the response id would go past the largest CAN id
+    pub fn expected(&self, id: u32) -> Option<u32> {
+        let offset = id.checked_sub(self.request)?;
+        if offset >= self.count {
+            return None;
+        }
+        self.response
+            .checked_add(offset)
+            .filter(|&response| response <= EFF_MASK)
+    }
+}
+
+impl FromStr for Matcher {
+    type Err = String;
+
+    /// `sdo`, `guard`, `REQ:RESP` for a single id or `REQ+n:RESP+n` for every node id,
+    /// ids are hex with an optional 0x prefix
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sdo" => return Ok(Matcher::SDO),
+            "guard" => return Ok(Matcher::GUARD),
+            _ => {}
+        }
+        let (request, response) = s
+            .split_once(':')
+            .ok_or_else(|| format!("{} should be REQ:RESP or REQ+n:RESP+n", s))?;
+        let per_node = |id| str::strip_suffix(id, "+n");
+        let (request, response, count) = match (per_node(request), per_node(response)) {
+            (Some(request), Some(response)) => (request, response, NODE_IDS),
+            (None, None) => (request, response, 1),
+            _ => return Err(format!("{}: +n should be on both ids or neither", s)),
+        };
+        let parse = |id: &str| match u32::from_str_radix(id.trim_start_matches("0x"), 16) {
+            Ok(parsed) if parsed <= EFF_MASK => Ok(parsed),
+            Ok(_) => Err(format!("{} is larger than the largest CAN id", id)),
+            Err(_) => Err(format!("{} is not a hex id", id)),
+        };
+        Ok(Matcher {
+            request: parse(request)?,
+            count,
+            response: parse(response)?,
+        })
+    }
+}
+
+impl fmt::Display for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.count == 1 {
+            write!(f, "{:X}:{:X}", self.request, self.response)
+        } else {
+            write!(f, "{:X}+n:{:X}+n", self.request, self.response)
+        }
+    }
+}
+
+/// Frames heard after a request, sorted by whether they answer it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Correlation {
+    /// Response id a matcher expects, None when no matcher covers the request
+    pub expected: Option<u32>,
+    /// Frames answering the request, any frame does when nothing is expected
+    pub responses: Vec<CanFrame>,
+    /// Frames other than the expected response
+    pub unexpected: Vec<CanFrame>,
+}
+
+impl Correlation {
+    /// Sort the frames `heard` after `request` with the first matcher covering it
+    pub fn new(matchers: &[Matcher], request: &CanFrame, heard: Vec<CanFrame>) -> Self {
+        let expected = matchers
+            .iter()
+            .find_map(|matcher| matcher.expected(request.id()));
+        let (responses, unexpected) = match expected {
+            Some(id) => heard.into_iter().partition(|frame| frame.id() == id),
+            None => (heard, vec![]),
+        };
+        Correlation {
+            expected,
+            responses,
+            unexpected,
+        }
+    }
+
+    pub fn is_answered(&self) -> bool {
+        !self.responses.is_empty()
+    }
+}
+
+/// Tally of the requests sent in listen mode and what was heard after them
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResponseReport {
+    pub requests: usize,
+    pub answered: usize,
+    /// Requests without a response, counted by request id
+    pub unanswered: BTreeMap<u32, usize>,
+    /// Frames other than the expected response, counted by request and frame id
+    pub unexpected: BTreeMap<(u32, u32), usize>,
+}
+
+impl ResponseReport {
+    pub fn add(&mut self, request: &CanFrame, correlation: &Correlation) {
+        self.requests += 1;
+        if correlation.is_answered() {
+            self.answered += 1;
+        } else {
+            *self.unanswered.entry(request.id()).or_default() += 1;
+        }
+        for frame in &correlation.unexpected {
+            *self
+                .unexpected
+                .entry((request.id(), frame.id()))
+                .or_default() += 1;
+        }
+    }
+}
+
+impl fmt::Display for ResponseReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} request(s), {} answered, {} unanswered",
+            self.requests,
+            self.answered,
+            self.requests - self.answered
+        )?;
+        for (id, count) in &self.unanswered {
+            write!(f, "\n  no response to {:X}: {} time(s)", id, count)?;
+        }
+        for ((request, id), count) in &self.unexpected {
+            write!(
+                f,
+                "\n  unexpected {:X} after {:X}: {} time(s)",
+                id, request, count
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(id: u32) -> CanFrame {
+        CanFrame::new(id, &[0x40], false, false).unwrap()
+    }
+
+    #[test]
+    fn it_parses_matchers() {
+        assert_eq!("sdo".parse(), Ok(Matcher::SDO));
+        assert_eq!("0x600+n:580+n".parse(), Ok(Matcher::SDO));
+        let single: Matcher = "7E0:7E8".parse().unwrap();
+        assert_eq!(single.expected(0x7E0), Some(0x7E8));
+        assert_eq!(single.expected(0x7E1), None);
+        assert_eq!(single.to_string(), "7E0:7E8");
+        assert_eq!(Matcher::SDO.expected(0x605), Some(0x585));
+        assert_eq!(Matcher::SDO.expected(0x680), None);
+        assert!("600+n:580".parse::<Matcher>().is_err());
+        assert!("600".parse::<Matcher>().is_err());
+        assert!("600:20000000".parse::<Matcher>().is_err());
+
+        // Responses can't go past the largest extended id
+        let top: Matcher = "0+n:1FFFFFC0+n".parse().unwrap();
+        assert_eq!(top.expected(0x3F), Some(EFF_MASK));
+        assert_eq!(top.expected(0x40), None);
+        let overflowing = Matcher {
+            request: 0,
+            count: NODE_IDS,
+            response: u32::MAX,
+        };
+        assert_eq!(overflowing.expected(1), None);
+    }
+
+    #[test]
+    fn it_reports_unanswered_and_unexpected_frames() {
+        let matchers = [Matcher::SDO];
+        let mut report = ResponseReport::default();
+
+        // Expected response among background traffic
+        let correlation =
+            Correlation::new(&matchers, &frame(0x605), vec![frame(0x705), frame(0x585)]);
+        assert_eq!(correlation.expected, Some(0x585));
+        assert_eq!(correlation.responses, [frame(0x585)]);
+        assert_eq!(correlation.unexpected, [frame(0x705)]);
+        report.add(&frame(0x605), &correlation);
+
+        // Wrong node answering
+        let correlation = Correlation::new(&matchers, &frame(0x605), vec![frame(0x586)]);
+        assert!(!correlation.is_answered());
+        report.add(&frame(0x605), &correlation);
+
+        // Without a matcher any frame answers
+        let correlation = Correlation::new(&matchers, &frame(0x123), vec![frame(0x124)]);
+        assert!(correlation.is_answered());
+        report.add(&frame(0x123), &correlation);
+
+        assert_eq!(report.requests, 3);
+        assert_eq!(report.answered, 2);
+        assert_eq!(report.unanswered.get(&0x605), Some(&1));
+        assert_eq!(report.unexpected.len(), 2);
+        assert!(report
+            .to_string()
+            .contains("unexpected 586 after 605: 1 time(s)"));
+    }
+}
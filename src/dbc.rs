@@ -0,0 +1,456 @@
+use crate::error::FuzzerError;
+use crate::frame::{CANFD_MAX_DLEN, CAN_MAX_DLEN, EFF_FLAG, EFF_MASK};
+use crate::msg_processor::{padding, raw_value_range, ByteOrder, MsgFormat, Section, SubSec};
+use std::fs;
+use std::path::Path;
+
+/// Id DBC editors give the pseudo message holding signals not assigned to any message
+const INDEPENDENT_SIGNALS_ID: u32 = 0xC000_0000;
+
+/// A BO_ message definition
+struct Message {
+    name: String,
+    /// Id as written in the file, bit 31 marks an extended id
+    id: u32,
+    dlc: usize,
+    line: usize,
+    signals: Vec<Signal>,
+}
+
+/// A SG_ signal definition
+struct Signal {
+    name: String,
+    /// Start bit, the lsb for Intel signals and the msb for Motorola signals
+    start: usize,
+    len: usize,
+    order: ByteOrder,
+    signed: bool,
+    factor: f64,
+    offset: f64,
+    min: f64,
+    max: f64,
+    /// Only sent for one value of the multiplexor signal (mN)
+    multiplexed: bool,
+}
+
+impl Signal {
+    /// First and last byte of the message the signal covers
+    fn byte_span(&self) -> (usize, usize) {
+        match self.order {
+            ByteOrder::LittleEndian => (self.start / 8, (self.start + self.len - 1) / 8),
+            ByteOrder::BigEndian => (self.start / 8, (self.msb() + self.len - 1) / 8),
+        }
+    }
+
+    /// Position of a Motorola signal's msb counting from the msb of byte 0
+    fn msb(&self) -> usize {
+        (self.start / 8) * 8 + 7 - self.start % 8
+    }
+
+    /// True when the signal is whole bytes starting on a byte boundary
+    fn is_byte_aligned(&self) -> bool {
+        let start_bit = match self.order {
+            ByteOrder::LittleEndian => 0,
+            ByteOrder::BigEndian => 7,
+        };
+        self.len.is_multiple_of(8) && self.start % 8 == start_bit
+    }
+
+    /// Physical [min|max] converted to raw values, None when the signal can take any value
+    /// Signed ranges are stored two's complement, so a range crossing zero can't be kept
+    fn raw_range(&self) -> (Option<u64>, Option<u64>) {
+        if (self.min == 0.0 && self.max == 0.0) || self.factor == 0.0 {
+            return (None, None);
+        }
+        let raw = |phys: f64| (phys - self.offset) / self.factor;
+        let (low, high) = (raw(self.min), raw(self.max));
+        let (low, high) = if low <= high {
+            (low, high)
+        } else {
+            (high, low)
+        };
+        raw_value_range(
+            nearly_whole(low).ceil() as i128,
+            nearly_whole(high).floor() as i128,
+            self.len,
+            self.signed,
+        )
+    }
+}
+
+/// Round values that are only off from a whole number by float error
+fn nearly_whole(value: f64) -> f64 {
+    if (value - value.round()).abs() < 1e-6 {
+        value.round()
+    } else {
+        value
+    }
+}
+
+/// Read a DBC file into one message format per BO_ message
+pub fn read_dbc(path: &Path) -> Result<Vec<MsgFormat>, FuzzerError> {
+    let text = fs::read_to_string(path).map_err(|source| FuzzerError::ConfigRead {
+        path: path.to_owned(),
+        source,
+    })?;
+    parse_dbc(&text, path)
+}
+
+/// Convert DBC text into message formats, `path` is only used in errors
+///
+/// Signals sharing bytes are packed into one section with the byte order of
+/// its first signal, gaps between them become subsections fixed to 0 and
+/// bytes no signal uses become sections fixed to 0. Messages without signals
+/// get random data. Multiplexed signals (mN) are skipped since they overlap
+/// each other, the multiplexor itself is kept.
+pub fn parse_dbc(text: &str, path: &Path) -> Result<Vec<MsgFormat>, FuzzerError> {
+    let error = |line: usize, reason: String| FuzzerError::ImportParse {
+        path: path.to_owned(),
+        line,
+        reason,
+    };
+
+    let mut messages: Vec<Message> = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if let Some(rest) = line.strip_prefix("BO_ ") {
+            messages.push(parse_message(rest, i + 1).map_err(|e| error(i + 1, e))?);
+        } else if let Some(rest) = line.strip_prefix("SG_ ") {
+            let signal = parse_signal(rest).map_err(|e| error(i + 1, e))?;
+            match messages.last_mut() {
+                Some(message) => message.signals.push(signal),
+                None => return Err(error(i + 1, String::from("signal outside of a message"))),
+            }
+        }
+    }
+
+    messages
+        .iter()
+        .filter(|message| message.id != INDEPENDENT_SIGNALS_ID)
+        .map(|message| to_format(message).map_err(|e| error(message.line, e)))
+        .collect()
+}
+
+/// Parse `<id> <name>: <dlc> <transmitter>`
+fn parse_message(rest: &str, line: usize) -> Result<Message, String> {
+    let (head, tail) = rest
+        .split_once(':')
+        .ok_or_else(|| String::from("message is missing ':'"))?;
+    let mut head = head.split_whitespace();
+    let id = head
+        .next()
+        .ok_or_else(|| String::from("message is missing an id"))?;
+    let name = head
+        .next()
+        .ok_or_else(|| String::from("message is missing a name"))?;
+    let dlc = tail
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| format!("message {} is missing a length", name))?;
+    Ok(Message {
+        name: name.to_owned(),
+        id: id
+            .parse()
+            .map_err(|_| format!("invalid message id {}", id))?,
+        dlc: dlc
+            .parse()
+            .map_err(|_| format!("invalid message length {}", dlc))?,
+        line,
+        signals: vec![],
+    })
+}
+
+/// Parse `<name> [mux] : <start>|<len>@<order><sign> (<factor>,<offset>) [<min>|<max>] ...`
+fn parse_signal(rest: &str) -> Result<Signal, String> {
+    let (head, tail) = rest
+        .split_once(':')
+        .ok_or_else(|| String::from("signal is missing ':'"))?;
+    let mut head = head.split_whitespace();
+    let name = head
+        .next()
+        .ok_or_else(|| String::from("signal is missing a name"))?
+        .to_owned();
+    let multiplexed = head.next().is_some_and(|mux| mux.starts_with('m'));
+
+    let invalid = |what: &str| format!("signal {} has an invalid {}", name, what);
+    let between = |open: char, close: char, what: &str| -> Result<&str, String> {
+        let start = tail.find(open).ok_or_else(|| invalid(what))?;
+        let end = tail[start..].find(close).ok_or_else(|| invalid(what))? + start;
+        Ok(&tail[start + 1..end])
+    };
+
+    let bits = tail[..tail.find('(').ok_or_else(|| invalid("scaling"))?].trim();
+    let (start, bits) = bits.split_once('|').ok_or_else(|| invalid("start bit"))?;
+    let (len, kind) = bits.split_once('@').ok_or_else(|| invalid("length"))?;
+    let order = match kind.chars().next() {
+        Some('1') => ByteOrder::LittleEndian,
+        Some('0') => ByteOrder::BigEndian,
+        _ => return Err(invalid("byte order")),
+    };
+    let signed = kind.ends_with('-');
+    let (factor, offset) = between('(', ')', "scaling")?
+        .split_once(',')
+        .ok_or_else(|| invalid("scaling"))?;
+    let (min, max) = between('[', ']', "range")?
+        .split_once('|')
+        .ok_or_else(|| invalid("range"))?;
+
+    let number = |value: &str, what: &str| value.trim().parse::<f64>().map_err(|_| invalid(what));
+    let signal = Signal {
+        start: start.trim().parse().map_err(|_| invalid("start bit"))?,
+        len: len.trim().parse().map_err(|_| invalid("length"))?,
+        order,
+        signed,
+        factor: number(factor, "factor")?,
+        offset: number(offset, "offset")?,
+        min: number(min, "minimum")?,
+        max: number(max, "maximum")?,
+        multiplexed,
+        name,
+    };
+    if signal.len == 0 || signal.len > 64 {
+        return Err(format!(
+            "signal {} is {} bits, only 1 to 64 are supported",
+            signal.name, signal.len
+        ));
+    }
+    Ok(signal)
+}
+
+/// Lay a message's signals out as sections
+fn to_format(message: &Message) -> Result<MsgFormat, String> {
+    let extended = message.id & EFF_FLAG != 0;
+    let id = if extended {
+        message.id & EFF_MASK
+    } else {
+        message.id
+    };
+    if message.dlc > CANFD_MAX_DLEN {
+        return Err(format!(
+            "message {} is {} bytes, a CAN FD frame holds {}",
+            message.name, message.dlc, CANFD_MAX_DLEN
+        ));
+    }
+
+    let mut signals: Vec<&Signal> = message.signals.iter().filter(|s| !s.multiplexed).collect();
+    for signal in &signals {
+        if signal.byte_span().1 >= message.dlc {
+            return Err(format!(
+                "signal {} doesn't fit in the {} bytes of {}",
+                signal.name, message.dlc, message.name
+            ));
+        }
+    }
+
+    // Signals sharing any byte end up in the same section
+    signals.sort_by_key(|signal| signal.byte_span());
+    let mut groups: Vec<(usize, usize, Vec<&Signal>)> = vec![];
+    for signal in signals {
+        let (first, last) = signal.byte_span();
+        match groups.last_mut() {
+            Some(group) if first <= group.1 => {
+                group.1 = group.1.max(last);
+                group.2.push(signal);
+            }
+            _ => groups.push((first, last, vec![signal])),
+        }
+    }
+
+    let mut sections = vec![];
+    if groups.is_empty() && message.dlc > 0 {
+        sections.push(Section::new(
+            String::from("Data"),
+            message.dlc as u8,
+            vec![],
+            false,
+            0,
+        ));
+    }
+    let mut next_byte = 0;
+    for (first, last, group) in &groups {
+        if *first > next_byte {
+            sections.push(unused_section(next_byte, first - next_byte));
+        }
+        sections.push(group_section(*first, *last, group)?);
+        next_byte = last + 1;
+    }
+    if !groups.is_empty() && next_byte < message.dlc {
+        sections.push(unused_section(next_byte, message.dlc - next_byte));
+    }
+
+    Ok(MsgFormat::new(
+        message.name.clone(),
+        id..id + 1,
+        vec![],
+        sections.len() as u8,
+        sections,
+        false,
+        0,
+    )
+    .with_extended(extended)
+    .with_fd(message.dlc > CAN_MAX_DLEN, false, false))
+}
+
+/// Bytes no signal uses, always sent as 0
+fn unused_section(first: usize, len: usize) -> Section {
+    Section::new(format!("Unused{}", first), len as u8, vec![], true, 0)
+}
+
+/// Section covering bytes first..=last holding the given signals
+fn group_section(first: usize, last: usize, signals: &[&Signal]) -> Result<Section, String> {
+    let order = signals[0].order;
+    let width = (last - first + 1) * 8;
+
+    // Bit position of each signal counting from the lsb of a little endian
+    // section or the msb of a big endian one
+    let mut fields = vec![];
+    for signal in signals {
+        let mut sub_sec = SubSec::new(signal.name.clone(), signal.len as u8, vec![], false, 0);
+        let (min, max) = signal.raw_range();
+        sub_sec = sub_sec.with_range(min, max);
+        let pos = if signal.order == order {
+            match order {
+                ByteOrder::LittleEndian => signal.start - first * 8,
+                ByteOrder::BigEndian => signal.msb() - first * 8,
+            }
+        } else if signal.is_byte_aligned() {
+            sub_sec = sub_sec.with_byte_order(signal.order);
+            (signal.byte_span().0 - first) * 8
+        } else {
+            return Err(format!(
+                "signal {} shares bytes with signals of the other byte order \
+                 without being byte aligned",
+                signal.name
+            ));
+        };
+        fields.push((pos, sub_sec));
+    }
+    fields.sort_by_key(|(pos, _)| *pos);
+
+    let mut sub_secs = vec![];
+    let mut next_bit = 0;
+    for (pos, sub_sec) in fields {
+        if pos < next_bit {
+            return Err(format!("signal {} overlaps another signal", sub_sec.name()));
+        }
+        padding(&mut sub_secs, next_bit, pos);
+        next_bit = pos + sub_sec.num_bits() as usize;
+        sub_secs.push(sub_sec);
+    }
+    padding(&mut sub_secs, next_bit, width);
+
+    let name = signals
+        .iter()
+        .map(|signal| signal.name.as_str())
+        .collect::<Vec<_>>()
+        .join("+");
+    Ok(Section::new(name, (last - first + 1) as u8, sub_secs, false, 0).with_byte_order(order))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg_processor::msg_processor;
+    use crate::validate::validate;
+
+    // Ranges of a single value make every signal's raw value known
+    const DBC: &str = r#"
+VERSION ""
+
+BU_: ECU Tester
+
+BO_ 256 Engine: 8 ECU
+ SG_ Speed : 0|16@1+ (0.5,0) [2330|2330] "rpm" Tester
+ SG_ Temp : 16|8@1- (1,-40) [-30|-30] "degC" Tester
+ SG_ Gear : 36|4@1+ (1,0) [3|3] "" Tester
+
+BO_ 2147484160 Motorola: 2 ECU
+ SG_ Mode : 7|4@0+ (1,0) [5|5] "" Tester
+ SG_ Level : 3|12@0+ (1,0) [2748|2748] "" Tester
+
+BO_ 768 Opaque: 4 ECU
+
+BO_ 3221225472 VECTOR__INDEPENDENT_SIG_MSG: 0 Vector__XXX
+ SG_ Orphan : 0|8@1+ (1,0) [0|0] "" Vector__XXX
+"#;
+
+    #[test]
+    fn it_imports_signals_at_their_bit_positions() {
+        let formats = parse_dbc(DBC, Path::new("test.dbc")).unwrap();
+        assert_eq!(formats.len(), 3);
+        for format in &formats {
+            assert_eq!(validate(format, Path::new("test.dbc")), vec![]);
+        }
+        let mut rng = rand::thread_rng();
+
+        let engine = &formats[0];
+        assert_eq!(engine.name(), "Engine");
+        assert_eq!(engine.cob_id_range(), &(0x100..0x101));
+        assert_eq!(
+            msg_processor(engine, &mut rng).unwrap().data,
+            vec![0x34, 0x12, 0x0A, 0x00, 0x30, 0x00, 0x00, 0x00]
+        );
+
+        let motorola = &formats[1];
+        assert!(motorola.is_extended());
+        assert_eq!(motorola.cob_id_range(), &(0x200..0x201));
+        assert_eq!(
+            msg_processor(motorola, &mut rng).unwrap().data[..2],
+            [0x5A, 0xBC]
+        );
+
+        // No signals, the whole message is random
+        assert_eq!(formats[2].sections().len(), 1);
+        assert_eq!(formats[2].sections()[0].specified_val(), None);
+    }
+
+    #[test]
+    fn it_converts_physical_ranges_to_raw_values() {
+        let signal = |signed: bool, factor: f64, offset: f64, min: f64, max: f64| Signal {
+            name: String::from("S"),
+            start: 0,
+            len: 8,
+            order: ByteOrder::LittleEndian,
+            signed,
+            factor,
+            offset,
+            min,
+            max,
+            multiplexed: false,
+        };
+        assert_eq!(
+            signal(false, 0.5, 0.0, 10.0, 100.0).raw_range(),
+            (Some(20), Some(200))
+        );
+        assert_eq!(
+            signal(false, 1.0, 0.0, 0.0, 255.0).raw_range(),
+            (None, None)
+        );
+        assert_eq!(signal(false, 1.0, 0.0, 0.0, 0.0).raw_range(), (None, None));
+        // Negative raw values are two's complement within the signal width
+        assert_eq!(
+            signal(true, 1.0, 0.0, -10.0, -1.0).raw_range(),
+            (Some(246), Some(255))
+        );
+        assert_eq!(
+            signal(true, 1.0, 0.0, -10.0, 10.0).raw_range(),
+            (None, None)
+        );
+    }
+
+    #[test]
+    fn it_reports_bad_lines() {
+        let err = parse_dbc(
+            "BO_ 1 A: 8 ECU\n SG_ B : 0|8@2+ (1,0) [0|0] \"\" X\n",
+            Path::new("x.dbc"),
+        );
+        assert!(matches!(err, Err(FuzzerError::ImportParse { line: 2, .. })));
+        let err = parse_dbc(" SG_ B : 0|8@1+ (1,0) [0|0] \"\" X\n", Path::new("x.dbc"));
+        assert!(matches!(err, Err(FuzzerError::ImportParse { line: 1, .. })));
+        let err = parse_dbc(
+            "BO_ 1 A: 1 ECU\n SG_ B : 4|8@1+ (1,0) [0|0] \"\" X\n",
+            Path::new("x.dbc"),
+        );
+        assert!(matches!(err, Err(FuzzerError::ImportParse { line: 1, .. })));
+    }
+}
@@ -0,0 +1,622 @@
+use crate::error::FuzzerError;
+use crate::msg_processor::{padding, raw_value_range, ByteOrder, MsgFormat, Section, SubSec};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// Function code of SDO requests (client to server), added to the node id
+pub const SDO_RX_BASE: u32 = 0x600;
+/// Function codes of the four default RPDOs, added to the node id
+const RPDO_BASES: [u32; 4] = [0x200, 0x300, 0x400, 0x500];
+/// Function codes of the four default TPDOs, added to the node id
+const TPDO_BASES: [u32; 4] = [0x180, 0x280, 0x380, 0x480];
+
+/// SDO command bytes (CiA 301)
+const SDO_DOWNLOAD_EXPEDITED: u8 = 0x23;
+const SDO_DOWNLOAD_SEGMENTED: u8 = 0x21;
+const SDO_UPLOAD: u8 = 0x40;
+/// Client command specifier of download and upload segments
+const SDO_CCS_DOWNLOAD_SEGMENT: u64 = 0;
+const SDO_CCS_UPLOAD_SEGMENT: u64 = 3;
+
+/// INI style file, section and key names are lower cased
+type Ini = BTreeMap<String, BTreeMap<String, String>>;
+
+/// A single value of the object dictionary, an object or one of its sub indices
+struct Entry {
+    index: u16,
+    sub_index: u8,
+    name: String,
+    data_type: u16,
+    readable: bool,
+    writable: bool,
+    low: Option<i128>,
+    high: Option<i128>,
+}
+
+impl Entry {
+    /// Size in bytes of the entry's data type, None for strings and domains
+    fn size(&self) -> Option<usize> {
+        type_size(self.data_type)
+    }
+
+    fn signed(&self) -> bool {
+        matches!(self.data_type, 0x2..=0x4 | 0x10 | 0x12..=0x15)
+    }
+
+    fn label(&self) -> String {
+        format!("0x{:04X}sub{} {}", self.index, self.sub_index, self.name)
+    }
+
+    /// Subsection holding a value of the entry, limited to LowLimit..HighLimit
+    fn value_sub_sec(&self, bits: usize) -> SubSec {
+        let sub_sec = SubSec::new(self.name.clone(), bits as u8, vec![], false, 0);
+        match (self.low, self.high) {
+            (None, None) => sub_sec,
+            (low, high) => {
+                let (min, max) = raw_value_range(
+                    low.unwrap_or(i128::MIN),
+                    high.unwrap_or(i128::MAX),
+                    bits,
+                    self.signed(),
+                );
+                sub_sec.with_range(min, max)
+            }
+        }
+    }
+}
+
+/// Size in bytes of a CANopen basic data type, None when it varies
+fn type_size(data_type: u16) -> Option<usize> {
+    match data_type {
+        0x1 | 0x2 | 0x5 => Some(1),
+        0x3 | 0x6 => Some(2),
+        0x10 | 0x16 => Some(3),
+        0x4 | 0x7 | 0x8 => Some(4),
+        0x12 | 0x18 => Some(5),
+        0x13 | 0x19 => Some(6),
+        0x14 | 0x1A => Some(7),
+        0x11 | 0x15 | 0x1B => Some(8),
+        _ => None,
+    }
+}
+
+/// Read an EDS or DCF file into SDO and PDO message formats
+/// DCF files name their node id, for EDS files `node_id` has to be given
+pub fn read_eds(path: &Path, node_id: Option<u8>) -> Result<Vec<MsgFormat>, FuzzerError> {
+    let text = fs::read_to_string(path).map_err(|source| FuzzerError::ConfigRead {
+        path: path.to_owned(),
+        source,
+    })?;
+    parse_eds(&text, path, node_id)
+}
+
+/// Convert EDS/DCF text into message formats, `path` is only used in errors
+///
+/// Every object dictionary entry gets an upload request format if it can be
+/// read and a download format if it can be written: expedited for values up
+/// to 4 bytes, an initiate with the size for anything larger. Segment formats
+/// are added when any entry needs them, and each mapped RPDO/TPDO becomes a
+/// format of its mapped values. A node id in the file (DCF) wins over `node_id`.
+pub fn parse_eds(
+    text: &str,
+    path: &Path,
+    node_id: Option<u8>,
+) -> Result<Vec<MsgFormat>, FuzzerError> {
+    let error = |line: usize, reason: String| FuzzerError::ImportParse {
+        path: path.to_owned(),
+        line,
+        reason,
+    };
+    let ini = parse_ini(text).map_err(|(line, reason)| error(line, reason))?;
+
+    let file_node_id = ini
+        .get("devicecomissioning")
+        .and_then(|section| section.get("nodeid"))
+        .and_then(|value| parse_value(value, 0));
+    let node_id = match file_node_id.or(node_id.map(i128::from)) {
+        Some(id) if (1..=127).contains(&id) => id as u32,
+        Some(id) => return Err(error(0, format!("node id {} is not in 1..=127", id))),
+        None => {
+            return Err(error(
+                0,
+                String::from("no node id in the file, pass one with --node-id"),
+            ))
+        }
+    };
+
+    let entries = entries(&ini, node_id).map_err(|reason| error(0, reason))?;
+    let mut formats = vec![];
+    // Values over 4 bytes are sent in segments after the initiate
+    let (mut download_segments, mut upload_segments) = (false, false);
+    for entry in &entries {
+        let segmented = entry.size().is_none_or(|size| size > 4);
+        if entry.readable {
+            upload_segments |= segmented;
+            formats.push(sdo_format(
+                format!("SDO upload {}", entry.label()),
+                node_id,
+                SDO_UPLOAD,
+                entry,
+                zero_data(),
+            ));
+        }
+        if !entry.writable {
+            continue;
+        }
+        match entry.size() {
+            Some(size) if size <= 4 => {
+                // Expedited with the number of unused data bytes in bits 2-3
+                let command = SDO_DOWNLOAD_EXPEDITED | (((4 - size) as u8) << 2);
+                let mut value = vec![entry.value_sub_sec(size * 8)];
+                padding(&mut value, size * 8, 32);
+                formats.push(sdo_format(
+                    format!("SDO download {}", entry.label()),
+                    node_id,
+                    command,
+                    entry,
+                    Section::new(String::from("Data"), 4, value, false, 0)
+                        .with_byte_order(ByteOrder::LittleEndian),
+                ));
+            }
+            size => {
+                download_segments = true;
+                // Initiate a segmented download, random sizes for strings and domains
+                let size = match size {
+                    Some(size) => Section::new(String::from("Size"), 4, vec![], true, size as u64),
+                    None => Section::new(
+                        String::from("Size"),
+                        4,
+                        vec![SubSec::new(String::from("Size"), 32, vec![], false, 0)],
+                        false,
+                        0,
+                    ),
+                };
+                formats.push(sdo_format(
+                    format!("SDO segmented download {}", entry.label()),
+                    node_id,
+                    SDO_DOWNLOAD_SEGMENTED,
+                    entry,
+                    size.with_byte_order(ByteOrder::LittleEndian),
+                ));
+            }
+        }
+    }
+    formats.append(&mut segment_formats(
+        node_id,
+        download_segments,
+        upload_segments,
+    ));
+
+    for (number, &base) in RPDO_BASES.iter().enumerate() {
+        formats.extend(pdo_format(
+            "RPDO", number, 0x1400, 0x1600, base, &ini, &entries, node_id,
+        ));
+    }
+    for (number, &base) in TPDO_BASES.iter().enumerate() {
+        formats.extend(pdo_format(
+            "TPDO", number, 0x1800, 0x1A00, base, &ini, &entries, node_id,
+        ));
+    }
+    Ok(formats)
+}
+
+/// Parse `[section]` headers and `key=value` lines, `;` starts a comment
+fn parse_ini(text: &str) -> Result<Ini, (usize, String)> {
+    let mut ini = Ini::new();
+    let mut current: Option<String> = None;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[') {
+            let name = name
+                .strip_suffix(']')
+                .ok_or_else(|| (i + 1, format!("unterminated section {}", line)))?;
+            let name = name.trim().to_lowercase();
+            ini.entry(name.clone()).or_default();
+            current = Some(name);
+        } else if let Some((key, value)) = line.split_once('=') {
+            let section = current
+                .as_ref()
+                .ok_or_else(|| (i + 1, String::from("value outside of a section")))?;
+            ini.get_mut(section)
+                .unwrap()
+                .insert(key.trim().to_lowercase(), value.trim().to_owned());
+        } else {
+            return Err((i + 1, format!("expected key=value, found {}", line)));
+        }
+    }
+    Ok(ini)
+}
+
+/// Parse an EDS number: decimal, 0x hex, or a sum with $NODEID in it
+fn parse_value(value: &str, node_id: u32) -> Option<i128> {
+    let value = value.trim();
+    if value.is_empty() {
+        return None;
+    }
+    value
+        .to_uppercase()
+        .split('+')
+        .map(|term| {
+            let term = term.trim();
+            if term == "$NODEID" {
+                Some(node_id as i128)
+            } else if let Some(hex) = term.strip_prefix("0X") {
+                i128::from_str_radix(hex, 16).ok()
+            } else {
+                term.parse().ok()
+            }
+        })
+        .sum()
+}
+
+/// Every variable of the object dictionary, objects with sub indices are split up
+fn entries(ini: &Ini, node_id: u32) -> Result<Vec<Entry>, String> {
+    let mut entries = vec![];
+    for (name, section) in ini {
+        let index = match parse_index(name) {
+            Some(index) => index,
+            None => continue,
+        };
+        let object_type = section
+            .get("objecttype")
+            .and_then(|value| parse_value(value, node_id))
+            .unwrap_or(0x7);
+        // ARRAY and RECORD objects only hold their sub indices
+        if object_type == 0x8 || object_type == 0x9 {
+            continue;
+        }
+        let (index, sub_index) = match name.split_once("sub") {
+            Some((_, sub)) => (
+                index,
+                u8::from_str_radix(sub, 16)
+                    .map_err(|_| format!("invalid sub index in [{}]", name))?,
+            ),
+            None => (index, 0),
+        };
+        let access = section
+            .get("accesstype")
+            .map(|access| access.to_lowercase())
+            .unwrap_or_default();
+        let value = |key: &str| section.get(key).and_then(|v| parse_value(v, node_id));
+        entries.push(Entry {
+            index,
+            sub_index,
+            name: section.get("parametername").cloned().unwrap_or_default(),
+            data_type: value("datatype").unwrap_or(0) as u16,
+            readable: matches!(access.as_str(), "ro" | "rw" | "rwr" | "rww" | "const"),
+            writable: matches!(access.as_str(), "wo" | "rw" | "rwr" | "rww"),
+            low: value("lowlimit"),
+            high: value("highlimit"),
+        });
+    }
+    Ok(entries)
+}
+
+/// Index of an object section name like `1018` or `1018sub2`
+fn parse_index(name: &str) -> Option<u16> {
+    let index = name.split("sub").next()?;
+    if index.len() != 4 {
+        return None;
+    }
+    u16::from_str_radix(index, 16).ok()
+}
+
+/// SDO request addressing `entry`: command, index (little endian), sub index and data
+fn sdo_format(name: String, node_id: u32, command: u8, entry: &Entry, data: Section) -> MsgFormat {
+    let id = SDO_RX_BASE + node_id;
+    let sections = vec![
+        Section::new(String::from("Command"), 1, vec![], true, command as u64),
+        Section::new(String::from("Index"), 2, vec![], true, entry.index as u64)
+            .with_byte_order(ByteOrder::LittleEndian),
+        Section::new(
+            String::from("SubIndex"),
+            1,
+            vec![],
+            true,
+            entry.sub_index as u64,
+        ),
+        data,
+    ];
+    MsgFormat::new(name, id..id + 1, vec![], 4, sections, false, 0)
+}
+
+fn zero_data() -> Section {
+    Section::new(String::from("Data"), 4, vec![], true, 0)
+}
+
+/// Download and upload segments, they don't name an entry so one of each is enough
+fn segment_formats(node_id: u32, download: bool, upload: bool) -> Vec<MsgFormat> {
+    let id = SDO_RX_BASE + node_id;
+    let fixed = |name: &str, bits: u8, value: u64| {
+        SubSec::new(String::from(name), bits, vec![], true, value)
+    };
+    let random = |name: &str, bits: u8| SubSec::new(String::from(name), bits, vec![], false, 0);
+
+    let mut formats = vec![];
+    if download {
+        let sections = vec![
+            Section::new(
+                String::from("Command"),
+                1,
+                vec![
+                    fixed("ccs", 3, SDO_CCS_DOWNLOAD_SEGMENT),
+                    random("Toggle", 1),
+                    random("Unused", 3),
+                    random("Last", 1),
+                ],
+                false,
+                0,
+            ),
+            Section::new(String::from("Data"), 7, vec![], false, 0),
+        ];
+        let name = String::from("SDO download segment");
+        formats.push(MsgFormat::new(
+            name,
+            id..id + 1,
+            vec![],
+            2,
+            sections,
+            false,
+            0,
+        ));
+    }
+    if upload {
+        let sections = vec![
+            Section::new(
+                String::from("Command"),
+                1,
+                vec![
+                    fixed("ccs", 3, SDO_CCS_UPLOAD_SEGMENT),
+                    random("Toggle", 1),
+                    fixed("Reserved", 4, 0),
+                ],
+                false,
+                0,
+            ),
+            Section::new(String::from("Reserved"), 7, vec![], true, 0),
+        ];
+        let name = String::from("SDO upload segment");
+        formats.push(MsgFormat::new(
+            name,
+            id..id + 1,
+            vec![],
+            2,
+            sections,
+            false,
+            0,
+        ));
+    }
+    formats
+}
+
+/// Format of the `number`th PDO from its communication and mapping parameters
+/// None when the PDO isn't in the file, maps nothing or is marked invalid
+#[allow(clippy::too_many_arguments)]
+fn pdo_format(
+    kind: &str,
+    number: usize,
+    comm_base: u16,
+    mapping_base: u16,
+    default_base: u32,
+    ini: &Ini,
+    entries: &[Entry],
+    node_id: u32,
+) -> Option<MsgFormat> {
+    let mapping_index = mapping_base + number as u16;
+    // DCF files hold the configured value, EDS files only the default
+    let value = |index: u16, sub: u8| {
+        let section = ini.get(&format!("{:04x}sub{:x}", index, sub))?;
+        section
+            .get("parametervalue")
+            .or_else(|| section.get("defaultvalue"))
+            .and_then(|value| parse_value(value, node_id))
+    };
+
+    let count = value(mapping_index, 0)?;
+    let mut sub_secs = vec![];
+    let mut bits = 0;
+    // A mapping holds at most 64 entries, a negative count maps nothing
+    for sub in 1..=count.clamp(0, 64) as u8 {
+        let mapping = value(mapping_index, sub)? as u32;
+        let (index, sub_index, len) = ((mapping >> 16) as u16, (mapping >> 8) as u8, mapping as u8);
+        if len == 0 {
+            continue;
+        }
+        let sub_sec = match entries
+            .iter()
+            .find(|entry| entry.index == index && entry.sub_index == sub_index)
+        {
+            Some(entry) => entry.value_sub_sec(len as usize),
+            None => SubSec::new(
+                format!("0x{:04X}sub{}", index, sub_index),
+                len,
+                vec![],
+                false,
+                0,
+            ),
+        };
+        sub_secs.push(sub_sec);
+        bits += len as usize;
+    }
+    if bits == 0 || bits > 64 {
+        return None;
+    }
+    let num_bytes = bits.div_ceil(8);
+    padding(&mut sub_secs, bits, num_bytes * 8);
+
+    // COB-ID from the communication parameter, bit 31 marks the PDO as unused
+    let cob_id = value(comm_base + number as u16, 1)
+        .map(|cob_id| cob_id as u32)
+        .unwrap_or(default_base + node_id);
+    if cob_id & 0x8000_0000 != 0 {
+        return None;
+    }
+    let extended = cob_id & 0x2000_0000 != 0;
+    let id = cob_id & if extended { 0x1FFF_FFFF } else { 0x7FF };
+
+    let section = Section::new(String::from("Mapped"), num_bytes as u8, sub_secs, false, 0)
+        .with_byte_order(ByteOrder::LittleEndian);
+    Some(
+        MsgFormat::new(
+            format!("{}{} (0x{:04X})", kind, number + 1, mapping_index),
+            id..id + 1,
+            vec![],
+            1,
+            vec![section],
+            false,
+            0,
+        )
+        .with_extended(extended),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg_processor::msg_processor;
+    use crate::validate::validate;
+
+    const EDS: &str = r#"
+[DeviceInfo]
+VendorName=Test
+
+[1008]
+ParameterName=Manufacturer device name
+ObjectType=0x7
+DataType=0x0009
+AccessType=const
+
+[1017]
+ParameterName=Producer heartbeat time
+ObjectType=0x7
+DataType=0x0006
+AccessType=rw
+DefaultValue=0
+
+[1600]
+ParameterName=RPDO1 mapping
+ObjectType=0x9
+SubNumber=3
+
+[1600sub0]
+ParameterName=Number of entries
+DataType=0x0005
+AccessType=rw
+DefaultValue=2
+
+[1600sub1]
+ParameterName=Mapped object 1
+DataType=0x0007
+AccessType=rw
+DefaultValue=0x60400010
+
+[1600sub2]
+ParameterName=Mapped object 2
+DataType=0x0007
+AccessType=rw
+DefaultValue=0x60600008
+
+[1400sub1]
+ParameterName=COB-ID
+DataType=0x0007
+AccessType=rw
+DefaultValue=$NODEID+0x200
+
+[6040]
+ParameterName=Controlword
+ObjectType=0x7
+DataType=0x0006
+AccessType=rww
+LowLimit=0x0006
+HighLimit=0x0006
+
+[6060]
+ParameterName=Mode
+ObjectType=0x7
+DataType=0x0002
+AccessType=rw
+"#;
+
+    fn find<'a>(formats: &'a [MsgFormat], name: &str) -> &'a MsgFormat {
+        formats
+            .iter()
+            .find(|format| format.name().starts_with(name))
+            .unwrap_or_else(|| panic!("no format named {}", name))
+    }
+
+    #[test]
+    fn it_generates_sdo_and_pdo_formats() {
+        let formats = parse_eds(EDS, Path::new("test.eds"), Some(5)).unwrap();
+        for format in &formats {
+            assert_eq!(validate(format, Path::new("test.eds")), vec![]);
+        }
+        let mut rng = rand::thread_rng();
+
+        let download = find(&formats, "SDO download 0x6040sub0");
+        assert_eq!(download.cob_id_range(), &(0x605..0x606));
+        assert_eq!(
+            msg_processor(download, &mut rng).unwrap().data,
+            vec![0x2B, 0x40, 0x60, 0x00, 0x06, 0x00, 0x00, 0x00]
+        );
+        let upload = find(&formats, "SDO upload 0x1017sub0");
+        assert_eq!(
+            msg_processor(upload, &mut rng).unwrap().data,
+            vec![0x40, 0x17, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00]
+        );
+
+        // Strings can't be written here, and are only read
+        assert!(formats
+            .iter()
+            .all(|format| !format.name().starts_with("SDO download 0x1008")));
+        find(&formats, "SDO upload 0x1008sub0");
+        find(&formats, "SDO upload segment");
+
+        let rpdo = find(&formats, "RPDO1");
+        assert_eq!(rpdo.cob_id_range(), &(0x205..0x206));
+        let data = msg_processor(rpdo, &mut rng).unwrap().data;
+        assert_eq!(&data[..2], &[0x06, 0x00]);
+        assert_eq!(&data[3..], &[0; 5]);
+        assert!(formats
+            .iter()
+            .all(|format| !format.name().starts_with("TPDO")));
+    }
+
+    #[test]
+    fn it_needs_a_node_id() {
+        assert!(matches!(
+            parse_eds(EDS, Path::new("test.eds"), None),
+            Err(FuzzerError::ImportParse { .. })
+        ));
+        // DCF files carry their own
+        let dcf = format!("[DeviceComissioning]\nNodeID=0x10\n{}", EDS);
+        let formats = parse_eds(&dcf, Path::new("test.dcf"), Some(5)).unwrap();
+        assert_eq!(find(&formats, "RPDO1").cob_id_range(), &(0x210..0x211));
+    }
+
+    #[test]
+    fn it_maps_nothing_for_negative_mapping_counts() {
+        let eds = EDS.replace(
+            "Number of entries\nDataType=0x0005\nAccessType=rw\nDefaultValue=2",
+            "Number of entries\nDataType=0x0005\nAccessType=rw\nDefaultValue=-1",
+        );
+        assert_ne!(eds, EDS);
+        let formats = parse_eds(&eds, Path::new("test.eds"), Some(5)).unwrap();
+        assert!(formats
+            .iter()
+            .all(|format| !format.name().starts_with("RPDO1")));
+    }
+
+    #[test]
+    fn it_parses_eds_values() {
+        assert_eq!(parse_value("0x1A", 0), Some(0x1A));
+        assert_eq!(parse_value("-5", 0), Some(-5));
+        assert_eq!(parse_value("$NODEID+0x180", 3), Some(0x183));
+        assert_eq!(parse_value("", 3), None);
+        assert_eq!(parse_index("1600sub1"), Some(0x1600));
+        assert_eq!(parse_index("deviceinfo"), None);
+    }
+}
@@ -0,0 +1,242 @@
+use crate::frame::CanFrame;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Base id of CANopen EMCY messages, sent on 0x080 + node id
+pub const EMCY: u32 = 0x080;
+
+/// Meaning of each error register (0x1001) bit, lowest bit first
+const REGISTER_BITS: [&str; 8] = [
+    "generic",
+    "current",
+    "voltage",
+    "temperature",
+    "communication",
+    "device profile specific",
+    "reserved",
+    "manufacturer specific",
+];
+
+/// A CANopen emergency message a node sent to report an error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Emcy {
+    pub node: u8,
+    /// Emergency error code, little endian in the first two bytes
+    pub code: u16,
+    /// The node's error register (0x1001)
+    pub register: u8,
+    pub manufacturer: [u8; 5],
+}
+
+impl Emcy {
+    /// Decode a frame heard on 0x081 - 0x0FF, bytes missing from a short frame read as 0
+    pub fn from_frame(frame: &CanFrame) -> Option<Emcy> {
+        let node = frame.id().checked_sub(EMCY)?;
+        let data = frame.data();
+        if !(1..=0x7F).contains(&node)
+            || frame.is_extended()
+            || frame.is_rtr()
+            || frame.is_error()
+            || data.len() < 2
+        {
+            return None;
+        }
+        let byte = |i: usize| data.get(i).copied().unwrap_or_default();
+        let mut manufacturer = [0; 5];
+        for (i, byte_out) in manufacturer.iter_mut().enumerate() {
+            *byte_out = byte(3 + i);
+        }
+        Some(Emcy {
+            node: node as u8,
+            code: u16::from_le_bytes([byte(0), byte(1)]),
+            register: byte(2),
+            manufacturer,
+        })
+    }
+
+    /// Whether the node is reporting that its errors are gone rather than a new one
+    pub fn is_reset(&self) -> bool {
+        self.code == 0
+    }
+
+    /// Error code class from CiA 301
+    pub fn class(&self) -> &'static str {
+        match self.code >> 8 {
+            0x00 => "error reset or no error",
+            0xFF => "device specific",
+            _ => match self.code >> 12 {
+                0x1 => "generic error",
+                0x2 => "current",
+                0x3 => "voltage",
+                0x4 => "temperature",
+                0x5 => "device hardware",
+                0x6 => "device software",
+                0x7 => "additional modules",
+                0x8 => "monitoring",
+                0x9 => "external error",
+                0xF => "additional functions",
+                _ => "reserved",
+            },
+        }
+    }
+
+    /// What the communication error codes of CiA 301 stand for
+    pub fn description(&self) -> Option<&'static str> {
+        match self.code {
+            0x8110 => Some("CAN overrun, objects lost"),
+            0x8120 => Some("CAN in error passive mode"),
+            0x8130 => Some("life guard or heartbeat error"),
+            0x8140 => Some("recovered from bus off"),
+            0x8150 => Some("CAN-ID collision"),
+            0x8210 => Some("PDO not processed due to length error"),
+            0x8220 => Some("PDO length exceeded"),
+            0x8230 => Some("DAM MPDO not processed, destination object not available"),
+            0x8240 => Some("unexpected SYNC data length"),
+            0x8250 => Some("RPDO timeout"),
+            _ => None,
+        }
+    }
+
+    /// Names of the error register bits set
+    pub fn register_bits(&self) -> Vec<&'static str> {
+        REGISTER_BITS
+            .iter()
+            .enumerate()
+            .filter(|&(bit, _)| self.register & (1 << bit) != 0)
+            .map(|(_, name)| *name)
+            .collect()
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "node": self.node,
+            "code": format!("{:04X}", self.code),
+            "class": self.class(),
+            "description": self.description(),
+            "register": self.register,
+            "register_bits": self.register_bits(),
+            "manufacturer": self
+                .manufacturer
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect::<String>(),
+        })
+    }
+}
+
+impl fmt::Display for Emcy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "EMCY from node {}: {:04X} {}",
+            self.node,
+            self.code,
+            self.class()
+        )?;
+        if let Some(description) = self.description() {
+            write!(f, ", {}", description)?;
+        }
+        write!(f, ", error register 0x{:02X}", self.register)?;
+        let bits = self.register_bits();
+        if !bits.is_empty() {
+            write!(f, " ({})", bits.join(", "))?;
+        }
+        write!(f, ", manufacturer data")?;
+        for byte in &self.manufacturer {
+            write!(f, " {:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Tally of the errors nodes reported in EMCY messages during a run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FaultReport {
+    /// Reports counted by node and error code, with the last one of each
+    pub faults: BTreeMap<(u8, u16), (usize, Emcy)>,
+}
+
+impl FaultReport {
+    /// Count an EMCY message, error resets aren't faults
+    pub fn add(&mut self, emcy: Emcy) {
+        if emcy.is_reset() {
+            return;
+        }
+        let entry = self
+            .faults
+            .entry((emcy.node, emcy.code))
+            .or_insert((0, emcy));
+        *entry = (entry.0 + 1, emcy);
+    }
+
+    pub fn len(&self) -> usize {
+        self.faults.values().map(|(count, _)| count).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.faults.is_empty()
+    }
+}
+
+impl fmt::Display for FaultReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} target-reported fault(s)", self.len())?;
+        for (count, emcy) in self.faults.values() {
+            write!(f, "\n  {}: {} time(s)", emcy, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(id: u32, data: &[u8]) -> CanFrame {
+        CanFrame::new(id, data, false, false).unwrap()
+    }
+
+    #[test]
+    fn it_decodes_emcy_frames() {
+        let emcy = Emcy::from_frame(&frame(0x085, &[0x10, 0x81, 0x11, 1, 2, 3, 4, 5])).unwrap();
+        assert_eq!(emcy.node, 5);
+        assert_eq!(emcy.code, 0x8110);
+        assert_eq!(emcy.class(), "monitoring");
+        assert_eq!(emcy.description(), Some("CAN overrun, objects lost"));
+        assert_eq!(emcy.register_bits(), ["generic", "communication"]);
+        assert_eq!(emcy.manufacturer, [1, 2, 3, 4, 5]);
+        assert_eq!(
+            emcy.to_string(),
+            "EMCY from node 5: 8110 monitoring, CAN overrun, objects lost, \
+             error register 0x11 (generic, communication), manufacturer data 01 02 03 04 05"
+        );
+        assert_eq!(emcy.to_json()["code"], "8110");
+
+        let short = Emcy::from_frame(&frame(0x0FF, &[0x00, 0x42])).unwrap();
+        assert_eq!(short.class(), "temperature");
+        assert_eq!(short.manufacturer, [0; 5]);
+        // SYNC, TIME and frames too short for an error code
+        assert_eq!(Emcy::from_frame(&frame(0x080, &[0x10, 0x81])), None);
+        assert_eq!(Emcy::from_frame(&frame(0x100, &[0x10, 0x81])), None);
+        assert_eq!(Emcy::from_frame(&frame(0x085, &[0x10])), None);
+    }
+
+    #[test]
+    fn it_tallies_faults_without_resets() {
+        let mut report = FaultReport::default();
+        for data in &[
+            [0x10, 0x81, 0x11, 0, 0, 0, 0, 0],
+            [0x00, 0x00, 0x00, 0, 0, 0, 0, 0],
+            [0x10, 0x81, 0x11, 0, 0, 0, 0, 1],
+            [0x00, 0x50, 0x01, 0, 0, 0, 0, 0],
+        ] {
+            report.add(Emcy::from_frame(&frame(0x085, data)).unwrap());
+        }
+        assert_eq!(report.len(), 3);
+        let (count, last) = report.faults[&(5, 0x8110)];
+        assert_eq!(count, 2);
+        assert_eq!(last.manufacturer, [0, 0, 0, 0, 1]);
+        assert!(report.to_string().starts_with("3 target-reported fault(s)"));
+    }
+}
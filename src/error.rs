@@ -0,0 +1,77 @@
+use crate::bit_writer::PackError;
+use crate::can_bus::BusError;
+use crate::frame::FrameError;
+use crate::validate::ValidationError;
+use socketcan::CANSocketOpenError;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Errors returned by the fuzzer library
+///
+/// Every fallible step of loading formats, setting up buses and sending
+/// frames ends up here so callers can match on what went wrong.
+#[derive(Debug, Error)]
+pub enum FuzzerError {
+    /// A message format file couldn't be read
+    #[error("unable to read {}: {source}", path.display())]
+    ConfigRead { path: PathBuf, source: io::Error },
+    /// A message format file isn't valid JSON or doesn't match the format schema
+    #[error("unable to parse {}: {source}", path.display())]
+    ConfigParse {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    /// A DBC (or other network description) file couldn't be turned into message formats
+    #[error("unable to import {}:{line}: {reason}", path.display())]
+    ImportParse {
+        path: PathBuf,
+        line: usize,
+        reason: String,
+    },
+    /// A log being replayed or read as a corpus couldn't be read
+    #[error("unable to read log {}: {source}", path.display())]
+    LogRead { path: PathBuf, source: io::Error },
+    /// A line of a log being replayed isn't a logged frame
+    #[error("{}:{line}: {reason}", path.display())]
+    LogParse {
+        path: PathBuf,
+        line: usize,
+        reason: String,
+    },
+    /// Message formats were parsed but can't be used as they are
+    #[error(transparent)]
+    Validation(#[from] ValidationError),
+    /// A socket couldn't be opened on a CAN interface
+    #[error("unable to open {channel}: {source}")]
+    TransportOpen {
+        channel: String,
+        source: CANSocketOpenError,
+    },
+    /// A log, finding or converted message format couldn't be written
+    #[error("unable to write {}: {source}", path.display())]
+    LogWrite { path: PathBuf, source: io::Error },
+    /// Sending or receiving on a transport failed, or a frame couldn't be added to an open log
+    #[error("transport error: {0}")]
+    Transport(#[from] io::Error),
+    /// A CAN interface couldn't be created or brought up
+    #[error("unable to set up bus: {0}")]
+    BusSetup(#[from] BusError),
+    /// Id, data or flags don't make a valid frame
+    #[error("unable to build frame: {0}")]
+    Frame(#[from] FrameError),
+    /// Generated values don't fit where the message format puts them
+    #[error("unable to pack message: {0}")]
+    Pack(#[from] PackError),
+    /// The watched node wasn't heard before minimizing could replay a candidate
+    #[error("node {node} wasn't heard within {} ms", waited.as_millis())]
+    TargetSilent { node: u8, waited: Duration },
+    /// A command line value couldn't be parsed
+    #[error("invalid value {value:?} for --{arg}: {reason}")]
+    InvalidArgument {
+        arg: String,
+        value: String,
+        reason: String,
+    },
+}
@@ -0,0 +1,293 @@
+use crate::emcy::Emcy;
+use crate::frame::{CanFrame, ERR_BUSOFF};
+use crate::liveness::LivenessEvent;
+use crate::msg_processor::FieldTrace;
+use crate::recorder::{candump_frame, candump_line, Direction, TraceField};
+use serde_json::json;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// A frame the fuzzer sent, with what it was generated from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SentFrame {
+    /// Time since the unix epoch
+    pub timestamp: Duration,
+    pub channel: String,
+    pub frame: CanFrame,
+    /// Message format the frame was generated from
+    pub format: Option<String>,
+    /// Section and sub section values, when generated from a format
+    pub trace: Vec<FieldTrace>,
+    /// Mutation made to a corpus frame
+    pub mutation: Option<String>,
+}
+
+/// Ring buffer of the last frames sent, oldest first
+#[derive(Debug, Clone)]
+pub struct History {
+    frames: VecDeque<SentFrame>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            frames: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Add a frame, dropping the oldest one when full
+    pub fn push(&mut self, frame: SentFrame) {
+        if self.frames.len() == self.capacity {
+            self.frames.pop_front();
+        }
+        if self.capacity > 0 {
+            self.frames.push_back(frame);
+        }
+    }
+
+    pub fn frames(&self) -> Vec<SentFrame> {
+        self.frames.iter().cloned().collect()
+    }
+}
+
+/// Something the target or bus did that suggests a fault was found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Anomaly {
+    Liveness(LivenessEvent),
+    /// EMCY message reporting an error
+    Emcy(Emcy),
+    ErrorFrame(CanFrame),
+    /// A controller on the bus stopped taking part
+    BusOff(CanFrame),
+}
+
+impl Anomaly {
+    /// Anomaly shown by a frame heard on the bus, if any
+    /// EMCY messages only count from `node` when given, and not when they clear errors
+    pub fn from_frame(frame: &CanFrame, node: Option<u8>) -> Option<Anomaly> {
+        if frame.is_error() {
+            return Some(if frame.id() & ERR_BUSOFF != 0 {
+                Anomaly::BusOff(*frame)
+            } else {
+                Anomaly::ErrorFrame(*frame)
+            });
+        }
+        Emcy::from_frame(frame)
+            .filter(|emcy| !emcy.is_reset() && node.is_none_or(|node| node == emcy.node))
+            .map(Anomaly::Emcy)
+    }
+
+    /// Short name used in file names
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Anomaly::Liveness(LivenessEvent::Silent { .. }) => "silent",
+            Anomaly::Liveness(LivenessEvent::Recovered { .. }) => "recovered",
+            Anomaly::Liveness(LivenessEvent::BootUp { .. }) => "boot-up",
+            Anomaly::Liveness(LivenessEvent::StateChange { .. }) => "state-change",
+            Anomaly::Emcy(_) => "emcy",
+            Anomaly::ErrorFrame(_) => "error-frame",
+            Anomaly::BusOff(_) => "bus-off",
+        }
+    }
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Anomaly::Liveness(event) => write!(f, "{}", event),
+            Anomaly::Emcy(emcy) => write!(f, "{}", emcy),
+            Anomaly::ErrorFrame(frame) => write!(f, "error frame {}", candump_frame(frame)),
+            Anomaly::BusOff(frame) => write!(f, "bus-off {}", candump_frame(frame)),
+        }
+    }
+}
+
+/// Anomaly seen during a run, with the frames last sent before it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Incident {
+    pub channel: String,
+    pub anomaly: Anomaly,
+    /// Most recent last
+    pub suspects: Vec<SentFrame>,
+}
+
+impl fmt::Display for Incident {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} on {}, suspected trigger(s):",
+            self.anomaly, self.channel
+        )?;
+        for sent in &self.suspects {
+            write!(f, "\n  {} {}", sent.channel, candump_frame(&sent.frame))?;
+        }
+        Ok(())
+    }
+}
+
+/// Directory incidents are saved to, each as a candump log of its suspects that can be
+/// replayed, next to a json file describing the anomaly and how each frame was generated
+#[derive(Debug, Clone)]
+pub struct Findings {
+    dir: PathBuf,
+    next: usize,
+}
+
+impl Findings {
+    /// Create the directory if needed, numbering continues after earlier findings
+    pub fn create(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let next = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name();
+                name.to_str()?.split('-').next()?.parse::<usize>().ok()
+            })
+            .max()
+            .map_or(1, |last| last + 1);
+        Ok(Self {
+            dir: dir.to_owned(),
+            next,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Save an incident, returns the path of its replayable log
+    pub fn save(&mut self, incident: &Incident, seed: u64) -> io::Result<PathBuf> {
+        let stem = format!("{:04}-{}", self.next, incident.anomaly.kind());
+        self.next += 1;
+
+        let log: String = incident
+            .suspects
+            .iter()
+            .map(|sent| candump_line(sent.timestamp, &sent.channel, &sent.frame, Direction::Tx))
+            .collect();
+        let log_path = self.dir.join(format!("{}.log", stem));
+        fs::write(&log_path, log)?;
+
+        let frames: Vec<_> = incident
+            .suspects
+            .iter()
+            .map(|sent| {
+                json!({
+                    "channel": sent.channel,
+                    "frame": candump_frame(&sent.frame),
+                    "format": sent.format,
+                    "mutation": sent.mutation,
+                    "fields": sent.trace.iter().map(TraceField::from).collect::<Vec<_>>(),
+                })
+            })
+            .collect();
+        let mut description = json!({
+            "anomaly": incident.anomaly.to_string(),
+            "kind": incident.anomaly.kind(),
+            "channel": incident.channel,
+            "seed": seed,
+            "frames": frames,
+        });
+        if let Anomaly::Emcy(emcy) = incident.anomaly {
+            description["emcy"] = emcy.to_json();
+        }
+        fs::write(
+            self.dir.join(format!("{}.json", stem)),
+            serde_json::to_string_pretty(&description)?,
+        )?;
+        Ok(log_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::ERR_FLAG;
+    use crate::replay::read_log;
+    use tempfile::tempdir;
+
+    fn frame(id: u32, data: &[u8]) -> CanFrame {
+        CanFrame::new(id, data, false, false).unwrap()
+    }
+
+    fn sent(id: u32) -> SentFrame {
+        SentFrame {
+            timestamp: Duration::from_millis(u64::from(id)),
+            channel: String::from("vcan0"),
+            frame: frame(id, &[0x01]),
+            format: None,
+            trace: vec![],
+            mutation: Some(String::from("bit flip")),
+        }
+    }
+
+    #[test]
+    fn it_keeps_the_last_frames_sent() {
+        let mut history = History::new(3);
+        for id in 1..=5 {
+            history.push(sent(id));
+        }
+        let ids: Vec<u32> = history.frames().iter().map(|s| s.frame.id()).collect();
+        assert_eq!(ids, [3, 4, 5]);
+    }
+
+    #[test]
+    fn it_spots_anomalies_in_frames_heard() {
+        let bus_off = CanFrame::from_raw(ERR_FLAG | ERR_BUSOFF, &[0; 8]).unwrap();
+        assert_eq!(
+            Anomaly::from_frame(&bus_off, None),
+            Some(Anomaly::BusOff(bus_off))
+        );
+        let emcy = frame(0x085, &[0x10, 0x81, 0x11, 0, 0, 0, 0, 0]);
+        let decoded = Emcy::from_frame(&emcy).unwrap();
+        assert_eq!(
+            Anomaly::from_frame(&emcy, None),
+            Some(Anomaly::Emcy(decoded))
+        );
+        assert_eq!(
+            Anomaly::from_frame(&emcy, Some(5)),
+            Some(Anomaly::Emcy(decoded))
+        );
+        assert_eq!(Anomaly::from_frame(&emcy, Some(6)), None);
+        // Error reset and other traffic
+        assert_eq!(Anomaly::from_frame(&frame(0x085, &[0; 8]), None), None);
+        assert_eq!(Anomaly::from_frame(&frame(0x185, &[0x10]), None), None);
+    }
+
+    #[test]
+    fn it_saves_replayable_findings() {
+        let dir = tempdir().unwrap();
+        let incident = Incident {
+            channel: String::from("vcan0"),
+            anomaly: Anomaly::Emcy(Emcy::from_frame(&frame(0x085, &[0x10, 0x81])).unwrap()),
+            suspects: vec![sent(0x601), sent(0x602)],
+        };
+        let mut findings = Findings::create(dir.path()).unwrap();
+        let first = findings.save(&incident, 42).unwrap();
+        assert!(first.ends_with("0001-emcy.log"));
+
+        let logged = read_log(&first).unwrap();
+        assert_eq!(logged.len(), 2);
+        assert_eq!(logged[1].frame, incident.suspects[1].frame);
+        assert_eq!(logged[1].timestamp, Some(Duration::from_millis(0x602)));
+        let description: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(first.with_extension("json")).unwrap())
+                .unwrap();
+        assert_eq!(description["seed"], 42);
+        assert_eq!(description["frames"][0]["mutation"], "bit flip");
+        assert_eq!(description["emcy"]["class"], "monitoring");
+
+        // Numbering carries on from what is in the directory
+        let mut findings = Findings::create(dir.path()).unwrap();
+        assert!(findings
+            .save(&incident, 42)
+            .unwrap()
+            .ends_with("0002-emcy.log"));
+    }
+}
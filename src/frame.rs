@@ -0,0 +1,317 @@
+use thiserror::Error;
+
+/// Largest 11-bit standard frame identifier
+pub const SFF_MASK: u32 = 0x0000_07FF;
+/// Largest 29-bit extended frame identifier
+pub const EFF_MASK: u32 = 0x1FFF_FFFF;
+/// Extended frame format flag in a kernel can_id
+pub const EFF_FLAG: u32 = 0x8000_0000;
+/// Remote transmission request flag in a kernel can_id
+pub const RTR_FLAG: u32 = 0x4000_0000;
+/// Error frame flag in a kernel can_id
+pub const ERR_FLAG: u32 = 0x2000_0000;
+/// Error class of an error frame reporting the controller went bus-off
+pub const ERR_BUSOFF: u32 = 0x0000_0040;
+/// Maximum payload of a classic CAN frame
+pub const CAN_MAX_DLEN: usize = 8;
+/// Maximum payload of a CAN FD frame
+pub const CANFD_MAX_DLEN: usize = 64;
+/// Bit rate switch flag in a kernel canfd_frame
+pub const CANFD_BRS: u8 = 0x01;
+/// Error state indicator flag in a kernel canfd_frame
+pub const CANFD_ESI: u8 = 0x02;
+/// Payload lengths a CAN FD frame can carry, indexed by DLC
+pub const CANFD_LENGTHS: [usize; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 12, 16, 20, 24, 32, 48, 64];
+
+/// Smallest valid CAN FD payload length that fits `len` bytes
+/// Returns None when more than 64 bytes are given
+pub fn fd_len(len: usize) -> Option<usize> {
+    CANFD_LENGTHS.iter().copied().find(|&valid| valid >= len)
+}
+
+/// Errors returned when building a frame
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum FrameError {
+    /// Identifier doesn't fit in 29 bits (or 11 bits for a standard frame)
+    #[error("CAN id 0x{0:X} is too large")]
+    IdTooLarge(u32),
+    /// Payload is longer than the frame type allows
+    #[error("{0} bytes of data does not fit in a CAN frame")]
+    TooMuchData(usize),
+    /// Flag combination that can't be put on the bus, e.g. an FD remote frame
+    #[error("invalid frame flags: {0}")]
+    InvalidFlags(&'static str),
+}
+
+/// Frame options that aren't part of the id or payload
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameFlags {
+    /// 29-bit extended frame format
+    pub extended: bool,
+    /// Remote transmission request, classic frames only
+    pub rtr: bool,
+    /// Error frame
+    pub err: bool,
+    /// CAN FD frame carrying up to 64 bytes
+    pub fd: bool,
+    /// FD bit rate switch, data phase is sent at the higher bit rate
+    pub brs: bool,
+    /// FD error state indicator, set by error passive nodes
+    pub esi: bool,
+}
+
+/// A classic or FD CAN frame independent of any backend
+///
+/// Unlike socketcan::CANFrame the extended flag is kept separately from the
+/// id, so a 29-bit frame can carry an id below 0x800
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CanFrame {
+    id: u32,
+    flags: FrameFlags,
+    len: u8,
+    data: [u8; CANFD_MAX_DLEN],
+}
+
+impl CanFrame {
+    /// Returns a frame with the given id and data
+    /// Ids above the 11-bit range are sent as extended frames
+    pub fn new(id: u32, data: &[u8], rtr: bool, err: bool) -> Result<Self, FrameError> {
+        Self::with_flags(
+            id,
+            data,
+            FrameFlags {
+                extended: id > SFF_MASK,
+                rtr,
+                err,
+                ..FrameFlags::default()
+            },
+        )
+    }
+
+    /// Returns a frame using the 29-bit extended frame format, whatever the id
+    pub fn new_extended(id: u32, data: &[u8], rtr: bool, err: bool) -> Result<Self, FrameError> {
+        Self::with_flags(
+            id,
+            data,
+            FrameFlags {
+                extended: true,
+                rtr,
+                err,
+                ..FrameFlags::default()
+            },
+        )
+    }
+
+    /// Returns a frame with the given flags
+    /// FD payloads are zero padded up to the next valid FD length (12, 16, 20, 24, 32, 48, 64)
+    pub fn with_flags(id: u32, data: &[u8], flags: FrameFlags) -> Result<Self, FrameError> {
+        if id > EFF_MASK || (!flags.extended && id > SFF_MASK) {
+            return Err(FrameError::IdTooLarge(id));
+        }
+        let len = if flags.fd {
+            if flags.rtr {
+                return Err(FrameError::InvalidFlags("CAN FD has no remote frames"));
+            }
+            fd_len(data.len()).ok_or(FrameError::TooMuchData(data.len()))?
+        } else {
+            if flags.brs || flags.esi {
+                return Err(FrameError::InvalidFlags("BRS and ESI need an FD frame"));
+            }
+            if data.len() > CAN_MAX_DLEN {
+                return Err(FrameError::TooMuchData(data.len()));
+            }
+            data.len()
+        };
+
+        let mut buf = [0; CANFD_MAX_DLEN];
+        buf[..data.len()].copy_from_slice(data);
+        Ok(Self {
+            id,
+            flags,
+            len: len as u8,
+            data: buf,
+        })
+    }
+
+    /// Returns a frame from a kernel can_id (id with EFF/RTR/ERR flags) and data
+    pub fn from_raw(can_id: u32, data: &[u8]) -> Result<Self, FrameError> {
+        Self::with_flags(
+            raw_id_without_flags(can_id),
+            data,
+            FrameFlags {
+                extended: can_id & EFF_FLAG != 0,
+                rtr: can_id & RTR_FLAG != 0,
+                err: can_id & ERR_FLAG != 0,
+                ..FrameFlags::default()
+            },
+        )
+    }
+
+    /// Returns an FD frame from a kernel can_id, canfd_frame flags and data
+    pub fn from_raw_fd(can_id: u32, fd_flags: u8, data: &[u8]) -> Result<Self, FrameError> {
+        Self::with_flags(
+            raw_id_without_flags(can_id),
+            data,
+            FrameFlags {
+                extended: can_id & EFF_FLAG != 0,
+                err: can_id & ERR_FLAG != 0,
+                fd: true,
+                brs: fd_flags & CANFD_BRS != 0,
+                esi: fd_flags & CANFD_ESI != 0,
+                ..FrameFlags::default()
+            },
+        )
+    }
+
+    /// Identifier without any flags
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Identifier with EFF/RTR/ERR flags set, as used by the kernel
+    pub fn raw_id(&self) -> u32 {
+        let mut can_id = self.id;
+        if self.flags.extended {
+            can_id |= EFF_FLAG;
+        }
+        if self.flags.rtr {
+            can_id |= RTR_FLAG;
+        }
+        if self.flags.err {
+            can_id |= ERR_FLAG;
+        }
+        can_id
+    }
+
+    /// BRS/ESI bits of a kernel canfd_frame
+    pub fn fd_flags(&self) -> u8 {
+        let mut fd_flags = 0;
+        if self.flags.brs {
+            fd_flags |= CANFD_BRS;
+        }
+        if self.flags.esi {
+            fd_flags |= CANFD_ESI;
+        }
+        fd_flags
+    }
+
+    pub fn flags(&self) -> FrameFlags {
+        self.flags
+    }
+
+    pub fn is_extended(&self) -> bool {
+        self.flags.extended
+    }
+
+    pub fn is_rtr(&self) -> bool {
+        self.flags.rtr
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.flags.err
+    }
+
+    pub fn is_fd(&self) -> bool {
+        self.flags.fd
+    }
+
+    pub fn is_brs(&self) -> bool {
+        self.flags.brs
+    }
+
+    pub fn is_esi(&self) -> bool {
+        self.flags.esi
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+
+    /// Id formatted for display, 3 hex digits or 8 for extended frames
+    pub fn id_string(&self) -> String {
+        if self.flags.extended {
+            format!("0x{:08X}", self.id)
+        } else {
+            format!("0x{:03X}", self.id)
+        }
+    }
+}
+
+fn raw_id_without_flags(can_id: u32) -> u32 {
+    if can_id & EFF_FLAG != 0 {
+        can_id & EFF_MASK
+    } else {
+        can_id & SFF_MASK
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_keeps_extended_flag_for_low_ids() {
+        let frame = CanFrame::new_extended(0x10, &[1, 2], false, false).unwrap();
+        assert!(frame.is_extended());
+        assert_eq!(frame.id(), 0x10);
+        assert_eq!(frame.raw_id(), 0x10 | EFF_FLAG);
+        assert_eq!(
+            CanFrame::from_raw(frame.raw_id(), frame.data()).unwrap(),
+            frame
+        );
+
+        // Standard frames switch to extended once the id doesn't fit 11 bits
+        assert!(!CanFrame::new(0x7FF, &[], false, false)
+            .unwrap()
+            .is_extended());
+        assert!(CanFrame::new(0x800, &[], false, false)
+            .unwrap()
+            .is_extended());
+    }
+
+    #[test]
+    fn it_rejects_invalid_frames() {
+        assert_eq!(
+            CanFrame::new_extended(EFF_MASK + 1, &[], false, false),
+            Err(FrameError::IdTooLarge(EFF_MASK + 1))
+        );
+        assert_eq!(
+            CanFrame::new(0x1, &[0; 9], false, false),
+            Err(FrameError::TooMuchData(9))
+        );
+    }
+
+    #[test]
+    fn it_pads_fd_frames_to_valid_lengths() {
+        let flags = FrameFlags {
+            fd: true,
+            brs: true,
+            ..FrameFlags::default()
+        };
+        let frame = CanFrame::with_flags(0x123, &[0xAA; 10], flags).unwrap();
+        assert_eq!(frame.data().len(), 12);
+        assert_eq!(&frame.data()[10..], &[0, 0]);
+        assert_eq!(frame.fd_flags(), CANFD_BRS);
+        assert_eq!(
+            CanFrame::from_raw_fd(frame.raw_id(), frame.fd_flags(), frame.data()).unwrap(),
+            frame
+        );
+
+        assert_eq!(fd_len(7), Some(7));
+        assert_eq!(fd_len(33), Some(48));
+        assert_eq!(fd_len(65), None);
+        assert_eq!(
+            CanFrame::with_flags(0x1, &[0; 65], flags),
+            Err(FrameError::TooMuchData(65))
+        );
+        assert!(CanFrame::with_flags(
+            0x1,
+            &[],
+            FrameFlags {
+                brs: true,
+                ..FrameFlags::default()
+            }
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,578 @@
+use crate::correlate::{Correlation, Matcher, ResponseReport};
+use crate::emcy::{Emcy, FaultReport};
+use crate::error::FuzzerError;
+use crate::findings::{Anomaly, Findings, History, Incident, SentFrame};
+use crate::frame::{CanFrame, FrameFlags};
+use crate::liveness::{Liveness, LivenessConfig, LivenessEvent};
+use crate::msg_processor::*;
+use crate::mutator::mutate;
+use crate::recorder::{Clock, Direction, FrameSink, Origin};
+use crate::rng::seeded_rng;
+use crate::strategy::{Generator, Strategy};
+use crate::transport::CanTransport;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Options controlling what the fuzz loop sends and how often
+#[derive(Debug, Clone)]
+pub struct FuzzConfig {
+    /// Time between rounds, in listen mode the window takes its place
+    pub delay: Duration,
+    /// Number of rounds to send, -1 for infinite
+    pub repeat: i64,
+    /// COB ID used when no format or random id is requested
+    pub id: u32,
+    /// Message used when no format or random message is requested
+    pub message: Vec<u8>,
+    pub random_id: bool,
+    pub random_message: bool,
+    /// Frame flags for the fixed or random messages, extended ids are drawn
+    /// from the 29-bit range and FD messages may be up to 64 bytes
+    pub flags: FrameFlags,
+    /// Formats to generate messages from, takes precedence over the options above
+    pub msg_formats: Option<Vec<MsgFormat>>,
+    /// How field values of the formats are chosen
+    pub strategy: Strategy,
+    /// Recorded frames to send mutated copies of, used when no formats are given
+    pub corpus: Option<Vec<CanFrame>>,
+    /// Collect the frames heard within window after each message
+    pub listen_mode: bool,
+    /// How long frames are collected after each message in listen mode
+    pub window: Duration,
+    /// Say which responses answer which requests, any frame heard answers a
+    /// request none of them cover
+    pub matchers: Vec<Matcher>,
+    /// Watch the target's heartbeat or guard it in listen mode, to tell when it fails
+    pub liveness: Option<LivenessConfig>,
+    /// Number of frames last sent that are kept as the suspects of an anomaly
+    pub history: usize,
+    /// Directory each anomaly seen in listen mode is saved to with its suspects
+    pub findings: Option<PathBuf>,
+    /// Print the section and sub section values of every frame generated from a format
+    pub trace: bool,
+    /// Seed for every random choice made while generating frames
+    pub seed: u64,
+}
+
+/// What a run sent and heard
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunSummary {
+    pub sent: usize,
+    /// Responses to the frames sent, only tallied in listen mode
+    pub responses: ResponseReport,
+    /// Anomalies seen in listen mode
+    pub incidents: Vec<Incident>,
+    /// Errors the target reported in EMCY messages heard in listen mode
+    pub faults: FaultReport,
+}
+
+/// Main fuzz loop, each round sends one message on every transport
+/// Every frame sent, and in listen mode every response, is recorded to `sink`
+pub fn run(
+    config: &FuzzConfig,
+    transports: &[Box<dyn CanTransport>],
+    mut sink: Option<&mut (dyn FrameSink + '_)>,
+) -> Result<RunSummary, FuzzerError> {
+    let mut repeat = config.repeat;
+    let mut id = config.id;
+    let mut message_parsed = config.message.clone();
+    let mut flags = config.flags;
+    let mut rng = seeded_rng(config.seed);
+    let formats = config.msg_formats.as_deref().unwrap_or_default();
+    let mut generator = Generator::new(config.strategy, formats);
+    let mut sent: usize = 0;
+    let mut summary = RunSummary::default();
+    // One node is watched on every channel, each with the frames last sent on it
+    let mut monitors: Vec<Liveness> = match config.liveness {
+        Some(liveness) => transports
+            .iter()
+            .map(|_| Liveness::new(liveness, Instant::now()))
+            .collect(),
+        None => vec![],
+    };
+    let mut histories: Vec<History> = transports
+        .iter()
+        .map(|_| History::new(config.history))
+        .collect();
+    let mut findings = match &config.findings {
+        Some(dir) => Some(
+            Findings::create(dir).map_err(|source| FuzzerError::LogWrite {
+                path: dir.clone(),
+                source,
+            })?,
+        ),
+        None => None,
+    };
+    let clock = Clock::new();
+
+    while repeat != 0 {
+        for (t, transport) in transports.iter().enumerate() {
+            if let Some(request) = monitors
+                .get_mut(t)
+                .map(|liveness| liveness.guard_request(Instant::now()))
+                .transpose()?
+                .flatten()
+            {
+                // A lost guard request shows up as a guarding timeout, the run goes on
+                match transport.send_frame(&request) {
+                    Ok(()) => {
+                        if let Some(sink) = sink.as_deref_mut() {
+                            sink.record(Direction::Tx, transport.channel(), &request)?;
+                        }
+                    }
+                    Err(e) => eprintln!(
+                        "Unable to send guard request on {}: {}",
+                        transport.channel(),
+                        e
+                    ),
+                }
+            }
+
+            // Random values come from a random format, systematic strategies
+            // take the formats in turn so each is covered evenly
+            let index = match (formats.len(), config.strategy) {
+                (0, _) => None,
+                (len, Strategy::Random) => Some(rng.gen_range(0..len)),
+                (len, _) => Some(sent % len),
+            };
+            sent = sent.wrapping_add(1);
+            // Values the frame was built from when it comes from a format
+            let mut trace = None;
+            let mut mutation = None;
+            if let Some(index) = index {
+                let format = &formats[index];
+                id = random_cob_id_with_format(format, &mut rng)?;
+                flags = format.frame_flags();
+                let values = generator.values(index, format, &mut rng)?;
+                let msg = pack_msg(format, &values, &mut rng)?;
+                message_parsed = msg.data;
+                trace = Some(msg.trace);
+            } else if let Some(seed) = config
+                .corpus
+                .as_ref()
+                .and_then(|corpus| corpus.choose(&mut rng))
+            {
+                let (made, frame) = mutate(seed, &mut rng)?;
+                mutation = Some(made.to_string());
+                id = frame.id();
+                flags = frame.flags();
+                message_parsed = frame.data().to_vec();
+            } else {
+                if config.random_id && config.flags.extended {
+                    id = random_extended_cob_id(&mut rng)
+                } else if config.random_id {
+                    id = random_cob_id(&mut rng)
+                }
+                if config.random_message && config.flags.fd {
+                    message_parsed = random_fd_msg(&mut rng);
+                } else if config.random_message {
+                    message_parsed = random_msg(&mut rng);
+                }
+            }
+
+            match create_frame_send_msg(transport.as_ref(), id, &message_parsed, flags) {
+                Ok(frame) => {
+                    if config.trace {
+                        print_trace(trace.as_deref().unwrap_or_default());
+                    }
+                    if let Some(sink) = sink.as_deref_mut() {
+                        match index.zip(trace.as_deref()) {
+                            Some((index, trace)) => {
+                                let origin = Origin {
+                                    format: &formats[index],
+                                    trace,
+                                };
+                                sink.record_generated(transport.channel(), &frame, &origin)?
+                            }
+                            None => sink.record(Direction::Tx, transport.channel(), &frame)?,
+                        }
+                    }
+                    summary.sent += 1;
+                    histories[t].push(SentFrame {
+                        timestamp: clock.now(),
+                        channel: transport.channel().to_owned(),
+                        frame,
+                        format: index.map(|index| formats[index].name().to_owned()),
+                        trace: trace.unwrap_or_default(),
+                        mutation,
+                    });
+                    // In listen mode the window is used as delay
+                    if config.listen_mode {
+                        let mut heard =
+                            listen(transport.as_ref(), sink.as_deref_mut(), config.window)?;
+                        for emcy in heard.iter().filter_map(Emcy::from_frame) {
+                            println!("{} on {}", emcy, transport.channel());
+                            summary.faults.add(emcy);
+                        }
+                        let node = config.liveness.map(|liveness| liveness.node);
+                        let mut anomalies: Vec<Anomaly> = heard
+                            .iter()
+                            .filter_map(|frame| Anomaly::from_frame(frame, node))
+                            .collect();
+                        // Error frames come from the controller, not the target
+                        heard.retain(|frame| !frame.is_error());
+                        if let Some(liveness) = monitors.get_mut(t) {
+                            for event in check_liveness(liveness, &mut heard) {
+                                if event.is_failure() {
+                                    anomalies.push(Anomaly::Liveness(event));
+                                } else {
+                                    println!("Liveness: {}", event);
+                                }
+                            }
+                        }
+                        for anomaly in anomalies {
+                            let incident = Incident {
+                                channel: transport.channel().to_owned(),
+                                anomaly,
+                                suspects: histories[t].frames(),
+                            };
+                            println!("Anomaly: {}", incident);
+                            if let Some(findings) = findings.as_mut() {
+                                let path =
+                                    findings.save(&incident, config.seed).map_err(|source| {
+                                        FuzzerError::LogWrite {
+                                            path: findings.dir().to_owned(),
+                                            source,
+                                        }
+                                    })?;
+                                println!("Saved to {}", path.display());
+                            }
+                            summary.incidents.push(incident);
+                        }
+                        let correlation = Correlation::new(&config.matchers, &frame, heard);
+                        if !correlation.is_answered() {
+                            println!(
+                                "No response to {} on {}",
+                                frame.id_string(),
+                                transport.channel()
+                            );
+                        }
+                        for unexpected in &correlation.unexpected {
+                            println!(
+                                "Unexpected {} after {} on {}",
+                                unexpected.id_string(),
+                                frame.id_string(),
+                                transport.channel()
+                            );
+                        }
+                        summary.responses.add(&frame, &correlation);
+                    }
+                }
+                Err(e) => eprintln!("Unable to send frame on {}: {}", transport.channel(), e),
+            }
+        }
+
+        if repeat != -1 {
+            repeat -= 1;
+        }
+
+        // listen mode uses blocking read to add delay
+        if !config.listen_mode {
+            thread::sleep(config.delay);
+        }
+    }
+    Ok(summary)
+}
+
+/// Update `liveness` with the frames heard and take the node's own frames out of them,
+/// they aren't responses. Returns the changes seen
+fn check_liveness(liveness: &mut Liveness, heard: &mut Vec<CanFrame>) -> Vec<LivenessEvent> {
+    let now = Instant::now();
+    let mut events: Vec<LivenessEvent> = heard
+        .iter()
+        .flat_map(|frame| liveness.observe(frame, now))
+        .collect();
+    events.extend(liveness.check(now));
+    heard.retain(|frame| !liveness.is_liveness_frame(frame));
+    events
+}
+
+/// How long monitor waits on one transport before checking the next
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Passively print every frame heard on the transports, nothing is sent
+/// Frames are also recorded to `sink` when one is given.
+/// Runs for `duration`, or until the process is stopped when None.
+/// Returns the number of frames heard
+pub fn monitor(
+    transports: &[Box<dyn CanTransport>],
+    mut sink: Option<&mut (dyn FrameSink + '_)>,
+    duration: Option<Duration>,
+) -> Result<usize, FuzzerError> {
+    let deadline = duration.map(|d| Instant::now() + d);
+    let mut heard = 0;
+    while deadline.is_none_or(|deadline| Instant::now() < deadline) {
+        for transport in transports {
+            if let Some(frame) = transport.recv_frame(POLL_INTERVAL)? {
+                print_frame(transport.channel(), &frame);
+                if let Some(emcy) = Emcy::from_frame(&frame) {
+                    println!("  {}", emcy);
+                }
+                if let Some(sink) = sink.as_deref_mut() {
+                    sink.record(Direction::Rx, transport.channel(), &frame)?;
+                }
+                heard += 1;
+            }
+        }
+    }
+    Ok(heard)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::liveness::LivenessMode;
+    use crate::recorder::{open_sink, LogFormat};
+    use crate::replay::read_log;
+    use crate::sim_bus::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn config() -> FuzzConfig {
+        FuzzConfig {
+            delay: Duration::from_millis(0),
+            repeat: 3,
+            id: 0x10,
+            message: vec![0x01],
+            random_id: false,
+            random_message: false,
+            flags: FrameFlags::default(),
+            msg_formats: None,
+            strategy: Strategy::default(),
+            corpus: None,
+            listen_mode: false,
+            window: Duration::from_millis(0),
+            matchers: vec![],
+            liveness: None,
+            history: 8,
+            findings: None,
+            trace: false,
+            seed: 0,
+        }
+    }
+
+    #[test]
+    fn it_fuzzes_on_a_simulated_bus() {
+        let bus = SimBus::new(SimBusConfig::default());
+        let observer = bus.attach("sim0");
+        let transports: Vec<Box<dyn CanTransport>> = vec![Box::new(bus.attach("sim0"))];
+
+        let mut config = config();
+        config.msg_formats = Some(read_configs(std::path::Path::new("assets/emcy.json")).unwrap());
+        run(&config, &transports, None).unwrap();
+
+        for _ in 0..3 {
+            let frame = observer
+                .recv_frame(Duration::from_millis(10))
+                .unwrap()
+                .unwrap();
+            assert!((0x080..0x0FF).contains(&frame.id()));
+        }
+        assert!(observer
+            .recv_frame(Duration::from_millis(1))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn it_mutates_corpus_frames() {
+        let bus = SimBus::new(SimBusConfig::default());
+        let observer = bus.attach("sim0");
+        let transports: Vec<Box<dyn CanTransport>> = vec![Box::new(bus.attach("sim0"))];
+
+        let seed = CanFrame::new(0x201, &[0x01, 0x02], false, false).unwrap();
+        let mut config = config();
+        config.corpus = Some(vec![seed]);
+        config.repeat = 20;
+        run(&config, &transports, None).unwrap();
+
+        let mut sent = 0;
+        while let Some(frame) = observer.recv_frame(Duration::from_millis(1)).unwrap() {
+            assert_ne!(frame, seed);
+            assert!((0x200..=0x202).contains(&frame.id()));
+            sent += 1;
+        }
+        assert_eq!(sent, 20);
+    }
+
+    #[test]
+    fn it_logs_responses_in_listen_mode() {
+        let dir = tempdir().unwrap();
+        let log_path = dir.path().join("log.txt");
+        let bus = SimBus::new(SimBusConfig {
+            latency: Duration::from_millis(1),
+            drop_rate: 0.0,
+            seed: 0,
+        });
+        let transports: Vec<Box<dyn CanTransport>> = vec![Box::new(bus.attach("sim0"))];
+
+        // Simulated target answering every request on id + 1
+        let target = bus.attach("sim0");
+        let responder = thread::spawn(move || {
+            for _ in 0..3 {
+                let request = target.recv_frame(Duration::from_secs(5)).unwrap().unwrap();
+                let response =
+                    CanFrame::new(request.id() + 1, request.data(), false, false).unwrap();
+                target.send_frame(&response).unwrap();
+            }
+        });
+
+        let mut config = config();
+        config.listen_mode = true;
+        config.window = Duration::from_millis(100);
+        let mut sink = open_sink(&log_path, LogFormat::Candump).unwrap();
+        let summary = run(&config, &transports, Some(sink.as_mut())).unwrap();
+        responder.join().unwrap();
+        assert_eq!(summary.sent, 3);
+        assert_eq!(summary.responses.answered, 3);
+
+        let logged = read_log(&log_path).unwrap();
+        assert_eq!(logged.len(), 6);
+        for pair in logged.chunks(2) {
+            assert_eq!(pair[0].direction, Some(Direction::Tx));
+            assert_eq!(pair[0].frame.id(), 0x010);
+            assert_eq!(pair[1].direction, Some(Direction::Rx));
+            assert_eq!(pair[1].frame.id(), 0x011);
+            assert_eq!(pair[1].channel, "sim0");
+        }
+    }
+
+    #[test]
+    fn it_correlates_responses_in_listen_mode() {
+        let bus = SimBus::new(SimBusConfig {
+            latency: Duration::from_millis(1),
+            drop_rate: 0.0,
+            seed: 0,
+        });
+        let transports: Vec<Box<dyn CanTransport>> = vec![Box::new(bus.attach("sim0"))];
+
+        // Node answering the first request among a heartbeat, the second on the
+        // wrong id and the third not at all
+        let target = bus.attach("sim0");
+        let responder = thread::spawn(move || {
+            let answers: [&[u32]; 3] = [&[0x701, 0x581], &[0x582], &[]];
+            for ids in &answers {
+                target.recv_frame(Duration::from_secs(5)).unwrap().unwrap();
+                for &id in *ids {
+                    let frame = CanFrame::new(id, &[0x00], false, false).unwrap();
+                    target.send_frame(&frame).unwrap();
+                }
+            }
+        });
+
+        let mut config = config();
+        config.id = 0x601;
+        config.listen_mode = true;
+        config.window = Duration::from_millis(100);
+        config.matchers = vec![Matcher::SDO];
+        let summary = run(&config, &transports, None).unwrap();
+        responder.join().unwrap();
+
+        let report = summary.responses;
+        assert_eq!((report.requests, report.answered), (3, 1));
+        assert_eq!(report.unanswered.get(&0x601), Some(&2));
+        assert_eq!(report.unexpected.get(&(0x601, 0x701)), Some(&1));
+        assert_eq!(report.unexpected.get(&(0x601, 0x582)), Some(&1));
+    }
+
+    #[test]
+    fn it_flags_the_frames_sent_before_a_reset() {
+        let bus = SimBus::new(SimBusConfig {
+            latency: Duration::from_millis(1),
+            drop_rate: 0.0,
+            seed: 0,
+        });
+        let transports: Vec<Box<dyn CanTransport>> = vec![Box::new(bus.attach("sim0"))];
+
+        // Node 5 sends a heartbeat after each request, and boots up again after the second
+        let target = bus.attach("sim0");
+        let responder = thread::spawn(move || {
+            for state in &[0x05, 0x00, 0x7F] {
+                target.recv_frame(Duration::from_secs(5)).unwrap().unwrap();
+                let heartbeat = CanFrame::new(0x705, &[*state], false, false).unwrap();
+                target.send_frame(&heartbeat).unwrap();
+            }
+        });
+
+        let mut config = config();
+        config.listen_mode = true;
+        config.window = Duration::from_millis(50);
+        config.liveness = Some(LivenessConfig {
+            mode: LivenessMode::Heartbeat,
+            node: 5,
+            timeout: Duration::from_secs(5),
+        });
+        let summary = run(&config, &transports, None).unwrap();
+        responder.join().unwrap();
+
+        assert_eq!(summary.incidents.len(), 1);
+        let incident = &summary.incidents[0];
+        assert_eq!(
+            incident.anomaly,
+            Anomaly::Liveness(LivenessEvent::BootUp { node: 5 })
+        );
+        assert_eq!(incident.suspects.len(), 2);
+        assert_eq!(incident.suspects[1].frame.id(), 0x10);
+        // Heartbeats aren't responses
+        assert_eq!(summary.responses.answered, 0);
+    }
+
+    #[test]
+    fn it_replays_the_same_frames_from_a_seed() {
+        let mut config = config();
+        config.msg_formats = Some(read_configs(std::path::Path::new("assets")).unwrap());
+        config.repeat = 20;
+        config.seed = 1234;
+
+        let capture = |config: &FuzzConfig| {
+            let bus = SimBus::new(SimBusConfig::default());
+            let observer = bus.attach("sim0");
+            let transports: Vec<Box<dyn CanTransport>> = vec![Box::new(bus.attach("sim0"))];
+            run(config, &transports, None).unwrap();
+            let mut frames = vec![];
+            while let Some(frame) = observer.recv_frame(Duration::from_millis(1)).unwrap() {
+                frames.push((frame.id(), frame.data().to_vec()));
+            }
+            frames
+        };
+
+        let first = capture(&config);
+        assert_eq!(first.len(), 20);
+        assert_eq!(first, capture(&config));
+        config.seed = 4321;
+        assert_ne!(first, capture(&config));
+    }
+
+    #[test]
+    fn it_monitors_without_sending() {
+        let dir = tempdir().unwrap();
+        let log_path = dir.path().join("monitor.txt");
+        let bus = SimBus::new(SimBusConfig::default());
+        let transports: Vec<Box<dyn CanTransport>> = vec![Box::new(bus.attach("sim0"))];
+        let other = bus.attach("sim0");
+        for id in 0x100..0x103 {
+            other
+                .send_frame(&CanFrame::new(id, &[0xAA], false, false).unwrap())
+                .unwrap();
+        }
+
+        let mut sink = open_sink(&log_path, LogFormat::Text).unwrap();
+        let heard = monitor(
+            &transports,
+            Some(sink.as_mut()),
+            Some(Duration::from_millis(50)),
+        )
+        .unwrap();
+        assert_eq!(heard, 3);
+        let log = fs::read_to_string(&log_path).unwrap();
+        assert_eq!(log.lines().filter(|l| l.starts_with("RX")).count(), 3);
+        // Nothing was sent back on the bus
+        assert!(other
+            .recv_frame(Duration::from_millis(1))
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,292 @@
+use crate::frame::{CanFrame, FrameError};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Base id of the CANopen NMT error control frames, heartbeat, boot-up and node guarding
+pub const NMT_ERROR_CONTROL: u32 = 0x700;
+
+/// How the target's liveness is checked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LivenessMode {
+    /// Listen for the heartbeats the node produces on its own
+    Heartbeat,
+    /// Poll the node with remote frames and check its answers
+    Guard,
+}
+
+impl LivenessMode {
+    pub const NAMES: [&'static str; 2] = ["heartbeat", "guard"];
+}
+
+impl FromStr for LivenessMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "heartbeat" => Ok(LivenessMode::Heartbeat),
+            "guard" => Ok(LivenessMode::Guard),
+            _ => Err(format!("unknown liveness mode {}", s)),
+        }
+    }
+}
+
+/// What to watch and how long silence is tolerated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LivenessConfig {
+    pub mode: LivenessMode,
+    pub node: u8,
+    /// Longest time without a heartbeat, or time between guard requests
+    pub timeout: Duration,
+}
+
+impl LivenessConfig {
+    /// Longest silence before the node counts as silent. When node guarding the node is
+    /// only heard a guard time apart at best, so it's silent once a whole request goes
+    /// unanswered
+    pub fn allowed_silence(&self) -> Duration {
+        match self.mode {
+            LivenessMode::Heartbeat => self.timeout,
+            LivenessMode::Guard => self.timeout * 2,
+        }
+    }
+}
+
+/// NMT state a node reports in its heartbeat or guard response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NmtState {
+    BootUp,
+    Stopped,
+    Operational,
+    PreOperational,
+    Unknown(u8),
+}
+
+impl From<u8> for NmtState {
+    /// State from the low 7 bits, the top bit is the node guarding toggle
+    fn from(byte: u8) -> Self {
+        match byte & 0x7F {
+            0x00 => NmtState::BootUp,
+            0x04 => NmtState::Stopped,
+            0x05 => NmtState::Operational,
+            0x7F => NmtState::PreOperational,
+            other => NmtState::Unknown(other),
+        }
+    }
+}
+
+impl fmt::Display for NmtState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NmtState::BootUp => write!(f, "boot-up"),
+            NmtState::Stopped => write!(f, "stopped"),
+            NmtState::Operational => write!(f, "operational"),
+            NmtState::PreOperational => write!(f, "pre-operational"),
+            NmtState::Unknown(state) => write!(f, "unknown state 0x{:02X}", state),
+        }
+    }
+}
+
+/// Change in the target's liveness worth reporting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LivenessEvent {
+    /// Nothing heard from the node for longer than the timeout
+    Silent { node: u8, silent_for: Duration },
+    /// The node is heard again after being silent
+    Recovered { node: u8 },
+    /// The node sent a boot-up message, it has been reset
+    BootUp { node: u8 },
+    /// The node reported a different NMT state than before
+    StateChange {
+        node: u8,
+        from: NmtState,
+        to: NmtState,
+    },
+}
+
+impl LivenessEvent {
+    /// Whether the event suggests the target was crashed or reset by recent inputs
+    pub fn is_failure(&self) -> bool {
+        !matches!(self, LivenessEvent::Recovered { .. })
+    }
+}
+
+impl fmt::Display for LivenessEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LivenessEvent::Silent { node, silent_for } => {
+                write!(f, "node {} silent for {} ms", node, silent_for.as_millis())
+            }
+            LivenessEvent::Recovered { node } => write!(f, "node {} heard again", node),
+            LivenessEvent::BootUp { node } => {
+                write!(f, "node {} sent boot-up, it was reset", node)
+            }
+            LivenessEvent::StateChange { node, from, to } => {
+                write!(f, "node {} went from {} to {}", node, from, to)
+            }
+        }
+    }
+}
+
+/// Tracks one node's heartbeats or guard responses and reports when it goes quiet,
+/// resets or changes state. Times are passed in so the checks don't depend on the clock
+#[derive(Debug, Clone)]
+pub struct Liveness {
+    config: LivenessConfig,
+    /// Last time the node was heard, or when monitoring started
+    last_heard: Instant,
+    last_guard: Option<Instant>,
+    state: Option<NmtState>,
+    silent: bool,
+}
+
+impl Liveness {
+    pub fn new(config: LivenessConfig, now: Instant) -> Self {
+        Self {
+            config,
+            last_heard: now,
+            last_guard: None,
+            state: None,
+            silent: false,
+        }
+    }
+
+    /// Id the node's heartbeats and guard responses are sent on
+    pub fn id(&self) -> u32 {
+        NMT_ERROR_CONTROL + u32::from(self.config.node)
+    }
+
+    /// Whether `frame` is one of the node's heartbeats or guard responses
+    pub fn is_liveness_frame(&self, frame: &CanFrame) -> bool {
+        frame.id() == self.id() && !frame.is_extended() && !frame.is_rtr() && !frame.is_error()
+    }
+
+    /// Remote frame polling the node when node guarding and a request is due
+    pub fn guard_request(&mut self, now: Instant) -> Result<Option<CanFrame>, FrameError> {
+        if self.config.mode != LivenessMode::Guard
+            || self
+                .last_guard
+                .is_some_and(|last| now.duration_since(last) < self.config.timeout)
+        {
+            return Ok(None);
+        }
+        self.last_guard = Some(now);
+        CanFrame::new(self.id(), &[0], true, false).map(Some)
+    }
+
+    /// Update with a frame heard on the bus, other nodes' frames are ignored
+    pub fn observe(&mut self, frame: &CanFrame, now: Instant) -> Vec<LivenessEvent> {
+        let node = self.config.node;
+        let mut events = vec![];
+        let state = match frame.data().first() {
+            Some(&byte) if self.is_liveness_frame(frame) => NmtState::from(byte),
+            _ => return events,
+        };
+        self.last_heard = now;
+        if self.silent {
+            self.silent = false;
+            events.push(LivenessEvent::Recovered { node });
+        }
+        if state == NmtState::BootUp {
+            events.push(LivenessEvent::BootUp { node });
+        } else if let Some(from) = self.state.filter(|&from| from != state) {
+            // The first state after boot-up is expected
+            if from != NmtState::BootUp {
+                events.push(LivenessEvent::StateChange {
+                    node,
+                    from,
+                    to: state,
+                });
+            }
+        }
+        self.state = Some(state);
+        events
+    }
+
+    /// Report the node as silent once it hasn't been heard for longer than allowed
+    pub fn check(&mut self, now: Instant) -> Option<LivenessEvent> {
+        let silent_for = now.duration_since(self.last_heard);
+        if self.silent || silent_for <= self.config.allowed_silence() {
+            return None;
+        }
+        self.silent = true;
+        Some(LivenessEvent::Silent {
+            node: self.config.node,
+            silent_for,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heartbeat(node: u8, state: u8) -> CanFrame {
+        CanFrame::new(NMT_ERROR_CONTROL + u32::from(node), &[state], false, false).unwrap()
+    }
+
+    #[test]
+    fn it_detects_silence_resets_and_state_changes() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut liveness = Liveness::new(
+            LivenessConfig {
+                mode: LivenessMode::Heartbeat,
+                node: 5,
+                timeout: Duration::from_millis(150),
+            },
+            start,
+        );
+
+        assert!(liveness.observe(&heartbeat(5, 0x05), at(100)).is_empty());
+        // Other nodes and quiet periods within the timeout are fine
+        assert!(liveness.observe(&heartbeat(6, 0x00), at(150)).is_empty());
+        assert_eq!(liveness.check(at(200)), None);
+
+        assert!(matches!(
+            liveness.check(at(300)),
+            Some(LivenessEvent::Silent { node: 5, .. })
+        ));
+        assert_eq!(liveness.check(at(400)), None);
+        assert_eq!(
+            liveness.observe(&heartbeat(5, 0x00), at(500)),
+            [
+                LivenessEvent::Recovered { node: 5 },
+                LivenessEvent::BootUp { node: 5 }
+            ]
+        );
+        // Pre-operational after boot-up is expected, stopping isn't
+        assert!(liveness.observe(&heartbeat(5, 0x7F), at(600)).is_empty());
+        assert_eq!(
+            liveness.observe(&heartbeat(5, 0x04), at(700)),
+            [LivenessEvent::StateChange {
+                node: 5,
+                from: NmtState::PreOperational,
+                to: NmtState::Stopped
+            }]
+        );
+    }
+
+    #[test]
+    fn it_sends_guard_requests_once_per_timeout() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut liveness = Liveness::new(
+            LivenessConfig {
+                mode: LivenessMode::Guard,
+                node: 0x10,
+                timeout: Duration::from_millis(100),
+            },
+            start,
+        );
+        let request = liveness.guard_request(at(0)).unwrap().unwrap();
+        assert_eq!(request.id(), 0x710);
+        assert!(request.is_rtr());
+        assert_eq!(liveness.guard_request(at(50)).unwrap(), None);
+        assert!(liveness.guard_request(at(100)).unwrap().is_some());
+
+        // The toggle bit doesn't change the state
+        assert!(liveness.observe(&heartbeat(0x10, 0x85), at(110)).is_empty());
+        assert!(liveness.observe(&heartbeat(0x10, 0x05), at(210)).is_empty());
+    }
+}
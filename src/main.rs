@@ -1,237 +1,1198 @@
+pub mod bit_writer;
 pub mod can_bus;
+pub mod correlate;
+pub mod dbc;
+pub mod eds;
+pub mod emcy;
+pub mod error;
+pub mod findings;
+pub mod frame;
+pub mod fuzzer;
+pub mod liveness;
+pub mod minimize;
 pub mod msg_processor;
+pub mod mutator;
+pub mod pcapng;
+pub mod recorder;
+pub mod replay;
+pub mod rng;
+pub mod sim_bus;
+pub mod strategy;
+pub mod transport;
+pub mod validate;
 use can_bus::*;
+use error::FuzzerError;
+use frame::FrameFlags;
+use fuzzer::*;
+use liveness::{LivenessConfig, LivenessMode};
 use msg_processor::*;
-use socketcan::*;
+use recorder::{candump_frame, candump_line, open_sink, Direction, FrameSink, LogFormat};
+use sim_bus::*;
+use transport::*;
+use validate::validate_path;
 #[macro_use]
 extern crate clap;
-use clap::{App, Arg};
-use rand::seq::SliceRandom;
-use std::path::Path;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process;
-use std::{thread, time};
+use std::time;
 
 fn main() {
     let matches = App::new("Rusty Can Fuzzer")
         .version("0.1")
         .author(crate_authors!())
         .about("A CAN Bus fuzzer CLI written in rust")
-        .arg(
-            Arg::with_name("channels")
-                .short("c")
-                .long("channels")
-                .value_name("CHANNEL")
-                .help("The channel to create and send CAN messages on")
-                .takes_value(true)
-                .multiple(true)
-                .default_value("vcan0"),
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        .subcommand(
+            SubCommand::with_name("send")
+                .about("Send a single message on every channel")
+                .args(&bus_args())
+                .args(&frame_args())
+                .args(&id_message_args()),
         )
-        .arg(
-            Arg::with_name("delay")
-                .short("d")
-                .long("delay")
-                .value_name("DELAY")
-                .help("Adjust the message-send delay time, used in conjunction with -r")
-                .takes_value(true)
-                .default_value("1"),
-        )
-        .arg(
-            Arg::with_name("id")
-                .short("i")
-                .long("id")
-                .value_name("ID")
-                .help("The COB ID to use for the messages")
-                .takes_value(true)
-                .default_value("10"),
+        .subcommand(
+            SubCommand::with_name("fuzz")
+                .about("Send generated messages, from message formats or random values")
+                .args(&bus_args())
+                .args(&frame_args())
+                .args(&id_message_args())
+                .args(&import_args())
+                .arg(
+                    Arg::with_name("delay")
+                        .short("d")
+                        .long("delay")
+                        .value_name("DELAY")
+                        .help("Adjust the message-send delay time, used in conjunction with -r")
+                        .takes_value(true)
+                        .default_value("1"),
+                )
+                .arg(
+                    Arg::with_name("repeat")
+                        .short("r")
+                        .long("repeat")
+                        .value_name("N")
+                        .help(
+                            "Repeat sending the message N times or -1 for infinite times, \
+                             every so often defined by -d, using in conjunction with -d",
+                        )
+                        .takes_value(true)
+                        .allow_hyphen_values(true),
+                )
+                .arg(
+                    Arg::with_name("random_id")
+                        .long("random-id")
+                        .help(
+                            "Use a randomly generated ID, with --extended-id ids are drawn \
+                             from the full 29-bit range",
+                        )
+                        .conflicts_with_all(&["id", "message_format"]),
+                )
+                .arg(
+                    Arg::with_name("random_message")
+                        .long("random-message")
+                        .help(
+                            "Use a randomly generated message, with --fd a random valid FD \
+                             length is picked",
+                        )
+                        .conflicts_with_all(&["message", "message_format"]),
+                )
+                .arg(
+                    Arg::with_name("message_format")
+                        .short("f")
+                        .long("message-format")
+                        .value_name("FILE|DIR")
+                        .takes_value(true)
+                        .help(
+                            "Use provided message formats, a json, DBC or EDS/DCF file or a \
+                             directory of them",
+                        )
+                        .conflicts_with_all(&["random_message", "random_id", "message"]),
+                )
+                .arg(
+                    Arg::with_name("strategy")
+                        .long("strategy")
+                        .value_name("STRATEGY")
+                        .takes_value(true)
+                        .possible_values(&strategy::Strategy::NAMES)
+                        .default_value("random")
+                        .help(
+                            "How values of --message-format fields are chosen: random, \
+                             boundary values, every combination of small fields (exhaustive) \
+                             or every pair of boundary values (pairwise)",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("corpus")
+                        .long("corpus")
+                        .value_name("FILE|DIR")
+                        .takes_value(true)
+                        .help(
+                            "Send mutated copies of recorded frames, a candump -l or listen \
+                             log or a directory of them",
+                        )
+                        .conflicts_with_all(&[
+                            "message_format",
+                            "random_message",
+                            "random_id",
+                            "message",
+                            "id",
+                        ]),
+                )
+                .arg(
+                    Arg::with_name("listen_mode")
+                        .short("l")
+                        .long("listen")
+                        .help(
+                            "Collect the frames heard within --window after each message \
+                             and report requests left unanswered",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("window")
+                        .long("window")
+                        .value_name("MS")
+                        .takes_value(true)
+                        .requires("listen_mode")
+                        .help(
+                            "How long frames are collected after each message, --delay by default",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("expect")
+                        .long("expect")
+                        .value_name("MATCHER")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .requires("listen_mode")
+                        .help(
+                            "Response expected to requests: sdo (0x600+n answered on 0x580+n), \
+                             guard (0x700+n on 0x700+n), REQ:RESP or REQ+n:RESP+n in hex. \
+                             Any frame answers requests no matcher covers",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("listen_log")
+                        .long("listen-log")
+                        .takes_value(true)
+                        .default_value("log.txt")
+                        .value_name("FILE")
+                        .help(
+                            "Log file for storing the messages sent and the responses heard, \
+                             written with --listen or when given",
+                        ),
+                )
+                .arg(log_format_arg())
+                .arg(pcap_arg())
+                .arg(
+                    Arg::with_name("liveness")
+                        .long("liveness")
+                        .value_name("MODE")
+                        .takes_value(true)
+                        .possible_values(&LivenessMode::NAMES)
+                        .requires_all(&["listen_mode", "node_id"])
+                        .help(
+                            "Watch the --node-id node's heartbeat, or guard it with remote \
+                             frames, and report resets, state changes and silence along \
+                             with the frames sent just before",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("liveness_timeout")
+                        .long("liveness-timeout")
+                        .value_name("MS")
+                        .takes_value(true)
+                        .default_value("1000")
+                        .help("Longest time without a heartbeat, or time between guard requests"),
+                )
+                .arg(
+                    Arg::with_name("findings")
+                        .long("findings")
+                        .value_name("DIR")
+                        .takes_value(true)
+                        .requires("listen_mode")
+                        .help(
+                            "Save every anomaly seen in listen mode to DIR, with the \
+                             frames sent before it as a log that can be replayed",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("history")
+                        .long("history")
+                        .value_name("N")
+                        .takes_value(true)
+                        .default_value("8")
+                        .help("Number of frames sent before an anomaly kept as its suspects"),
+                )
+                .arg(Arg::with_name("trace").long("trace").help(
+                    "Print the section and sub section values each frame was \
+                             generated from, below the frame",
+                ))
+                .arg(
+                    Arg::with_name("seed")
+                        .short("s")
+                        .long("seed")
+                        .value_name("SEED")
+                        .help(
+                            "Seed for message generation, running again with the same seed \
+                             and options sends the same sequence of frames",
+                        )
+                        .takes_value(true),
+                ),
         )
-        .arg(
-            Arg::with_name("destroy")
-                .short("n")
-                .long("no-destroy")
-                .help("Stop rusty-can-dev from destroying the channel at end of life")
-                .takes_value(false),
+        .subcommand(
+            SubCommand::with_name("listen")
+                .about("Print every frame heard on the channels without sending anything")
+                .args(&bus_args())
+                .arg(
+                    Arg::with_name("fd")
+                        .long("fd")
+                        .help("Receive CAN FD frames as well as classic frames"),
+                )
+                .arg(
+                    Arg::with_name("log")
+                        .long("log")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .help("Also append heard frames to a log file"),
+                )
+                .arg(log_format_arg())
+                .arg(pcap_arg())
+                .arg(
+                    Arg::with_name("duration")
+                        .long("duration")
+                        .value_name("SECONDS")
+                        .takes_value(true)
+                        .help("Stop after listening this long, runs until Ctrl-C otherwise"),
+                ),
         )
-        .arg(
-            Arg::with_name("message")
-                .short("m")
-                .long("message")
-                .value_name("BYTE")
-                .help("The 8 bytes to send as the CAN message")
-                .multiple(true)
-                .takes_value(true)
-                .default_value("1"),
+        .subcommand(
+            SubCommand::with_name("replay")
+                .about("Send the frames recorded in a candump or listen log again")
+                .args(&bus_args())
+                .arg(
+                    Arg::with_name("log")
+                        .value_name("FILE")
+                        .help(
+                            "candump -l log or log written by fuzz --listen, frames marked \
+                             as received are skipped unless --all is given",
+                        )
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("delay")
+                        .short("d")
+                        .long("delay")
+                        .value_name("MS")
+                        .help("Time between frames in milliseconds")
+                        .takes_value(true)
+                        .default_value("100"),
+                )
+                .arg(
+                    Arg::with_name("original_timing")
+                        .long("original-timing")
+                        .help("Keep the spacing of the candump timestamps instead of --delay"),
+                )
+                .arg(
+                    Arg::with_name("speed")
+                        .long("speed")
+                        .value_name("FACTOR")
+                        .takes_value(true)
+                        .requires("original_timing")
+                        .help("Play the original timing this many times faster, 1 by default"),
+                )
+                .arg(
+                    Arg::with_name("all")
+                        .long("all")
+                        .help("Also send the frames marked as received"),
+                ),
         )
-        .arg(
-            Arg::with_name("repeat")
-                .short("r")
-                .long("repeat")
-                .value_name("N")
-                .help(
-                    "Repeat sending the message N times or -1 for infinite times, \
-                     every so often defined by -d, using in conjunction with -d",
-                )
-                .takes_value(true)
-                .allow_hyphen_values(true),
+        .subcommand(
+            SubCommand::with_name("minimize")
+                .about(
+                    "Shrink a saved finding to the frames and bytes needed to make the \
+                     --node-id node fail",
+                )
+                .args(&bus_args())
+                .args(&import_args())
+                .arg(
+                    Arg::with_name("log")
+                        .value_name("FILE")
+                        .help(
+                            "Finding, candump -l log or log written by fuzz --listen to \
+                             minimize, frames marked as received are skipped",
+                        )
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .help(
+                            "Candump log to write the minimal frames to, FILE-min.log by default",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("liveness")
+                        .long("liveness")
+                        .value_name("MODE")
+                        .takes_value(true)
+                        .possible_values(&LivenessMode::NAMES)
+                        .default_value("heartbeat")
+                        .help("Watch the node's heartbeat, or guard it with remote frames"),
+                )
+                .arg(
+                    Arg::with_name("liveness_timeout")
+                        .long("liveness-timeout")
+                        .value_name("MS")
+                        .takes_value(true)
+                        .default_value("1000")
+                        .help("Longest time without a heartbeat, or time between guard requests"),
+                )
+                .arg(
+                    Arg::with_name("window")
+                        .long("window")
+                        .value_name("MS")
+                        .takes_value(true)
+                        .default_value("100")
+                        .help("Time listened after each frame sent"),
+                )
+                .arg(
+                    Arg::with_name("settle")
+                        .long("settle")
+                        .value_name("MS")
+                        .takes_value(true)
+                        .default_value("5000")
+                        .help("Longest wait for the node to be heard again before each attempt"),
+                ),
         )
-        .arg(
-            Arg::with_name("random_id")
-                .long("random-id")
-                .help("Use a randomly generated ID")
-                .conflicts_with_all(&["id", "message_format"]),
+        .subcommand(
+            SubCommand::with_name("validate")
+                .about("Check message format files and report every problem found")
+                .args(&import_args())
+                .arg(
+                    Arg::with_name("path")
+                        .value_name("FILE|DIR")
+                        .help("Message format file (json, DBC or EDS/DCF) or directory of them")
+                        .required(true),
+                ),
         )
-        .arg(
-            Arg::with_name("random_message")
-                .long("random-message")
-                .help("Use a randomly generated message")
-                .conflicts_with_all(&["message", "message_format"]),
+        .subcommand(
+            SubCommand::with_name("convert")
+                .about("Rewrite message formats (json, DBC or EDS/DCF) as pretty printed json")
+                .args(&import_args())
+                .arg(
+                    Arg::with_name("input")
+                        .value_name("FILE|DIR")
+                        .help(
+                            "Message formats to convert, .dbc and .eds/.dcf files hold \
+                             several formats each",
+                        )
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .value_name("FILE|DIR")
+                        .takes_value(true)
+                        .help(
+                            "File to write a single format to, or directory to write each \
+                             format to under its input file name, prints to stdout otherwise",
+                        ),
+                ),
         )
-        .arg(
-            Arg::with_name("message_format")
-                .short("f")
-                .long("message-format")
-                .value_name("FILE|DIR")
-                .takes_value(true)
-                .help("Use a provided message format json file")
-                .conflicts_with_all(&["random_message", "random_id", "message"]),
+        .get_matches();
+
+    let result = match matches.subcommand() {
+        ("send", Some(matches)) => run_send(matches),
+        ("fuzz", Some(matches)) => run_fuzz(matches),
+        ("listen", Some(matches)) => run_listen(matches),
+        ("replay", Some(matches)) => run_replay(matches),
+        ("minimize", Some(matches)) => run_minimize(matches),
+        ("validate", Some(matches)) => parse_import_options(matches).map(|options| {
+            process::exit(run_validate(
+                Path::new(matches.value_of("path").unwrap()),
+                &options,
+            ))
+        }),
+        ("convert", Some(matches)) => run_convert(matches),
+        _ => unreachable!("clap requires a subcommand"),
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+/// Options choosing and setting up the buses a command runs on
+fn bus_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("channels")
+            .short("c")
+            .long("channels")
+            .value_name("CHANNEL")
+            .help("The channel to create and send CAN messages on")
+            .takes_value(true)
+            .multiple(true)
+            .default_value("vcan0"),
+        Arg::with_name("destroy")
+            .short("n")
+            .long("no-destroy")
+            .help("Stop rusty-can-dev from destroying the channel at end of life")
+            .takes_value(false),
+        Arg::with_name("backend")
+            .long("backend")
+            .value_name("BACKEND")
+            .help(
+                "CAN backend to use, \"sim\" runs on an in-process simulated bus \
+                 that needs no vcan interface or root. The simulated bus is send-only, \
+                 nothing else is on it to be heard",
+            )
+            .takes_value(true)
+            .possible_values(&["socketcan", "sim"])
+            .default_value("socketcan"),
+        Arg::with_name("sim_latency")
+            .long("sim-latency")
+            .value_name("MS")
+            .help("Simulated bus delivery latency in milliseconds")
+            .takes_value(true)
+            .default_value("0"),
+        Arg::with_name("sim_drop")
+            .long("sim-drop")
+            .value_name("RATE")
+            .help("Probability (0.0 - 1.0) that the simulated bus drops a frame")
+            .takes_value(true)
+            .default_value("0.0"),
+    ]
+}
+
+/// Options for the kind of frame sent
+fn frame_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("extended_id")
+            .short("e")
+            .long("extended-id")
+            .help("Send ids as 29-bit extended identifiers"),
+        Arg::with_name("fd")
+            .long("fd")
+            .help("Send CAN FD frames, --message may then be up to 64 bytes"),
+        Arg::with_name("brs")
+            .long("brs")
+            .help("Set the bit rate switch flag on CAN FD frames")
+            .requires("fd"),
+        Arg::with_name("esi")
+            .long("esi")
+            .help("Set the error state indicator flag on CAN FD frames")
+            .requires("fd"),
+    ]
+}
+
+/// Options for message format files that need more than the file describes
+fn import_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![Arg::with_name("node_id")
+        .long("node-id")
+        .value_name("ID")
+        .help(
+            "CANopen node id (1-127) that formats from EDS files are addressed to, \
+             and that --liveness watches",
         )
-        .arg(
-            Arg::with_name("listen_mode")
-                .short("l")
-                .long("listen")
-                .help(
-                    "Enable listen mode to log responses during delay, delay is shortened \
-                       when responses are heard",
-                ),
+        .takes_value(true)]
+}
+
+/// Format frames are logged in
+fn log_format_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("log_format")
+        .long("log-format")
+        .value_name("FORMAT")
+        .takes_value(true)
+        .possible_values(&LogFormat::NAMES)
+        .default_value("text")
+        .help(
+            "Log format, the original text lines, candump -l lines (sent and received \
+             frames marked T and R), a pcapng capture or JSON Lines events",
         )
-        .arg(
-            Arg::with_name("listen_log")
-                .long("listen-log")
-                .takes_value(true)
-                .default_value("log.txt")
-                .value_name("FILE")
-                .help("Listen mode log file for storing responses"),
+}
+
+/// Capture written alongside any log
+fn pcap_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("pcap")
+        .long("pcap")
+        .value_name("FILE")
+        .takes_value(true)
+        .help(
+            "Also append sent and heard frames to a pcapng capture for Wireshark, \
+             sent frames carry an \"injected by fuzzer\" comment",
         )
-        .get_matches();
+}
 
-    let channels: Vec<String> = matches
-        .values_of("channels")
-        .unwrap()
-        .map(String::from)
-        .collect();
-    let delay: u64 = matches
-        .value_of("delay")
-        .unwrap()
-        .parse()
-        .expect("Unable to parse delay value, should be a positive integer value");
-    let mut id: u32 = u32::from_str_radix(matches.value_of("id").unwrap(), 16)
-        .expect("Unable to parse id, should be a 32bit integer value");
-    let destroy: bool = !matches.is_present("destroy");
-    let mut message_parsed: Vec<u8> = matches
-        .values_of("message")
-        .unwrap()
-        .map(|x| {
-            u8::from_str_radix(x, 16)
-                .expect("Unable to parse message value, should be 1 byte hex value")
-        })
-        .collect();
+/// Options for a fixed id and message
+fn id_message_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("id")
+            .short("i")
+            .long("id")
+            .value_name("ID")
+            .help("The COB ID to use for the messages")
+            .takes_value(true)
+            .default_value("10"),
+        Arg::with_name("message")
+            .short("m")
+            .long("message")
+            .value_name("BYTE")
+            .help("The 8 bytes (up to 64 with --fd) to send as the CAN message")
+            .multiple(true)
+            .takes_value(true)
+            .default_value("1"),
+    ]
+}
 
-    let mut repeat: i64 = match matches.value_of("repeat").unwrap_or("1").parse() {
-        Ok(v) if v < -1 => panic!(
-            "Unable to parse repeat value, should be a postitive integer value \
-             (or -1 for infinite repeat), {} provided",
-            v
-        ),
-        Ok(v) => v,
-        Err(e) => panic!("Unable to parse repeat value: {}", e),
+/// Send one message built from --id and --message on every channel
+fn run_send(matches: &ArgMatches) -> Result<(), FuzzerError> {
+    let id = parse_id(matches)?;
+    let message = parse_message(matches)?;
+    let flags = parse_frame_flags(matches);
+
+    let mut buses = Buses::open(matches, flags.fd, false, rand::random())?;
+    print_banner();
+    let result = buses.transports.iter().try_for_each(|transport| {
+        create_frame_send_msg(transport.as_ref(), id, &message, flags).map(|_| ())
+    });
+    buses.close();
+    result
+}
+
+/// Set up buses from the command line options and run the fuzz loop
+/// Buses created here are torn down again whether or not the run succeeds
+fn run_fuzz(matches: &ArgMatches) -> Result<(), FuzzerError> {
+    let delay = parse_arg(
+        matches,
+        "delay",
+        "should be a positive integer value",
+        str::parse::<u64>,
+    )?;
+    let id = parse_id(matches)?;
+    let message_parsed = parse_message(matches)?;
+
+    let repeat: i64 = match matches.value_of("repeat") {
+        None => 1,
+        Some(v) => match v.parse() {
+            Ok(n) if n >= -1 => n,
+            _ => {
+                return Err(FuzzerError::InvalidArgument {
+                    arg: String::from("repeat"),
+                    value: v.to_owned(),
+                    reason: String::from(
+                        "should be a positive integer value (or -1 for infinite repeat)",
+                    ),
+                })
+            }
+        },
+    };
+
+    // Without a provided seed pick one, it's printed so the run can be replayed
+    let seed: u64 = match matches.value_of("seed") {
+        Some(_) => parse_arg(
+            matches,
+            "seed",
+            "should be a 64bit unsigned integer value",
+            str::parse,
+        )?,
+        None => rand::random(),
     };
 
     let random_id: bool = matches.is_present("random_id");
     let random_message: bool = matches.is_present("random_message");
+    let flags = parse_frame_flags(matches);
 
-    let msg_formats: Option<Vec<MsgFormat>> = matches
-        .value_of("message_format")
-        .map(|s| read_configs(Path::new(&s)).unwrap());
-
-    // Create Handler for keyboard interrupt signal
-    // This will cleanup bus when exiting with control+c
-    let channel_clone = channels.clone();
-    ctrlc::set_handler(move || {
-        if destroy {
-            for channel in &channel_clone {
-                destroy_bus(channel)
-            }
-        }
-        process::exit(0);
-    })
-    .expect("Error setting Ctrl-C handler");
+    let msg_formats: Option<Vec<MsgFormat>> = match matches.value_of("message_format") {
+        Some(s) => Some(
+            read_config_files(Path::new(&s), &parse_import_options(matches)?)?
+                .into_iter()
+                .map(|(_, format)| format)
+                .collect(),
+        ),
+        None => None,
+    };
+
+    let corpus = match matches.value_of("corpus") {
+        Some(path) => Some(mutator::read_corpus(Path::new(path))?),
+        None => None,
+    };
+    if corpus.as_ref().is_some_and(|corpus| corpus.is_empty()) {
+        return Err(FuzzerError::InvalidArgument {
+            arg: String::from("corpus"),
+            value: matches.value_of("corpus").unwrap().to_owned(),
+            reason: String::from("should hold at least one recorded frame"),
+        });
+    }
 
     let listen_mode = matches.is_present("listen_mode");
-    let listen_log = Path::new(matches.value_of("listen_log").unwrap());
+    let mut sinks = open_logs(
+        matches,
+        // Without --listen the log is only written when asked for, it then holds sent frames
+        matches
+            .value_of("listen_log")
+            .filter(|_| listen_mode || matches.occurrences_of("listen_log") > 0),
+    )?;
 
-    // Setup bus and socket objects
-    let mut sockets = Vec::new();
-    for channel in &channels {
-        create_bus(channel);
-        sockets.push((CANSocket::open(channel).unwrap(), channel));
-    }
+    // FD capable buses and sockets are only needed when FD frames get sent
+    let fd = flags.fd
+        || msg_formats
+            .iter()
+            .flatten()
+            .any(|format| format.frame_flags().fd)
+        || corpus.iter().flatten().any(|frame| frame.is_fd());
+
+    // Error frames are only needed to detect bus faults as anomalies
+    let error_frames = listen_mode || matches.is_present("findings");
+    let mut buses = Buses::open(matches, fd, error_frames, seed)?;
 
-    let delay_seconds = time::Duration::from_secs(delay);
+    let config = FuzzConfig {
+        delay: time::Duration::from_secs(delay),
+        repeat,
+        id,
+        message: message_parsed,
+        random_id,
+        random_message,
+        flags,
+        msg_formats,
+        strategy: parse_arg(matches, "strategy", "should be a strategy", str::parse)?,
+        corpus,
+        listen_mode,
+        window: match matches.value_of("window") {
+            Some(_) => time::Duration::from_millis(parse_arg(
+                matches,
+                "window",
+                "should be a positive integer value",
+                str::parse,
+            )?),
+            None => time::Duration::from_secs(delay),
+        },
+        matchers: matches
+            .values_of("expect")
+            .into_iter()
+            .flatten()
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|reason| FuzzerError::InvalidArgument {
+                        arg: String::from("expect"),
+                        value: value.to_owned(),
+                        reason,
+                    })
+            })
+            .collect::<Result<_, _>>()?,
+        liveness: parse_liveness(matches)?,
+        history: parse_arg(
+            matches,
+            "history",
+            "should be a positive integer value",
+            str::parse,
+        )?,
+        findings: matches.value_of("findings").map(PathBuf::from),
+        trace: matches.is_present("trace"),
+        seed,
+    };
 
-    // In listen mode read timeout is used as delay
+    println!("Seed: {} (replay with --seed {})", seed, seed);
+    print_banner();
+
+    // Main app loop
+    let result = run(
+        &config,
+        &buses.transports,
+        sinks.as_mut().map(|s| s as &mut dyn FrameSink),
+    );
+    buses.close();
+    let summary = result?;
+    println!("{} frame(s) sent", summary.sent);
     if listen_mode {
-        for socket in &sockets {
-            socket.0.set_read_timeout(delay_seconds).unwrap();
+        println!("Responses: {}", summary.responses);
+        println!("{}", summary.faults);
+        println!("{} anomal(ies)", summary.incidents.len());
+        for incident in &summary.incidents {
+            println!("{}", incident);
         }
     }
-    // Print Banner Message
-    println!(
-        "{0:<30} {1:<8} {2:<10} {3:<25}",
-        "Timestamp", "Channel", "COB ID", "Message"
+    Ok(())
+}
+
+/// Print frames heard on every channel until --duration passes or Ctrl-C
+fn run_listen(matches: &ArgMatches) -> Result<(), FuzzerError> {
+    let duration = match matches.value_of("duration") {
+        Some(_) => Some(time::Duration::from_secs(parse_arg(
+            matches,
+            "duration",
+            "should be a positive integer value",
+            str::parse,
+        )?)),
+        None => None,
+    };
+    let mut sinks = open_logs(matches, matches.value_of("log"))?;
+
+    let mut buses = Buses::open(matches, matches.is_present("fd"), false, rand::random())?;
+    print_banner();
+    let result = monitor(
+        &buses.transports,
+        sinks.as_mut().map(|s| s as &mut dyn FrameSink),
+        duration,
     );
-    println!("{:-<75}", "");
+    buses.close();
+    let heard = result?;
+    println!("{} frame(s) heard", heard);
+    Ok(())
+}
 
-    // Main app loop
-    while repeat != 0 {
-        for socket in &sockets {
-            if msg_formats.is_some() {
-                let formats = msg_formats.as_ref().unwrap();
-                // Choose random format when multiple provided
-                let format = &formats.choose(&mut rand::thread_rng()).unwrap();
-
-                id = random_cob_id_with_format(&format);
-                message_parsed = msg_processor(&format);
+/// Send the sent frames of a log again, with a fixed delay or the recorded timing
+fn run_replay(matches: &ArgMatches) -> Result<(), FuzzerError> {
+    let path = matches.value_of("log").unwrap();
+    let mut frames = replay::read_log(Path::new(path))?;
+    if !matches.is_present("all") {
+        frames.retain(replay::LoggedFrame::is_sent);
+    }
+
+    let timing = if matches.is_present("original_timing") {
+        if frames.iter().all(|logged| logged.timestamp.is_none()) {
+            return Err(FuzzerError::InvalidArgument {
+                arg: String::from("original-timing"),
+                value: path.to_owned(),
+                reason: String::from("needs a candump log, the log has no timestamps"),
+            });
+        }
+        let speed = match matches.value_of("speed") {
+            Some(_) => parse_arg(
+                matches,
+                "speed",
+                "should be a number above 0",
+                |value| match value.parse::<f64>() {
+                    Ok(speed) if speed > 0.0 && speed.is_finite() => Ok(speed),
+                    _ => Err(()),
+                },
+            )?,
+            None => 1.0,
+        };
+        replay::Timing::Original { speed }
+    } else {
+        replay::Timing::Fixed(time::Duration::from_millis(parse_arg(
+            matches,
+            "delay",
+            "should be a positive integer value",
+            str::parse,
+        )?))
+    };
+    let fd = frames.iter().any(|logged| logged.frame.is_fd());
+
+    let mut buses = Buses::open(matches, fd, false, rand::random())?;
+    print_banner();
+    let result = replay::replay(&frames, &buses.transports, timing);
+    buses.close();
+    result
+}
+
+/// Replay a log against the target, keeping the frames and bytes it takes to make the
+/// watched node fail, and write what is left as a candump log
+fn run_minimize(matches: &ArgMatches) -> Result<(), FuzzerError> {
+    let path = Path::new(matches.value_of("log").unwrap());
+    let frames: Vec<replay::LoggedFrame> = replay::read_log(path)?
+        .into_iter()
+        .filter(replay::LoggedFrame::is_sent)
+        .collect();
+    let liveness = parse_liveness(matches)?.unwrap();
+    let millis = |arg| -> Result<time::Duration, FuzzerError> {
+        Ok(time::Duration::from_millis(parse_arg(
+            matches,
+            arg,
+            "should be a positive integer value",
+            str::parse,
+        )?))
+    };
+    let window = millis("window")?;
+    let settle = millis("settle")?;
+    let output = match matches.value_of("output") {
+        Some(output) => PathBuf::from(output),
+        None => path.with_file_name(format!(
+            "{}-min.log",
+            path.file_stem().unwrap_or_default().to_string_lossy()
+        )),
+    };
+    let fd = frames.iter().any(|logged| logged.frame.is_fd());
+
+    let mut buses = Buses::open(matches, fd, false, rand::random())?;
+    let oracle = minimize::LivenessOracle::new(&buses.transports, liveness, window, settle);
+    let mut attempts = 0;
+    let result = minimize::minimize(&frames, |candidate| {
+        attempts += 1;
+        let reproduced = oracle.reproduces(candidate)?;
+        println!(
+            "Attempt {}: {} frame(s), {} byte(s), {}",
+            attempts,
+            candidate.len(),
+            candidate
+                .iter()
+                .map(|logged| logged.frame.data().len())
+                .sum::<usize>(),
+            if reproduced {
+                "reproduced"
             } else {
-                if random_id {
-                    id = random_cob_id()
-                }
-                if random_message {
-                    message_parsed = random_msg();
-                }
+                "no failure"
+            }
+        );
+        Ok(reproduced)
+    });
+    buses.close();
+
+    let minimal = result?.ok_or_else(|| FuzzerError::InvalidArgument {
+        arg: String::from("log"),
+        value: path.display().to_string(),
+        reason: format!("node {} didn't fail when it was replayed", liveness.node),
+    })?;
+    let log: String = minimal
+        .iter()
+        .map(|logged| {
+            candump_line(
+                logged.timestamp.unwrap_or_default(),
+                &logged.channel,
+                &logged.frame,
+                Direction::Tx,
+            )
+        })
+        .collect();
+    fs::write(&output, log).map_err(|source| FuzzerError::LogWrite {
+        path: output.clone(),
+        source,
+    })?;
+    println!(
+        "{} of {} frame(s) needed, written to {}",
+        minimal.len(),
+        frames.len(),
+        output.display()
+    );
+    for logged in &minimal {
+        println!("  {} {}", logged.channel, candump_frame(&logged.frame));
+    }
+    Ok(())
+}
+
+/// Open the log at `log_path` in the --log-format format and the --pcap capture
+/// Returns None when neither is written
+fn open_logs(
+    matches: &ArgMatches,
+    log_path: Option<&str>,
+) -> Result<Option<Vec<Box<dyn FrameSink>>>, FuzzerError> {
+    let mut outputs = vec![];
+    if let Some(path) = log_path {
+        let format = parse_arg(matches, "log_format", "should be a log format", str::parse)?;
+        outputs.push((path, format));
+    }
+    if let Some(path) = matches.value_of("pcap") {
+        outputs.push((path, LogFormat::Pcapng));
+    }
+
+    let mut sinks = vec![];
+    for (path, format) in outputs {
+        let path = Path::new(path);
+        sinks.push(
+            open_sink(path, format).map_err(|source| FuzzerError::LogWrite {
+                path: path.to_owned(),
+                source,
+            })?,
+        );
+    }
+    Ok(if sinks.is_empty() { None } else { Some(sinks) })
+}
+
+/// Read message formats and write them back out as pretty printed json
+fn run_convert(matches: &ArgMatches) -> Result<(), FuzzerError> {
+    let formats = read_config_files(
+        Path::new(matches.value_of("input").unwrap()),
+        &parse_import_options(matches)?,
+    )?;
+    let output = match matches.value_of("output") {
+        Some(output) => Path::new(output),
+        None => {
+            for (_, format) in &formats {
+                // Formats were just deserialized, serializing them again can't fail
+                println!("{}", serde_json::to_string_pretty(format).unwrap());
+            }
+            return Ok(());
+        }
+    };
+
+    let write_error = |path: &Path| {
+        let path = path.to_owned();
+        move |source| FuzzerError::LogWrite { path, source }
+    };
+    if formats.len() == 1 && !output.is_dir() {
+        return save_config(&output.to_string_lossy(), &formats[0].1).map_err(write_error(output));
+    }
+    // Each format keeps the name of the file it came from, files describing
+    // several formats (DBC) are split into one file per format name
+    fs::create_dir_all(output).map_err(write_error(output))?;
+    for (file, format) in &formats {
+        let shared = formats.iter().filter(|(other, _)| other == file).count() > 1;
+        let name: String = if shared {
+            format
+                .name()
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                .collect()
+        } else {
+            file.file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into()
+        };
+        let path = output.join(name + ".json");
+        save_config(&path.to_string_lossy(), format).map_err(write_error(&path))?;
+        println!("Wrote {}", path.display());
+    }
+    Ok(())
+}
+
+/// Transports a command runs on, and the interfaces it created for them
+struct Buses {
+    transports: Vec<Box<dyn CanTransport>>,
+    created: Vec<String>,
+    destroy: bool,
+}
+
+impl Buses {
+    /// Open a transport on every --channels entry with the chosen backend
+    /// SocketCAN sockets only receive error frames with `error_frames`
+    /// A Ctrl-C handler is installed that tears down created interfaces
+    fn open(
+        matches: &ArgMatches,
+        fd: bool,
+        error_frames: bool,
+        seed: u64,
+    ) -> Result<Self, FuzzerError> {
+        let channels: Vec<String> = matches
+            .values_of("channels")
+            .unwrap()
+            .map(String::from)
+            .collect();
+        let sim_backend = matches.value_of("backend") == Some("sim");
+        // Simulated buses only live in this process, nothing to tear down
+        let destroy: bool = !matches.is_present("destroy") && !sim_backend;
+
+        // Only buses created here are torn down, pre-existing interfaces are left alone
+        let mut buses = Buses {
+            transports: Vec::new(),
+            created: Vec::new(),
+            destroy,
+        };
+        if sim_backend {
+            let sim_config = SimBusConfig {
+                latency: time::Duration::from_millis(parse_arg(
+                    matches,
+                    "sim_latency",
+                    "should be a positive integer value",
+                    str::parse,
+                )?),
+                drop_rate: parse_arg(
+                    matches,
+                    "sim_drop",
+                    "should be a value from 0.0 to 1.0",
+                    str::parse,
+                )?,
+                seed,
+            };
+            // One simulated bus per channel, like separate vcan interfaces
+            // Nothing else is attached, so the buses are send-only
+            for channel in &channels {
+                buses
+                    .transports
+                    .push(Box::new(SimBus::new(sim_config).attach(channel)));
             }
+        } else if let Err(e) = buses.open_socketcan(&channels, fd, error_frames) {
+            buses.close();
+            return Err(e);
+        }
+
+        // Create Handler for keyboard interrupt signal
+        // This will cleanup buses we created when exiting with control+c
+        let channel_clone = buses.created.clone();
+        if let Err(e) = ctrlc::set_handler(move || {
+            destroy_buses(&channel_clone, destroy);
+            process::exit(0);
+        }) {
+            eprintln!(
+                "Unable to set Ctrl-C handler, buses won't be cleaned up on exit: {}",
+                e
+            );
+        }
+        Ok(buses)
+    }
 
-            let frame =
-                create_frame_send_msg(&socket.0, &socket.1, id, &message_parsed, false, false);
-            if listen_mode {
-                if let Ok(f) = frame {
-                    listen(&socket.0, &socket.1, listen_log, f).unwrap()
-                }
+    /// Create (or reuse) each channel's interface and open a socket on it
+    /// Channels are added to `created` as soon as they exist, so they can be
+    /// torn down if a later channel fails
+    fn open_socketcan(
+        &mut self,
+        channels: &[String],
+        fd: bool,
+        error_frames: bool,
+    ) -> Result<(), FuzzerError> {
+        for channel in channels {
+            if create_bus(channel, fd)? == BusState::Created {
+                self.created.push(channel.clone());
             }
+            let transport = if fd {
+                SocketCanTransport::open_fd(channel, error_frames)
+            } else {
+                SocketCanTransport::open(channel, error_frames)
+            };
+            let transport = transport.map_err(|source| FuzzerError::TransportOpen {
+                channel: channel.clone(),
+                source,
+            })?;
+            self.transports.push(Box::new(transport));
         }
+        Ok(())
+    }
 
-        if repeat != -1 {
-            repeat -= 1;
+    /// Close every transport and tear down the interfaces that were created
+    fn close(&mut self) {
+        for transport in &mut self.transports {
+            if let Err(e) = transport.close() {
+                eprintln!("Unable to close {}: {}", transport.channel(), e);
+            }
         }
+        destroy_buses(&self.created, self.destroy);
+    }
+}
+
+fn print_banner() {
+    println!(
+        "{0:<30} {1:<8} {2:<10} {3:<25}",
+        "Timestamp", "Channel", "COB ID", "Message"
+    );
+    println!("{:-<75}", "");
+}
 
-        // listen mode uses blocking read to add delay
-        if !listen_mode {
-            thread::sleep(delay_seconds);
+fn parse_id(matches: &ArgMatches) -> Result<u32, FuzzerError> {
+    parse_arg(matches, "id", "should be a 32bit hex value", |s| {
+        u32::from_str_radix(s, 16)
+    })
+}
+
+fn parse_message(matches: &ArgMatches) -> Result<Vec<u8>, FuzzerError> {
+    matches
+        .values_of("message")
+        .unwrap()
+        .map(|x| {
+            u8::from_str_radix(x, 16).map_err(|_| FuzzerError::InvalidArgument {
+                arg: String::from("message"),
+                value: x.to_owned(),
+                reason: String::from("should be 1 byte hex values"),
+            })
+        })
+        .collect()
+}
+
+fn parse_frame_flags(matches: &ArgMatches) -> FrameFlags {
+    FrameFlags {
+        extended: matches.is_present("extended_id"),
+        fd: matches.is_present("fd"),
+        brs: matches.is_present("brs"),
+        esi: matches.is_present("esi"),
+        ..FrameFlags::default()
+    }
+}
+
+fn parse_import_options(matches: &ArgMatches) -> Result<ImportOptions, FuzzerError> {
+    let node_id = match matches.value_of("node_id") {
+        Some(_) => Some(parse_arg(
+            matches,
+            "node_id",
+            "should be a CANopen node id from 1 to 127",
+            |value| match value.parse::<u8>() {
+                Ok(id) if (1..=127).contains(&id) => Ok(id),
+                _ => Err(()),
+            },
+        )?),
+        None => None,
+    };
+    Ok(ImportOptions { node_id })
+}
+
+/// Liveness checks asked for with --liveness, watching the --node-id node
+fn parse_liveness(matches: &ArgMatches) -> Result<Option<LivenessConfig>, FuzzerError> {
+    let mode = match matches.value_of("liveness") {
+        Some(_) => parse_arg(matches, "liveness", "should be a liveness mode", str::parse)?,
+        None => return Ok(None),
+    };
+    let node =
+        parse_import_options(matches)?
+            .node_id
+            .ok_or_else(|| FuzzerError::InvalidArgument {
+                arg: String::from("liveness"),
+                value: matches.value_of("liveness").unwrap().to_owned(),
+                reason: String::from("needs the --node-id of the node to watch"),
+            })?;
+    let timeout = time::Duration::from_millis(parse_arg(
+        matches,
+        "liveness_timeout",
+        "should be a positive integer value",
+        str::parse,
+    )?);
+    Ok(Some(LivenessConfig {
+        mode,
+        node,
+        timeout,
+    }))
+}
+
+/// Parse the value of a command line option, `reason` says what was expected
+fn parse_arg<T, E>(
+    matches: &ArgMatches,
+    arg: &str,
+    reason: &str,
+    parse: impl Fn(&str) -> Result<T, E>,
+) -> Result<T, FuzzerError> {
+    let value = matches.value_of(arg).unwrap();
+    parse(value).map_err(|_| FuzzerError::InvalidArgument {
+        arg: arg.replace('_', "-"),
+        value: value.to_owned(),
+        reason: reason.to_owned(),
+    })
+}
+
+/// Validate the formats at `path`, printing each issue found
+/// Returns the process exit code, non zero when any issue was found
+fn run_validate(path: &Path, options: &ImportOptions) -> i32 {
+    match validate_path(path, options) {
+        Ok(issues) if issues.is_empty() => {
+            println!("{}: ok", path.display());
+            0
+        }
+        Ok(issues) => {
+            for issue in &issues {
+                println!("{}", issue);
+            }
+            println!("{} problem(s) found", issues.len());
+            1
+        }
+        Err(e) => {
+            eprintln!("Unable to read {}: {}", path.display(), e);
+            1
         }
     }
+}
 
-    // Tear down bus
-    if destroy {
-        for channel in &channels {
-            destroy_bus(channel)
+/// Tear down the given buses, reporting but not stopping on failures
+fn destroy_buses(channels: &[String], destroy: bool) {
+    if !destroy {
+        return;
+    }
+    for channel in channels {
+        if let Err(e) = destroy_bus(channel) {
+            eprintln!("Unable to destroy bus: {}", e);
         }
     }
 }
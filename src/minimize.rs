@@ -0,0 +1,341 @@
+use crate::error::FuzzerError;
+use crate::frame::CanFrame;
+use crate::liveness::{Liveness, LivenessConfig, LivenessEvent};
+use crate::msg_processor::listen;
+use crate::replay::{is_sent_on, LoggedFrame};
+use crate::transport::CanTransport;
+use std::time::{Duration, Instant};
+
+/// How long to listen on one transport before checking the next while waiting
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Delta debugging, returns a subsequence of `items` that `test` still passes on and
+/// where removing any single item makes it fail. `items` must pass, the empty
+/// sequence is never tried
+pub fn ddmin<T: Clone, E>(
+    items: &[T],
+    mut test: impl FnMut(&[T]) -> Result<bool, E>,
+) -> Result<Vec<T>, E> {
+    let mut current = items.to_vec();
+    let mut granularity = 2;
+    while current.len() >= 2 {
+        let size = current.len().div_ceil(granularity);
+        let chunks: Vec<&[T]> = current.chunks(size).collect();
+        let mut reduced = None;
+
+        // A single chunk reproducing narrows the search the most
+        for chunk in &chunks {
+            if test(chunk)? {
+                reduced = Some((chunk.to_vec(), 2));
+                break;
+            }
+        }
+        // With two chunks the complements are the chunks themselves
+        if reduced.is_none() && chunks.len() > 2 {
+            for skipped in 0..chunks.len() {
+                let complement: Vec<T> = chunks
+                    .iter()
+                    .enumerate()
+                    .filter(|&(i, _)| i != skipped)
+                    .flat_map(|(_, chunk)| chunk.iter().cloned())
+                    .collect();
+                if test(&complement)? {
+                    reduced = Some((complement, (granularity - 1).max(2)));
+                    break;
+                }
+            }
+        }
+
+        match reduced {
+            Some((items, next)) => {
+                current = items;
+                granularity = next;
+            }
+            None if granularity >= current.len() => break,
+            None => granularity = (granularity * 2).min(current.len()),
+        }
+    }
+    Ok(current)
+}
+
+/// Shrink a frame sequence that `reproduces` a fault, first down to the frames needed
+/// and then each payload down to its shortest reproducing prefix, zeroing the bytes
+/// not needed. Bytes keep their positions, targets decode payloads by offset
+/// Returns None when the whole sequence doesn't reproduce it
+pub fn minimize(
+    frames: &[LoggedFrame],
+    mut reproduces: impl FnMut(&[LoggedFrame]) -> Result<bool, FuzzerError>,
+) -> Result<Option<Vec<LoggedFrame>>, FuzzerError> {
+    if frames.is_empty() || !reproduces(frames)? {
+        return Ok(None);
+    }
+    let mut frames = ddmin(frames, &mut reproduces)?;
+
+    for i in 0..frames.len() {
+        let original = frames[i].frame;
+        if original.is_rtr() || original.data().is_empty() {
+            continue;
+        }
+        // Payloads that don't make a valid frame, e.g. FD lengths, don't reproduce
+        let mut with_data = |data: &[u8]| -> Result<bool, FuzzerError> {
+            let frame = match CanFrame::with_flags(original.id(), data, original.flags()) {
+                Ok(frame) => frame,
+                Err(_) => return Ok(false),
+            };
+            let mut candidate = frames.clone();
+            candidate[i].frame = frame;
+            reproduces(&candidate)
+        };
+        let mut data = original.data().to_vec();
+        for len in 0..data.len() {
+            if with_data(&data[..len])? {
+                data.truncate(len);
+                break;
+            }
+        }
+        for j in 0..data.len() {
+            if data[j] == 0 {
+                continue;
+            }
+            let mut zeroed = data.clone();
+            zeroed[j] = 0;
+            if with_data(&zeroed)? {
+                data = zeroed;
+            }
+        }
+        frames[i].frame = CanFrame::with_flags(original.id(), &data, original.flags())?;
+    }
+    Ok(Some(frames))
+}
+
+/// Replays candidate sequences and tells whether the watched node failed
+/// Before each attempt the node has to be heard, so a reset caused by the previous
+/// attempt isn't blamed on the next one
+pub struct LivenessOracle<'a> {
+    transports: &'a [Box<dyn CanTransport>],
+    config: LivenessConfig,
+    /// Time listened after each frame
+    window: Duration,
+    /// Longest wait for the node before an attempt
+    settle: Duration,
+}
+
+impl<'a> LivenessOracle<'a> {
+    pub fn new(
+        transports: &'a [Box<dyn CanTransport>],
+        config: LivenessConfig,
+        window: Duration,
+        settle: Duration,
+    ) -> Self {
+        Self {
+            transports,
+            config,
+            window,
+            settle,
+        }
+    }
+
+    /// Send `frames` like replay does and watch the node until it could have gone silent
+    /// after the last one
+    pub fn reproduces(&self, frames: &[LoggedFrame]) -> Result<bool, FuzzerError> {
+        let mut monitors = self.wait_alive()?;
+        for logged in frames {
+            for (transport, liveness) in self.transports.iter().zip(&mut monitors) {
+                if !is_sent_on(logged, transport.as_ref(), self.transports) {
+                    continue;
+                }
+                transport.send_frame(&logged.frame)?;
+                if self.failed(transport.as_ref(), liveness, self.window)? {
+                    return Ok(true);
+                }
+            }
+        }
+
+        let deadline = Instant::now() + self.config.allowed_silence() + self.window;
+        while Instant::now() < deadline {
+            for (transport, liveness) in self.transports.iter().zip(&mut monitors) {
+                if self.failed(transport.as_ref(), liveness, POLL_INTERVAL)? {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Listen on `transport` for `window`, polling the node first when guarding
+    /// Returns whether a liveness failure was seen
+    fn failed(
+        &self,
+        transport: &dyn CanTransport,
+        liveness: &mut Liveness,
+        window: Duration,
+    ) -> Result<bool, FuzzerError> {
+        if let Some(request) = liveness.guard_request(Instant::now())? {
+            transport.send_frame(&request)?;
+        }
+        let heard = listen(transport, None, window)?;
+        let now = Instant::now();
+        let mut events: Vec<LivenessEvent> = heard
+            .iter()
+            .flat_map(|frame| liveness.observe(frame, now))
+            .collect();
+        events.extend(liveness.check(now));
+        Ok(events.iter().any(LivenessEvent::is_failure))
+    }
+
+    /// Fresh monitors for every transport, once the node has been heard on each
+    fn wait_alive(&self) -> Result<Vec<Liveness>, FuzzerError> {
+        let started = Instant::now();
+        let mut monitors: Vec<Liveness> = self
+            .transports
+            .iter()
+            .map(|_| Liveness::new(self.config, started))
+            .collect();
+        let mut alive = vec![false; self.transports.len()];
+        while alive.contains(&false) {
+            if started.elapsed() > self.settle {
+                return Err(FuzzerError::TargetSilent {
+                    node: self.config.node,
+                    waited: self.settle,
+                });
+            }
+            for ((transport, liveness), alive) in
+                self.transports.iter().zip(&mut monitors).zip(&mut alive)
+            {
+                if let Some(request) = liveness.guard_request(Instant::now())? {
+                    transport.send_frame(&request)?;
+                }
+                for frame in listen(transport.as_ref(), None, POLL_INTERVAL)? {
+                    *alive |= liveness.is_liveness_frame(&frame);
+                    liveness.observe(&frame, Instant::now());
+                }
+            }
+        }
+        Ok(monitors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::liveness::{LivenessMode, NMT_ERROR_CONTROL};
+    use crate::sim_bus::{SimBus, SimBusConfig};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    fn logged(id: u32, data: &[u8]) -> LoggedFrame {
+        LoggedFrame {
+            timestamp: None,
+            channel: String::from("sim0"),
+            direction: None,
+            frame: CanFrame::new(id, data, false, false).unwrap(),
+        }
+    }
+
+    #[test]
+    fn it_shrinks_frames_and_payloads_to_the_trigger() {
+        // Fails once 0x605 carries 0x23 after 0x000 was sent
+        let trigger = |frames: &[LoggedFrame]| -> Result<bool, FuzzerError> {
+            let start = frames.iter().position(|l| l.frame.id() == 0x000);
+            Ok(start.is_some_and(|start| {
+                frames[start..]
+                    .iter()
+                    .any(|l| l.frame.id() == 0x605 && l.frame.data().contains(&0x23))
+            }))
+        };
+        let frames = vec![
+            logged(0x601, &[0x40, 0x00]),
+            logged(0x000, &[0x01, 0x05]),
+            logged(0x605, &[0x40, 0x17]),
+            logged(0x605, &[0x2F, 0x23, 0x10, 0x00]),
+            logged(0x606, &[0x23]),
+        ];
+
+        let minimal = minimize(&frames, trigger).unwrap().unwrap();
+        let minimal: Vec<CanFrame> = minimal.into_iter().map(|l| l.frame).collect();
+        assert_eq!(
+            minimal,
+            [
+                CanFrame::new(0x000, &[], false, false).unwrap(),
+                CanFrame::new(0x605, &[0x00, 0x23], false, false).unwrap(),
+            ]
+        );
+
+        assert_eq!(minimize(&frames[2..], trigger).unwrap(), None);
+    }
+
+    #[test]
+    fn it_keeps_payload_bytes_at_their_index() {
+        // Fails when the third byte of 0x605 is 0xAA
+        let trigger = |frames: &[LoggedFrame]| -> Result<bool, FuzzerError> {
+            Ok(frames
+                .iter()
+                .any(|l| l.frame.id() == 0x605 && l.frame.data().get(2) == Some(&0xAA)))
+        };
+        let frames = vec![logged(0x605, &[0x11, 0x22, 0xAA, 0x33, 0xAA])];
+
+        let minimal = minimize(&frames, trigger).unwrap().unwrap();
+        assert_eq!(minimal[0].frame.data(), [0x00, 0x00, 0xAA]);
+    }
+
+    #[test]
+    fn it_checks_reproduction_with_the_liveness_oracle() {
+        let bus = SimBus::new(SimBusConfig::default());
+        let transports: Vec<Box<dyn CanTransport>> = vec![Box::new(bus.attach("sim0"))];
+
+        // Node 5 sends a heartbeat every 10 ms and resets when 0x10 carries 0xAA
+        let target = bus.attach("sim0");
+        let done = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&done);
+        let node = thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                let state = match target.recv_frame(Duration::from_millis(10)).unwrap() {
+                    Some(frame) if frame.id() == 0x10 && frame.data() == [0xAA] => 0x00,
+                    _ => 0x05,
+                };
+                let heartbeat = CanFrame::new(NMT_ERROR_CONTROL + 5, &[state], false, false);
+                target.send_frame(&heartbeat.unwrap()).unwrap();
+            }
+        });
+
+        let oracle = LivenessOracle::new(
+            &transports,
+            LivenessConfig {
+                mode: LivenessMode::Heartbeat,
+                node: 5,
+                timeout: Duration::from_millis(100),
+            },
+            Duration::from_millis(20),
+            Duration::from_secs(1),
+        );
+        let reset = oracle.reproduces(&[logged(0x10, &[0xAA])]);
+        let harmless = oracle.reproduces(&[logged(0x10, &[0xBB])]);
+        done.store(true, Ordering::Relaxed);
+        node.join().unwrap();
+
+        assert!(reset.unwrap());
+        assert!(!harmless.unwrap());
+    }
+
+    #[test]
+    fn it_fails_on_receive_errors_instead_of_blaming_the_node() {
+        let mut transport = SimBus::new(SimBusConfig::default()).attach("sim0");
+        transport.close().unwrap();
+        let transports: Vec<Box<dyn CanTransport>> = vec![Box::new(transport)];
+        let oracle = LivenessOracle::new(
+            &transports,
+            LivenessConfig {
+                mode: LivenessMode::Heartbeat,
+                node: 5,
+                timeout: Duration::from_millis(100),
+            },
+            Duration::from_millis(20),
+            Duration::from_millis(50),
+        );
+        assert!(matches!(
+            oracle.reproduces(&[logged(0x10, &[0xAA])]),
+            Err(FuzzerError::Transport(_))
+        ));
+    }
+}
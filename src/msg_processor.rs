@@ -1,23 +1,159 @@
+use crate::bit_writer::{BitWriter, PackError};
+use crate::dbc::read_dbc;
+use crate::eds::read_eds;
+use crate::error::FuzzerError;
+use crate::frame::*;
+use crate::recorder::{Direction, FrameSink};
+use crate::transport::CanTransport;
+use crate::validate::{validate_file, Issue, ValidationError};
 use chrono::Utc;
 use core::ops::Range;
+use rand::distributions::{Distribution, WeightedIndex};
 use rand::seq::SliceRandom;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
-use socketcan::*;
-use std::error::Error;
+use std::collections::BTreeSet;
 use std::fs::OpenOptions;
 use std::io::Write;
-use std::path::Path;
-use std::{error, fs, io};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::{fmt, fs, io, iter};
+
+/// Order multi-byte values are put on the wire in
+#[derive(Serialize, Deserialize, Debug, Default, Eq, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ByteOrder {
+    /// Most significant byte first (Motorola)
+    #[default]
+    BigEndian,
+    /// Least significant byte first (Intel), used by CANopen
+    LittleEndian,
+}
+
+/// Limits on the raw values generated for a sub section, or a section without sub sections
+/// Without any limits every value that fits is generated
+#[derive(Serialize, Deserialize, Debug, Default, Eq, PartialEq, Clone)]
+pub struct Constraints {
+    /// Smallest raw value generated, inclusive
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<u64>,
+    /// Largest raw value generated, inclusive
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<u64>,
+    /// Only generate these values, min and max are ignored when given
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_values: Vec<u64>,
+    /// Relative weight of each of allowed_values, they are equally likely when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub weights: Vec<u32>,
+}
+
+impl Constraints {
+    /// Returns true when every value of the field may be generated
+    pub fn is_empty(&self) -> bool {
+        self.min.is_none() && self.max.is_none() && self.allowed_values.is_empty()
+    }
+
+    /// Smallest and largest value of a `bits` wide field, both inclusive
+    pub fn range(&self, bits: usize) -> (u64, u64) {
+        let full = match bits {
+            0 => 0,
+            bits if bits >= 64 => u64::MAX,
+            bits => u64::MAX >> (64 - bits),
+        };
+        (self.min.unwrap_or(0), self.max.unwrap_or(full))
+    }
+
+    /// Generate a value of a `bits` wide field named `name` that isn't one of `holes`
+    /// Returns an error instead of searching forever when no value is left
+    pub fn pick<R: Rng + ?Sized>(
+        &self,
+        name: &str,
+        bits: usize,
+        holes: &[u64],
+        rng: &mut R,
+    ) -> Result<u64, PackError> {
+        let no_values = |reason: &str| PackError::NoValues {
+            name: name.to_owned(),
+            reason: reason.to_owned(),
+        };
+
+        if !self.allowed_values.is_empty() {
+            if !self.weights.is_empty() && self.weights.len() != self.allowed_values.len() {
+                return Err(no_values("weights and allowed_values differ in length"));
+            }
+            let weights = self.weights.iter().copied().chain(iter::repeat(1));
+            let candidates: Vec<(u64, u32)> = self
+                .allowed_values
+                .iter()
+                .copied()
+                .zip(weights)
+                .filter(|(value, _)| !holes.contains(value))
+                .collect();
+            if candidates.is_empty() {
+                return Err(no_values("every allowed value is a hole"));
+            }
+            let index = WeightedIndex::new(candidates.iter().map(|(_, weight)| weight))
+                .map_err(|_| no_values("every allowed value has weight 0"))?;
+            return Ok(candidates[index.sample(rng)].0);
+        }
+
+        let (min, max) = self.range(bits);
+        if min > max {
+            return Err(no_values("min is larger than max"));
+        }
+        let holes: BTreeSet<u64> = holes
+            .iter()
+            .copied()
+            .filter(|hole| (min..=max).contains(hole))
+            .collect();
+        let size = (max - min) as u128 + 1;
+        if holes.len() as u128 >= size {
+            return Err(no_values("every value from min to max is a hole"));
+        }
+        // Mostly holes, pick from what's left rather than retrying many times
+        if size <= 2 * holes.len() as u128 {
+            let left: Vec<u64> = (min..=max).filter(|value| !holes.contains(value)).collect();
+            return Ok(*left.choose(rng).unwrap());
+        }
+        loop {
+            let value = rng.gen_range(min..=max);
+            if !holes.contains(&value) {
+                return Ok(value);
+            }
+        }
+    }
+}
+
+impl fmt::Display for Constraints {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "min {:?}, max {:?}", self.min, self.max)?;
+        if !self.allowed_values.is_empty() {
+            write!(
+                f,
+                ", allowed_values {:?}, weights {:?}",
+                self.allowed_values, self.weights
+            )?;
+        }
+        Ok(())
+    }
+}
 
 /// SubSection used to define bits within a section definition
+/// A subsection can be up to 64 bits wide and doesn't need to be byte aligned
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
 pub struct SubSec {
     name: String,
     num_bits: u8,
-    holes: Vec<u8>,
+    holes: Vec<u64>,
     is_specified: bool,
-    specified_val: u8,
+    specified_val: u64,
+    /// Byte order of the value itself, defaults to the section's byte order
+    /// Only byte aligned subsections can differ from their section
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    byte_order: Option<ByteOrder>,
+    #[serde(flatten)]
+    constraints: Constraints,
 }
 
 impl SubSec {
@@ -25,9 +161,9 @@ impl SubSec {
     pub fn new(
         name: String,
         num_bits: u8,
-        holes: Vec<u8>,
+        holes: Vec<u64>,
         is_specified: bool,
-        specified_val: u8,
+        specified_val: u64,
     ) -> Self {
         Self {
             name,
@@ -35,21 +171,89 @@ impl SubSec {
             holes,
             is_specified,
             specified_val,
+            byte_order: None,
+            constraints: Constraints::default(),
         }
     }
 
+    /// Only generate raw values from min to max, both inclusive
+    pub fn with_range(mut self, min: Option<u64>, max: Option<u64>) -> Self {
+        self.constraints.min = min;
+        self.constraints.max = max;
+        self
+    }
+
+    /// Only generate the given raw values, picked by weight when weights are given
+    pub fn with_allowed_values(mut self, values: Vec<u64>, weights: Vec<u32>) -> Self {
+        self.constraints.allowed_values = values;
+        self.constraints.weights = weights;
+        self
+    }
+
+    /// Use the given byte order for this subsection instead of the section's
+    pub fn with_byte_order(mut self, byte_order: ByteOrder) -> Self {
+        self.byte_order = Some(byte_order);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn num_bits(&self) -> u8 {
+        self.num_bits
+    }
+
+    pub fn holes(&self) -> &[u64] {
+        &self.holes
+    }
+
+    /// Value this subsection is fixed to, None when it is generated
+    pub fn specified_val(&self) -> Option<u64> {
+        if self.is_specified {
+            Some(self.specified_val)
+        } else {
+            None
+        }
+    }
+
+    /// Byte order set on the subsection itself, None when it follows its section
+    pub fn byte_order(&self) -> Option<ByteOrder> {
+        self.byte_order
+    }
+
+    pub fn min(&self) -> Option<u64> {
+        self.constraints.min
+    }
+
+    pub fn max(&self) -> Option<u64> {
+        self.constraints.max
+    }
+
+    pub fn constraints(&self) -> &Constraints {
+        &self.constraints
+    }
+
     /// Formatted display of Subsection
     pub fn display(&self) {
         println!(
             "\t\t{}: \n\
                   \t\tnum_bits {}, holes {:?}, \n\
-                  \t\tis_specified {}, specified_val {}",
-            self.name, self.num_bits, self.holes, self.is_specified, self.specified_val
+                  \t\tis_specified {}, specified_val {}, {}",
+            self.name,
+            self.num_bits,
+            self.holes,
+            self.is_specified,
+            self.specified_val,
+            self.constraints
         );
     }
 }
 
 /// Section used to define 1 or more bytes within a message format
+/// Sub sections may not add up to more than num_bytes * 8. In a big endian section
+/// the first sub section takes the most significant bits, in a little endian
+/// section it takes the least significant bits (DBC/Intel layout).
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
 pub struct Section {
     name: String,
@@ -57,6 +261,12 @@ pub struct Section {
     sub_secs: Vec<SubSec>,
     is_specified: bool,
     specified_val: u64,
+    #[serde(default)]
+    byte_order: ByteOrder,
+    /// Limits on the value of a section without sub sections, which is then
+    /// generated as a single number of up to 64 bits
+    #[serde(flatten)]
+    constraints: Constraints,
 }
 
 impl Section {
@@ -74,15 +284,81 @@ impl Section {
             sub_secs,
             is_specified,
             specified_val,
+            byte_order: ByteOrder::default(),
+            constraints: Constraints::default(),
+        }
+    }
+
+    /// Pack the section with the given byte order
+    pub fn with_byte_order(mut self, byte_order: ByteOrder) -> Self {
+        self.byte_order = byte_order;
+        self
+    }
+
+    /// Generate the section as one number within the given constraints
+    pub fn with_constraints(mut self, constraints: Constraints) -> Self {
+        self.constraints = constraints;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn num_bytes(&self) -> u8 {
+        self.num_bytes
+    }
+
+    pub fn sub_secs(&self) -> &[SubSec] {
+        &self.sub_secs
+    }
+
+    /// Value this section is fixed to, None when it is generated
+    pub fn specified_val(&self) -> Option<u64> {
+        if self.is_specified {
+            Some(self.specified_val)
+        } else {
+            None
         }
     }
 
+    pub fn byte_order(&self) -> ByteOrder {
+        self.byte_order
+    }
+
+    /// Bit offset of each sub section from the start of the section, in the section's
+    /// byte order. Unused bits come before the sub sections of a big endian section
+    pub fn sub_sec_offsets(&self) -> Vec<usize> {
+        let width = self.num_bytes as usize * 8;
+        let bits: usize = self
+            .sub_secs
+            .iter()
+            .map(|sub_sec| sub_sec.num_bits as usize)
+            .sum();
+        let mut offset = match self.byte_order {
+            ByteOrder::BigEndian => width.saturating_sub(bits),
+            ByteOrder::LittleEndian => 0,
+        };
+        self.sub_secs
+            .iter()
+            .map(|sub_sec| {
+                let start = offset;
+                offset += sub_sec.num_bits as usize;
+                start
+            })
+            .collect()
+    }
+
+    pub fn constraints(&self) -> &Constraints {
+        &self.constraints
+    }
+
     /// Formatted display of a Section
     pub fn display(&self) {
         println!(
             "\t{}: \n\
-                  \tnum_bytes {}, is_specified {}, specified_val {}",
-            self.name, self.num_bytes, self.is_specified, self.specified_val
+                  \tnum_bytes {}, byte_order {:?}, is_specified {}, specified_val {}",
+            self.name, self.num_bytes, self.byte_order, self.is_specified, self.specified_val
         );
     }
 
@@ -105,6 +381,18 @@ pub struct MsgFormat {
     sections: Vec<Section>,
     is_specified: bool,
     specified_val: u64,
+    /// Send with 29-bit extended identifiers, cob_id_range/values may then use the full 29 bits
+    #[serde(default)]
+    extended: bool,
+    /// Send as CAN FD frames, sections may then add up to 64 bytes
+    #[serde(default)]
+    fd: bool,
+    /// Set the FD bit rate switch flag
+    #[serde(default)]
+    brs: bool,
+    /// Set the FD error state indicator flag
+    #[serde(default)]
+    esi: bool,
 }
 
 impl MsgFormat {
@@ -126,6 +414,71 @@ impl MsgFormat {
             sections,
             is_specified,
             specified_val,
+            extended: false,
+            fd: false,
+            brs: false,
+            esi: false,
+        }
+    }
+
+    /// Mark the format as using 29-bit extended identifiers
+    pub fn with_extended(mut self, extended: bool) -> Self {
+        self.extended = extended;
+        self
+    }
+
+    /// Send messages of this format as CAN FD frames with the given BRS/ESI flags
+    pub fn with_fd(mut self, fd: bool, brs: bool, esi: bool) -> Self {
+        self.fd = fd;
+        self.brs = brs;
+        self.esi = esi;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn cob_id_range(&self) -> &Range<u32> {
+        &self.cob_id_range
+    }
+
+    pub fn cob_id_values(&self) -> &[u32] {
+        &self.cob_id_values
+    }
+
+    pub fn num_sections(&self) -> u8 {
+        self.num_sections
+    }
+
+    pub fn sections(&self) -> &[Section] {
+        &self.sections
+    }
+
+    /// Sub sections whose values make up a message, every sub section of the
+    /// sections that aren't specified. Specified sub sections are included,
+    /// their only value is the specified one
+    pub fn fields(&self) -> Vec<&SubSec> {
+        self.sections
+            .iter()
+            .filter(|section| !section.is_specified)
+            .flat_map(|section| &section.sub_secs)
+            .collect()
+    }
+
+    /// Returns true when messages of this format use 29-bit identifiers
+    pub fn is_extended(&self) -> bool {
+        self.extended
+    }
+
+    /// Frame flags messages of this format are sent with
+    pub fn frame_flags(&self) -> FrameFlags {
+        FrameFlags {
+            extended: self.extended,
+            fd: self.fd,
+            brs: self.brs,
+            esi: self.esi,
+            ..FrameFlags::default()
         }
     }
 
@@ -133,9 +486,15 @@ impl MsgFormat {
     pub fn display(&self) {
         println!(
             "{}: \n\
-                  cob_id_range: {:?}, num_sections {}, \n\
+                  cob_id_range: {:?}, extended {}, fd {}, num_sections {}, \n\
                   is_specified {}, specified_val {}",
-            self.name, self.cob_id_range, self.num_sections, self.is_specified, self.specified_val
+            self.name,
+            self.cob_id_range,
+            self.extended,
+            self.fd,
+            self.num_sections,
+            self.is_specified,
+            self.specified_val
         );
     }
 
@@ -150,140 +509,414 @@ impl MsgFormat {
 
 /// Generate a random cob_id within message format allowed range or from provided COB-ID list
 /// cob_id_values takes precedence over cob_id_range
-pub fn random_cob_id_with_format(msg_format: &MsgFormat) -> u32 {
-    if !msg_format.cob_id_values.is_empty() {
-        return msg_format
-            .cob_id_values
-            .choose(&mut rand::thread_rng())
-            .unwrap()
-            .to_owned();
+/// Fails if both are empty, formats loaded through read_configs are validated against that
+pub fn random_cob_id_with_format<R: Rng + ?Sized>(
+    msg_format: &MsgFormat,
+    rng: &mut R,
+) -> Result<u32, PackError> {
+    if let Some(id) = msg_format.cob_id_values.choose(rng) {
+        return Ok(*id);
+    }
+    if msg_format.cob_id_range.is_empty() {
+        return Err(PackError::NoValues {
+            name: msg_format.name.clone(),
+            reason: String::from("cob_id_values and cob_id_range are both empty"),
+        });
     }
 
-    let mut rng = rand::thread_rng();
-    rng.gen_range(msg_format.cob_id_range.start..msg_format.cob_id_range.end)
+    Ok(rng.gen_range(msg_format.cob_id_range.start..msg_format.cob_id_range.end))
 }
 
 /// Generate any random cob_id
 /// Uses the valid range 0..2_021
-pub fn random_cob_id() -> u32 {
+pub fn random_cob_id<R: Rng + ?Sized>(rng: &mut R) -> u32 {
     //total range for cob_id in CANOpen is 0..2_021 (aka 0x0..0x7E5)
     //https://en.wikipedia.org/wiki/CANopen#Predefined_Connection_Set[7]
-    let mut rng = rand::thread_rng();
     rng.gen_range(0..2_021)
 }
 
+/// Generate any random 29-bit extended cob_id
+/// Uses the full range 0..=0x1FFF_FFFF (J1939 and proprietary protocols)
+pub fn random_extended_cob_id<R: Rng + ?Sized>(rng: &mut R) -> u32 {
+    rng.gen_range(0..=EFF_MASK)
+}
+
 /// Generate any random 8 byte CAN message
-pub fn random_msg() -> Vec<u8> {
-    let mut rng = rand::thread_rng();
-    let data: Vec<u8> = (0..8).map(|_| rng.gen_range(0..255)).collect();
+pub fn random_msg<R: Rng + ?Sized>(rng: &mut R) -> Vec<u8> {
+    let data: Vec<u8> = (0..8).map(|_| rng.gen()).collect();
     data
 }
 
-/// Output provided message data as a can message to a given socket
-/// Returns CANFrame that was sent
+/// Generate a random CAN FD message of any valid FD length (0-8, 12, 16, 20, 24, 32, 48, 64)
+pub fn random_fd_msg<R: Rng + ?Sized>(rng: &mut R) -> Vec<u8> {
+    let len = *CANFD_LENGTHS.choose(rng).unwrap();
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+/// Output provided message data as a can message to a given transport
+/// Extended frames use the 29-bit frame format even for ids below 0x800,
+/// FD payloads are padded to the next valid FD length
+/// Returns CanFrame that was sent
 pub fn create_frame_send_msg(
-    cs: &CANSocket,
-    channel: &str,
+    transport: &dyn CanTransport,
     cob_id: u32,
     data: &[u8],
-    rtr: bool,
-    err: bool,
-) -> Result<CANFrame, Box<dyn Error>> {
-    let frame = CANFrame::new(cob_id, data, rtr, err).unwrap();
-    cs.write_frame(&frame)?;
-    let mut formatted_data = "".to_owned();
-    for item in data {
-        formatted_data = format!("{}{:02X?} ", formatted_data, item);
-    }
+    flags: FrameFlags,
+) -> Result<CanFrame, FuzzerError> {
+    let frame = CanFrame::with_flags(cob_id, data, flags)?;
+    transport.send_frame(&frame)?;
+    print_frame(transport.channel(), &frame);
+
+    Ok(frame)
+}
+
+/// Print a frame to stdout as a row of the timestamp/channel/id/message table
+pub fn print_frame(channel: &str, frame: &CanFrame) {
     println!(
         "{0:<30} {1:<8} {2:<10} {3:<25}",
-        Utc::now().naive_local().format("[%a %b %e %H:%M:%S %Y]:"),
+        timestamp(),
+        channel,
+        frame.id_string(),
+        format_data(frame)
+    );
+}
+
+/// Append a frame to a log file, `note` says whether it was sent (TX) or received (RX)
+pub fn log_frame(logfile: &Path, note: &str, channel: &str, frame: &CanFrame) -> io::Result<()> {
+    let buffer: String = format!(
+        "{0:<3} {1:<30} {2:<8} {3:<10} {4:<25}\n",
+        note,
+        timestamp(),
         channel,
-        format!("0x{:03X?}", cob_id),
-        formatted_data
+        frame.id_string(),
+        format_data(frame)
     );
 
-    Ok(frame)
+    let mut file = OpenOptions::new().append(true).create(true).open(logfile)?;
+    file.write_all(buffer.as_bytes())
 }
 
-/// Create CAN message data using provided message format
-pub fn msg_processor(msg_format: &MsgFormat) -> Vec<u8> {
-    let mut sec_result;
-    let mut result = 0;
-    let msg_byte_array;
-    let mut msg_byte_vec: Vec<u8> = Vec::new();
-    let mut total_num_bytes = 0;
-    if msg_format.sections.len() == 1 {
-        result = section_proc(&msg_format.sections[0]);
-        total_num_bytes += msg_format.sections[0].num_bytes;
-    } else {
-        for i in 0..msg_format.sections.len() {
-            sec_result = section_proc(&msg_format.sections[i]);
-            // shifting the bits to make room for the new result
-            result <<= msg_format.sections[i].num_bytes * 8;
-            // ORing to add the new result on the end
-            result |= sec_result;
-            total_num_bytes += msg_format.sections[i].num_bytes;
+fn timestamp() -> String {
+    Utc::now()
+        .naive_local()
+        .format("[%a %b %e %H:%M:%S %Y]:")
+        .to_string()
+}
+
+/// Frame data as space separated hex bytes
+fn format_data(frame: &CanFrame) -> String {
+    let mut formatted_data = "".to_owned();
+    for item in frame.data() {
+        formatted_data = format!("{}{:02X?} ", formatted_data, item);
+    }
+    formatted_data
+}
+
+/// Value a section or sub section took in a generated message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceValue {
+    Value(u64),
+    /// Random bytes of a section without sub sections or constraints
+    Bytes(Vec<u8>),
+}
+
+impl fmt::Display for TraceValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceValue::Value(value) => write!(f, "{} (0x{:X})", value, value),
+            TraceValue::Bytes(bytes) => {
+                let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+                write!(f, "[{}]", hex.join(" "))
+            }
         }
     }
-    // bit shifting the final result so we push the actual
-    // code all the way to the left as needed for CAN
-    result <<= 64 - total_num_bytes * 8;
-    // Chopping up result into a vec<u8>!
-    // (done at end because it's simpler to do bit shifting with a single number before now)
-    msg_byte_array = result.to_be_bytes();
-    for msg in &msg_byte_array {
-        msg_byte_vec.push(*msg);
+}
+
+/// What one section, or one sub section of it, contributed to a generated message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldTrace {
+    pub section: String,
+    /// None when the entry is for the whole section
+    pub sub_sec: Option<String>,
+    pub value: TraceValue,
+    /// The value is fixed by the format rather than generated
+    pub specified: bool,
+}
+
+impl fmt::Display for FieldTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.section)?;
+        if let Some(sub_sec) = &self.sub_sec {
+            write!(f, ".{}", sub_sec)?;
+        }
+        write!(f, " = {}", self.value)?;
+        if self.specified {
+            write!(f, " (specified)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Message data generated from a format, with the values it was built from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GeneratedMsg {
+    pub data: Vec<u8>,
+    /// Every section, or each of its sub sections, in message order
+    pub trace: Vec<FieldTrace>,
+}
+
+/// Print the values a message was generated from, one indented line each
+pub fn print_trace(trace: &[FieldTrace]) {
+    for field in trace {
+        println!("    {}", field);
     }
-    msg_byte_vec
+}
+
+/// Create CAN message data using provided message format
+/// Classic messages are always 8 bytes, FD messages are padded to the next valid FD length
+/// Returns an error if a value or section doesn't fit where the format puts it
+pub fn msg_processor<R: Rng + ?Sized>(
+    msg_format: &MsgFormat,
+    rng: &mut R,
+) -> Result<GeneratedMsg, PackError> {
+    let values = msg_format
+        .fields()
+        .into_iter()
+        .map(|sub_sec| sub_sec_proc(sub_sec, rng))
+        .collect::<Result<Vec<u64>, PackError>>()?;
+    pack_msg(msg_format, &values, rng)
+}
+
+/// Create CAN message data from already chosen field values
+/// `values` holds one value for each of msg_format.fields(), in order. Sections
+/// without sub sections have no fields and are still filled from `rng`
+pub fn pack_msg<R: Rng + ?Sized>(
+    msg_format: &MsgFormat,
+    values: &[u64],
+    rng: &mut R,
+) -> Result<GeneratedMsg, PackError> {
+    let fields = msg_format.fields().len();
+    if values.len() != fields {
+        return Err(PackError::ValueCount {
+            name: msg_format.name.clone(),
+            expected: fields,
+            given: values.len(),
+        });
+    }
+    let max_len = if msg_format.fd {
+        CANFD_MAX_DLEN
+    } else {
+        CAN_MAX_DLEN
+    };
+    let mut msg = BitWriter::new(&msg_format.name, max_len * 8);
+    let mut values = values.iter().copied();
+    let mut trace = vec![];
+    // Sections are laid out one after another, each in its own byte order
+    for section in &msg_format.sections {
+        msg.push_bytes(&section_proc(section, &mut values, &mut trace, rng)?)?;
+    }
+
+    let mut msg_byte_vec = msg.into_bytes();
+    let len = if msg_format.fd {
+        fd_len(msg_byte_vec.len()).unwrap_or(CANFD_MAX_DLEN)
+    } else {
+        CAN_MAX_DLEN
+    };
+    msg_byte_vec.resize(len, 0);
+    Ok(GeneratedMsg {
+        data: msg_byte_vec,
+        trace,
+    })
 }
 
 /// Process a given message format section
-/// Returns u64 represenation of data generated
-fn section_proc(section: &Section) -> u64 {
-    let mut sub_sec_result;
-    let mut result: u64 = 0;
+/// Returns the num_bytes bytes of data generated, in the section's byte order
+/// Sub section values are taken from `values`, one for each sub section.
+/// The values used are added to `trace`
+fn section_proc<R: Rng + ?Sized>(
+    section: &Section,
+    values: &mut impl Iterator<Item = u64>,
+    trace: &mut Vec<FieldTrace>,
+    rng: &mut R,
+) -> Result<Vec<u8>, PackError> {
+    let width = section.num_bytes as usize * 8;
+    let mut result = BitWriter::new(&section.name, width);
+    let mut traced = |sub_sec: Option<&SubSec>, value, specified| {
+        trace.push(FieldTrace {
+            section: section.name.clone(),
+            sub_sec: sub_sec.map(|sub_sec| sub_sec.name.clone()),
+            value,
+            specified,
+        })
+    };
     if section.is_specified {
-        return section.specified_val;
+        traced(None, TraceValue::Value(section.specified_val), true);
+        // Specified values are right aligned, sections wider than 64 bits are zero filled
+        result.pad(width.saturating_sub(64))?;
+        result.push(&section.name, section.specified_val, width.min(64))?;
+        return Ok(in_byte_order(result.into_bytes(), section.byte_order));
     }
 
-    if section.sub_secs.is_empty() {
-        let mut rng = rand::thread_rng();
-        let mut max: u64 = 0xFF;
-        for _ in 0..section.num_bytes {
-            max <<= 8;
-            max += 0xFF;
+    if section.sub_secs.is_empty() && !section.constraints.is_empty() {
+        if width > 64 {
+            return Err(PackError::FieldTooWide {
+                name: section.name.clone(),
+                width,
+            });
         }
-        return rng.gen_range(0..max);
+        let value = section.constraints.pick(&section.name, width, &[], rng)?;
+        traced(None, TraceValue::Value(value), false);
+        result.push(&section.name, value, width)?;
+        return Ok(in_byte_order(result.into_bytes(), section.byte_order));
+    }
+    if section.sub_secs.is_empty() {
+        let mut data = vec![0; section.num_bytes as usize];
+        rng.fill(&mut data[..]);
+        traced(None, TraceValue::Bytes(data.clone()), false);
+        return Ok(data);
     }
 
-    for i in 0..section.sub_secs.len() {
-        sub_sec_result = sub_sec_proc(&section.sub_secs[i]);
-        //shifting the bits to make room for the new result
-        result <<= section.sub_secs[i].num_bits;
-        //ORing to add the new result on the end
-        result |= sub_sec_result as u64;
+    let mut swapped = Vec::with_capacity(section.sub_secs.len());
+    let offsets = section.sub_sec_offsets();
+    for ((sub_sec, value), offset) in section.sub_secs.iter().zip(values).zip(offsets) {
+        traced(
+            Some(sub_sec),
+            TraceValue::Value(value),
+            sub_sec.is_specified,
+        );
+        swapped.push(swap_sub_sec(sub_sec, value, section.byte_order, offset)?);
+    }
+
+    // Sub sections fill the low bits of the section, unused high bits are zero.
+    // The section is built as a big endian integer, a little endian section
+    // starts from its last sub section and has its bytes reversed at the end.
+    let sub_sec_bits: usize = section
+        .sub_secs
+        .iter()
+        .map(|sub_sec| sub_sec.num_bits as usize)
+        .sum();
+    result.pad(width.saturating_sub(sub_sec_bits))?;
+    let mut fields: Vec<_> = section.sub_secs.iter().zip(swapped).collect();
+    if section.byte_order == ByteOrder::LittleEndian {
+        fields.reverse();
+    }
+    for (sub_sec, value) in fields {
+        result.push(&sub_sec.name, value, sub_sec.num_bits as usize)?;
+    }
+    Ok(in_byte_order(result.into_bytes(), section.byte_order))
+}
+
+/// Reorder big endian bytes into the given byte order
+fn in_byte_order(mut bytes: Vec<u8>, byte_order: ByteOrder) -> Vec<u8> {
+    if byte_order == ByteOrder::LittleEndian {
+        bytes.reverse();
+    }
+    bytes
+}
+
+/// Byte swap a sub section value whose byte order differs from its section's,
+/// so it reads back in its own order once the section is laid out
+/// The sub section has to be whole bytes starting `offset` bits into the section
+fn swap_sub_sec(
+    sub_sec: &SubSec,
+    value: u64,
+    section_order: ByteOrder,
+    offset: usize,
+) -> Result<u64, PackError> {
+    let width = sub_sec.num_bits as usize;
+    match sub_sec.byte_order {
+        Some(order) if order != section_order && width > 8 => {
+            if !width.is_multiple_of(8) {
+                return Err(PackError::UnalignedByteOrder {
+                    name: sub_sec.name.clone(),
+                    width,
+                });
+            }
+            if !offset.is_multiple_of(8) {
+                return Err(PackError::MisalignedByteOrder {
+                    name: sub_sec.name.clone(),
+                    offset,
+                });
+            }
+            Ok(value.swap_bytes() >> (64 - width))
+        }
+        _ => Ok(value),
     }
-    result
 }
 
 /// Process a given message format sub section
-/// Returns u8 representation of generated sub section data
-pub fn sub_sec_proc(sub_sec: &SubSec) -> u8 {
-    let mut rng;
-    let range;
-    let mut result;
+/// Returns generated sub section data, at most 64 bits wide
+pub fn sub_sec_proc<R: Rng + ?Sized>(sub_sec: &SubSec, rng: &mut R) -> Result<u64, PackError> {
+    if sub_sec.num_bits > 64 {
+        return Err(PackError::FieldTooWide {
+            name: sub_sec.name.clone(),
+            width: sub_sec.num_bits as usize,
+        });
+    }
     if sub_sec.is_specified {
-        return sub_sec.specified_val;
+        return Ok(sub_sec.specified_val);
+    }
+    if sub_sec.num_bits == 0 {
+        return Ok(0);
     }
-    rng = rand::thread_rng();
-    //u16 instead of u8 because 8 bit long subsecs caused overflow
-    range = 2_u16.pow(sub_sec.num_bits as u32) - 1;
-    result = rng.gen_range(0..range as u8);
-    while sub_sec.holes.contains(&result) {
-        result = rng.gen_range(0..range as u8);
+    sub_sec.constraints.pick(
+        &sub_sec.name,
+        sub_sec.num_bits as usize,
+        &sub_sec.holes,
+        rng,
+    )
+}
+
+/// Limit a `bits` wide value to low..=high, as the min/max of a subsection
+/// The limits are clamped to what the type can hold. Signed values are stored
+/// two's complement, so a range crossing zero can't be expressed and neither
+/// it nor a range covering every value gives a limit.
+pub fn raw_value_range(
+    low: i128,
+    high: i128,
+    bits: usize,
+    signed: bool,
+) -> (Option<u64>, Option<u64>) {
+    let (type_min, type_max): (i128, i128) = if signed {
+        (-(1 << (bits - 1)), (1 << (bits - 1)) - 1)
+    } else {
+        (0, (1 << bits) - 1)
+    };
+    let (low, high) = (low.max(type_min), high.min(type_max));
+    if low > high || (low == type_min && high == type_max) || (low < 0 && high >= 0) {
+        return (None, None);
     }
-    result
+    let to_raw = |value: i128| {
+        (if value < 0 {
+            value + (1 << bits)
+        } else {
+            value
+        }) as u64
+    };
+    (Some(to_raw(low)), Some(to_raw(high)))
+}
+
+/// Fill bits from..to with subsections fixed to 0, at most 64 bits each
+pub fn padding(sub_secs: &mut Vec<SubSec>, from: usize, to: usize) {
+    let mut bit = from;
+    while bit < to {
+        let len = (to - bit).min(64);
+        sub_secs.push(SubSec::new(
+            format!("Unused{}", bit),
+            len as u8,
+            vec![],
+            true,
+            0,
+        ));
+        bit += len;
+    }
+}
+
+/// Settings for imported files that don't describe everything needed to send frames
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ImportOptions {
+    /// Node id the SDO and PDO formats of EDS files are addressed to, DCF files name their own
+    pub node_id: Option<u8>,
 }
 
 /// Read configuration files from a given path
@@ -291,83 +924,169 @@ pub fn sub_sec_proc(sub_sec: &SubSec) -> u8 {
 /// When a directory is provided a recursive search for files
 /// will be completed
 /// Returns a Vector of all found message formats
-pub fn read_configs(path: &Path) -> Result<Vec<MsgFormat>, Box<dyn error::Error>> {
+pub fn read_configs(path: &Path) -> Result<Vec<MsgFormat>, FuzzerError> {
+    Ok(read_config_files(path, &ImportOptions::default())?
+        .into_iter()
+        .map(|(_, format)| format)
+        .collect())
+}
+
+/// Like read_configs, but each format is paired with the file it was read from
+pub fn read_config_files(
+    path: &Path,
+    options: &ImportOptions,
+) -> Result<Vec<(PathBuf, MsgFormat)>, FuzzerError> {
+    let files = read_unvalidated(path, options)?;
+    let mut issues: Vec<Issue> = files
+        .iter()
+        .flat_map(|(file, formats)| validate_file(formats, file))
+        .collect();
+    if files.iter().all(|(_, formats)| formats.is_empty()) {
+        issues.push(Issue {
+            file: path.to_owned(),
+            path: String::new(),
+            message: String::from("no message formats found"),
+        });
+    }
+    if !issues.is_empty() {
+        return Err(ValidationError { issues }.into());
+    }
+
+    Ok(files
+        .into_iter()
+        .flat_map(|(file, formats)| formats.into_iter().map(move |f| (file.clone(), f)))
+        .collect())
+}
+
+/// Read the formats of every file in a file or directory, grouped by file
+fn read_unvalidated(
+    path: &Path,
+    options: &ImportOptions,
+) -> Result<Vec<(PathBuf, Vec<MsgFormat>)>, FuzzerError> {
     if !path.is_dir() {
-        return Ok(vec![read_config(path)?]);
+        return Ok(vec![(path.to_owned(), read_format_file(path, options)?)]);
     }
 
-    let mut result: Vec<MsgFormat> = vec![];
-    for entry in fs::read_dir(path)? {
-        let entry = entry?;
-        let filepath = entry.path();
-        if filepath.is_dir() {
-            result.append(&mut read_configs(&filepath)?);
-        } else {
-            result.push(read_config(&filepath)?);
-        }
+    let read_error = |source| FuzzerError::ConfigRead {
+        path: path.to_owned(),
+        source,
+    };
+    let mut result = vec![];
+    for entry in fs::read_dir(path).map_err(read_error)? {
+        result.append(&mut read_unvalidated(
+            &entry.map_err(read_error)?.path(),
+            options,
+        )?);
     }
 
     Ok(result)
 }
 
+/// Read every format a single file describes, picking the parser from the extension
+/// .dbc files hold one format per message, .eds/.dcf files SDO and PDO formats
+/// of a node, anything else is a single json format
+pub fn read_format_file(
+    path: &Path,
+    options: &ImportOptions,
+) -> Result<Vec<MsgFormat>, FuzzerError> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) if ext.eq_ignore_ascii_case("dbc") => read_dbc(path),
+        Some(ext) if ext.eq_ignore_ascii_case("eds") || ext.eq_ignore_ascii_case("dcf") => {
+            read_eds(path, options.node_id)
+        }
+        _ => Ok(vec![read_config(path)?]),
+    }
+}
+
 /// Read a single file path into a single message format object
+/// The format is not validated, see read_configs
 /// Returns a MsgFormat object
-fn read_config(filename: &Path) -> Result<MsgFormat, Box<dyn error::Error>> {
-    let file_data = fs::read_to_string(filename)?;
-    serde_json::from_str(&file_data).map_err(|err| Box::new(err) as Box<dyn std::error::Error>)
+pub fn read_config(filename: &Path) -> Result<MsgFormat, FuzzerError> {
+    let file_data = fs::read_to_string(filename).map_err(|source| FuzzerError::ConfigRead {
+        path: filename.to_owned(),
+        source,
+    })?;
+    serde_json::from_str(&file_data).map_err(|source| FuzzerError::ConfigParse {
+        path: filename.to_owned(),
+        source,
+    })
 }
 
 /// Write configuration object to a json file
 pub fn save_config(filename: &str, config: &MsgFormat) -> Result<(), io::Error> {
-    let json_config = serde_json::to_string(config)?;
+    let json_config = serde_json::to_string_pretty(config)?;
     fs::write(filename, json_config)?;
     Ok(())
 }
 
-/// Listen for a message on the can bus and write that message
-/// out to a provided log file
-/// Assumes that socket read timeout has already been set appropriately
+/// Shortest read timeout used, a zero timeout blocks forever on a socket
+const MIN_WAIT: Duration = Duration::from_micros(1);
+
+/// Collect every frame heard on the bus within `window`, recording each to `sink`
+/// Returns the frames heard, or the first receive error
 pub fn listen(
-    socket: &CANSocket,
-    channel: &str,
-    logfile: &Path,
-    tx_frame: CANFrame,
-) -> std::io::Result<()> {
-    fn write(frame: CANFrame, channel: &str, logfile: &Path, note: &str) -> std::io::Result<()> {
-        // TODO: Move formatting to seperate fn
-        let mut formatted_data = "".to_owned();
-        for item in frame.data() {
-            formatted_data = format!("{}{:02X?} ", formatted_data, item);
+    transport: &dyn CanTransport,
+    mut sink: Option<&mut (dyn FrameSink + '_)>,
+    window: Duration,
+) -> io::Result<Vec<CanFrame>> {
+    let deadline = Instant::now() + window;
+    let mut heard = vec![];
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match transport.recv_frame(left.max(MIN_WAIT)) {
+            Ok(Some(frame)) => {
+                if let Some(sink) = sink.as_deref_mut() {
+                    sink.record(Direction::Rx, transport.channel(), &frame)?;
+                }
+                heard.push(frame);
+            }
+            Ok(None) => {}
+            Err(e) => return Err(e),
         }
-        let buffer: String = format!(
-            "{0:<3} {1:<30} {2:<8} {3:<10} {4:<25}\n",
-            note,
-            Utc::now().naive_local().format("[%a %b %e %H:%M:%S %Y]:"),
-            channel,
-            format!("0x{:03X?}", frame.id()),
-            formatted_data
-        );
-
-        let mut file = OpenOptions::new().append(true).create(true).open(logfile)?;
-        file.write_all(buffer.as_bytes())?;
-        Ok(())
-    }
-
-    match socket.read_frame() {
-        Ok(frame) => {
-            write(tx_frame, channel, logfile, "TX")?;
-            write(frame, channel, logfile, "RX")
+        if Instant::now() >= deadline {
+            break;
         }
-        _ => Ok(()),
     }
+    Ok(heard)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::recorder::{open_sink, LogFormat};
+    use crate::rng::seeded_rng;
+    use crate::sim_bus::{SimBus, SimBusConfig};
+    use crate::validate::validate;
+    use std::cell::RefCell;
     use tempfile::tempdir;
 
+    /// Transport double that records sent frames and replays queued responses
+    struct MockTransport {
+        sent: RefCell<Vec<CanFrame>>,
+        responses: RefCell<Vec<CanFrame>>,
+    }
+
+    impl CanTransport for MockTransport {
+        fn channel(&self) -> &str {
+            "mock0"
+        }
+
+        fn send_frame(&self, frame: &CanFrame) -> io::Result<()> {
+            self.sent.borrow_mut().push(*frame);
+            Ok(())
+        }
+
+        fn recv_frame(&self, _timeout: Duration) -> io::Result<Option<CanFrame>> {
+            Ok(self.responses.borrow_mut().pop())
+        }
+
+        fn close(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
     #[test]
+    #[allow(clippy::needless_late_init, clippy::unnecessary_cast)]
     fn msg_processor_test() {
         let test_can_id: u32;
         let _test_can_msg: Vec<u8>;
@@ -416,8 +1135,10 @@ mod tests {
         println!("<#-END-#>");
         let width;
         let hex_cnt;
-        test_can_id = random_cob_id_with_format(&test_msg_format);
-        _test_can_msg = msg_processor(&test_msg_format);
+        test_can_id = random_cob_id_with_format(&test_msg_format, &mut rand::thread_rng()).unwrap();
+        _test_can_msg = msg_processor(&test_msg_format, &mut rand::thread_rng())
+            .unwrap()
+            .data;
         width = 12; //can_id typically expected to be <= 12 bits
         hex_cnt = (width) / 4;
         println!("--------");
@@ -493,6 +1214,64 @@ mod tests {
         assert_eq!(input_values, test_msg_format);
     }
 
+    #[test]
+    fn it_reads_and_writes_byte_orders() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("byte_order.json");
+        let file_path_str = file_path.to_str().unwrap();
+
+        // CANopen sends the error code least significant byte first
+        let test_msg_format = MsgFormat::new(
+            String::from("TestEMCYMsgFormat#2"),
+            std::ops::Range {
+                start: 0x080,
+                end: 0x0FF,
+            },
+            vec![],
+            2,
+            vec![
+                Section::new(
+                    String::from("EmergencyErrorCode"),
+                    2,
+                    vec![SubSec::new(String::from("EEC"), 16, vec![], false, 0)],
+                    false,
+                    0,
+                )
+                .with_byte_order(ByteOrder::LittleEndian),
+                Section::new(
+                    String::from("Mixed"),
+                    2,
+                    vec![
+                        SubSec::new(String::from("Motorola"), 8, vec![], false, 0)
+                            .with_byte_order(ByteOrder::BigEndian),
+                        SubSec::new(String::from("Intel"), 8, vec![], false, 0),
+                    ],
+                    false,
+                    0,
+                )
+                .with_byte_order(ByteOrder::LittleEndian),
+            ],
+            false,
+            0,
+        );
+
+        save_config(file_path_str, &test_msg_format).unwrap();
+        let json = fs::read_to_string(&file_path).unwrap();
+        assert!(json.contains("\"byte_order\": \"little_endian\""));
+        let input_values = read_config(Path::new(file_path_str)).unwrap();
+        assert_eq!(input_values, test_msg_format);
+
+        // Formats written before byte orders existed are big endian
+        let mut big_endian: serde_json::Value = serde_json::from_str(&json).unwrap();
+        big_endian["sections"][0]
+            .as_object_mut()
+            .unwrap()
+            .remove("byte_order");
+        fs::write(&file_path, big_endian.to_string()).unwrap();
+        let input_values = read_config(Path::new(file_path_str)).unwrap();
+        assert_eq!(input_values.sections[0].byte_order, ByteOrder::BigEndian);
+    }
+
     #[test]
     fn it_works_with_single_section_random_bytes() {
         let test_msg_format = MsgFormat::new(
@@ -509,7 +1288,421 @@ mod tests {
         );
 
         // Ensure randomizer does not panic
-        random_cob_id_with_format(&test_msg_format);
-        msg_processor(&test_msg_format);
+        random_cob_id_with_format(&test_msg_format, &mut rand::thread_rng()).unwrap();
+        msg_processor(&test_msg_format, &mut rand::thread_rng()).unwrap();
+    }
+
+    #[test]
+    fn it_sends_and_logs_through_transport() {
+        let dir = tempdir().unwrap();
+        let log_path = dir.path().join("log.txt");
+        let transport = MockTransport {
+            sent: RefCell::new(vec![]),
+            responses: RefCell::new(vec![CanFrame::new(0x581, &[0x60], false, false).unwrap()]),
+        };
+
+        let tx_frame = create_frame_send_msg(
+            &transport,
+            0x601,
+            &[0x40, 0x00, 0x10],
+            FrameFlags::default(),
+        )
+        .unwrap();
+        assert_eq!(transport.sent.borrow().len(), 1);
+        assert_eq!(transport.sent.borrow()[0].id(), 0x601);
+        assert_eq!(transport.sent.borrow()[0].data(), &[0x40, 0x00, 0x10]);
+
+        let mut sink = open_sink(&log_path, LogFormat::Text).unwrap();
+        sink.record(Direction::Tx, transport.channel(), &tx_frame)
+            .unwrap();
+        let heard = listen(&transport, Some(sink.as_mut()), Duration::from_millis(1)).unwrap();
+        assert_eq!(
+            heard,
+            [CanFrame::new(0x581, &[0x60], false, false).unwrap()]
+        );
+        let log = fs::read_to_string(&log_path).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("TX") && lines[0].contains("0x601"));
+        assert!(lines[1].starts_with("RX") && lines[1].contains("0x581"));
+
+        // Nothing heard, nothing logged
+        let heard = listen(&transport, Some(sink.as_mut()), Duration::from_millis(1)).unwrap();
+        assert!(heard.is_empty());
+        assert_eq!(fs::read_to_string(&log_path).unwrap().lines().count(), 2);
+    }
+
+    #[test]
+    fn it_reports_receive_errors_while_listening() {
+        // A closed socket isn't a quiet bus
+        let mut closed = SimBus::new(SimBusConfig::default()).attach("sim0");
+        closed.close().unwrap();
+        let err = listen(&closed, None, Duration::from_millis(1)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+    }
+
+    #[test]
+    fn it_generates_extended_ids() {
+        let json = r#"{"name": "J1939", "cob_id_range": {"start": 0, "end": 16},
+            "cob_id_values": [], "num_sections": 1, "extended": true,
+            "sections": [{"name": "DATA", "num_bytes": 8, "sub_secs": [],
+                "is_specified": false, "specified_val": 0}],
+            "is_specified": false, "specified_val": 0}"#;
+        let format: MsgFormat = serde_json::from_str(json).unwrap();
+        assert!(format.is_extended());
+
+        let transport = MockTransport {
+            sent: RefCell::new(vec![]),
+            responses: RefCell::new(vec![]),
+        };
+        let mut rng = rand::thread_rng();
+        let id = random_cob_id_with_format(&format, &mut rng).unwrap();
+        let frame = create_frame_send_msg(&transport, id, &[], format.frame_flags()).unwrap();
+        assert!(frame.is_extended() && frame.id() < 16);
+
+        for _ in 0..100 {
+            assert!(random_extended_cob_id(&mut rng) <= EFF_MASK);
+        }
+
+        let mut no_ids = format;
+        no_ids.cob_id_range = 0..0;
+        assert!(matches!(
+            random_cob_id_with_format(&no_ids, &mut rng),
+            Err(PackError::NoValues { .. })
+        ));
+        // Formats written before the extended flag existed are standard
+        assert!(!read_configs(Path::new("assets/emcy.json")).unwrap()[0].is_extended());
+    }
+
+    #[test]
+    fn it_generates_fd_messages() {
+        let sections = (0..5)
+            .map(|i| Section::new(format!("DATA#{}", i), 8, vec![], false, 0))
+            .collect();
+        let format = MsgFormat::new(
+            String::from("FdFormat"),
+            Range {
+                start: 0x100,
+                end: 0x200,
+            },
+            vec![],
+            5,
+            sections,
+            false,
+            0,
+        )
+        .with_fd(true, true, false);
+
+        let mut rng = rand::thread_rng();
+        // 40 bytes of sections, padded up to the 48 byte FD length
+        let data = msg_processor(&format, &mut rng).unwrap().data;
+        assert_eq!(data.len(), 48);
+        assert!(data[40..].iter().all(|b| *b == 0));
+
+        let transport = MockTransport {
+            sent: RefCell::new(vec![]),
+            responses: RefCell::new(vec![]),
+        };
+        let frame = create_frame_send_msg(&transport, 0x100, &data, format.frame_flags()).unwrap();
+        assert!(frame.is_fd() && frame.is_brs() && !frame.is_esi());
+
+        for _ in 0..100 {
+            assert!(CANFD_LENGTHS.contains(&random_fd_msg(&mut rng).len()));
+        }
+    }
+
+    #[test]
+    fn it_packs_wide_and_unaligned_sub_sections() {
+        let format = |sub_secs: Vec<SubSec>| {
+            MsgFormat::new(
+                String::from("Signals"),
+                Range {
+                    start: 0x100,
+                    end: 0x101,
+                },
+                vec![],
+                2,
+                vec![
+                    Section::new(String::from("Signals"), 6, sub_secs, false, 0),
+                    Section::new(String::from("Counter"), 2, vec![], true, 0xBEEF),
+                ],
+                false,
+                0,
+            )
+        };
+        let signals = format(vec![
+            SubSec::new(String::from("Speed"), 12, vec![], true, 0xABC),
+            SubSec::new(String::from("Flags"), 4, vec![], true, 0x5),
+            SubSec::new(String::from("Odometer"), 32, vec![], true, 0xDEAD_BEEF),
+        ]);
+        let data = msg_processor(&signals, &mut rand::thread_rng())
+            .unwrap()
+            .data;
+        assert_eq!(data, vec![0xAB, 0xC5, 0xDE, 0xAD, 0xBE, 0xEF, 0xBE, 0xEF]);
+
+        // Random 32 bit values stay inside their own field
+        let random = format(vec![
+            SubSec::new(String::from("Low"), 16, vec![], true, 0),
+            SubSec::new(String::from("Wide"), 32, vec![], false, 0),
+        ]);
+        let data = msg_processor(&random, &mut rand::thread_rng())
+            .unwrap()
+            .data;
+        assert_eq!(&data[..2], &[0, 0]);
+        assert_eq!(&data[6..], &[0xBE, 0xEF]);
+
+        // Values and sub sections that don't fit are errors, not truncated
+        let too_wide_value = format(vec![SubSec::new(
+            String::from("Speed"),
+            12,
+            vec![],
+            true,
+            0x1000,
+        )]);
+        assert!(matches!(
+            msg_processor(&too_wide_value, &mut rand::thread_rng()),
+            Err(PackError::ValueTooWide { .. })
+        ));
+        let too_many_bits = format(vec![
+            SubSec::new(String::from("A"), 40, vec![], false, 0),
+            SubSec::new(String::from("B"), 9, vec![], false, 0),
+        ]);
+        assert!(matches!(
+            msg_processor(&too_many_bits, &mut rand::thread_rng()),
+            Err(PackError::Overflow { .. })
+        ));
+    }
+
+    #[test]
+    fn it_packs_little_endian_sections() {
+        let format = |sections: Vec<Section>| {
+            MsgFormat::new(
+                String::from("Intel"),
+                Range {
+                    start: 0x80,
+                    end: 0x81,
+                },
+                vec![],
+                sections.len() as u8,
+                sections,
+                false,
+                0,
+            )
+        };
+        let le = |section: Section| section.with_byte_order(ByteOrder::LittleEndian);
+
+        // The first sub section takes the least significant bits
+        let signals = format(vec![
+            le(Section::new(
+                String::from("Signals"),
+                4,
+                vec![
+                    SubSec::new(String::from("A"), 4, vec![], true, 0x1),
+                    SubSec::new(String::from("B"), 12, vec![], true, 0xABC),
+                    SubSec::new(String::from("C"), 16, vec![], true, 0x1234),
+                ],
+                false,
+                0,
+            )),
+            le(Section::new(String::from("Code"), 2, vec![], true, 0x1234)),
+            Section::new(String::from("Counter"), 2, vec![], true, 0xBEEF),
+        ]);
+        let data = msg_processor(&signals, &mut rand::thread_rng())
+            .unwrap()
+            .data;
+        assert_eq!(data, vec![0xC1, 0xAB, 0x34, 0x12, 0x34, 0x12, 0xBE, 0xEF]);
+
+        // A sub section can keep its own byte order inside a section
+        let mixed = format(vec![le(Section::new(
+            String::from("Mixed"),
+            4,
+            vec![
+                SubSec::new(String::from("Motorola"), 16, vec![], true, 0x1234)
+                    .with_byte_order(ByteOrder::BigEndian),
+                SubSec::new(String::from("Intel"), 16, vec![], true, 0x5678),
+            ],
+            false,
+            0,
+        ))]);
+        let data = msg_processor(&mixed, &mut rand::thread_rng()).unwrap().data;
+        assert_eq!(&data[..4], &[0x12, 0x34, 0x78, 0x56]);
+
+        let unaligned = format(vec![le(Section::new(
+            String::from("Unaligned"),
+            2,
+            vec![SubSec::new(String::from("A"), 12, vec![], true, 0x123)
+                .with_byte_order(ByteOrder::BigEndian)],
+            false,
+            0,
+        ))]);
+        assert!(matches!(
+            msg_processor(&unaligned, &mut rand::thread_rng()),
+            Err(PackError::UnalignedByteOrder { .. })
+        ));
+
+        // Whole bytes that don't start on a byte boundary can't be swapped either
+        let misaligned = format(vec![le(Section::new(
+            String::from("Misaligned"),
+            3,
+            vec![
+                SubSec::new(String::from("A"), 4, vec![], true, 0),
+                SubSec::new(String::from("B"), 16, vec![], true, 0x1234)
+                    .with_byte_order(ByteOrder::BigEndian),
+                SubSec::new(String::from("C"), 4, vec![], true, 0),
+            ],
+            false,
+            0,
+        ))]);
+        assert!(matches!(
+            msg_processor(&misaligned, &mut rand::thread_rng()),
+            Err(PackError::MisalignedByteOrder { offset: 4, .. })
+        ));
+    }
+
+    #[test]
+    fn it_returns_typed_errors_for_bad_configs() {
+        let dir = tempdir().unwrap();
+        assert!(matches!(
+            read_configs(&dir.path().join("missing.json")),
+            Err(FuzzerError::ConfigRead { .. })
+        ));
+
+        let file = dir.path().join("format.json");
+        fs::write(&file, "{ \"name\": ").unwrap();
+        assert!(matches!(
+            read_configs(&file),
+            Err(FuzzerError::ConfigParse { .. })
+        ));
+
+        let format = MsgFormat::new(
+            String::from("Empty"),
+            Range { start: 0, end: 0 },
+            vec![],
+            0,
+            vec![],
+            false,
+            0,
+        );
+        save_config(file.to_str().unwrap(), &format).unwrap();
+        match read_configs(&file) {
+            Err(FuzzerError::Validation(e)) => {
+                assert_eq!(e.issues[0].path, "cob_id_range");
+                let message = e.to_string();
+                assert!(message.starts_with(&format!(
+                    "{} problem(s) in message formats\n  {}",
+                    e.issues.len(),
+                    e.issues[0]
+                )));
+            }
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_generates_values_inclusively() {
+        let mut rng = seeded_rng(0);
+        // Every value of a 1 bit and an 8 bit field, including the maximum
+        let bit = SubSec::new(String::from("Bit"), 1, vec![], false, 0);
+        let byte = SubSec::new(String::from("Byte"), 8, vec![0x00], false, 0);
+        let bits: BTreeSet<u64> = (0..100)
+            .map(|_| sub_sec_proc(&bit, &mut rng).unwrap())
+            .collect();
+        assert_eq!(bits, (0..=1).collect());
+        let bytes: BTreeSet<u64> = (0..5000)
+            .map(|_| sub_sec_proc(&byte, &mut rng).unwrap())
+            .collect();
+        assert_eq!(bytes, (1..=0xFF).collect());
+
+        let weighted = SubSec::new(String::from("Mode"), 8, vec![3], false, 0)
+            .with_allowed_values(vec![1, 2, 3, 4], vec![1, 0, 5, 1]);
+        let modes: BTreeSet<u64> = (0..200)
+            .map(|_| sub_sec_proc(&weighted, &mut rng).unwrap())
+            .collect();
+        assert_eq!(modes, vec![1, 4].into_iter().collect());
+
+        let section = Section::new(String::from("Count"), 2, vec![], false, 0)
+            .with_byte_order(ByteOrder::LittleEndian)
+            .with_constraints(Constraints {
+                allowed_values: vec![0x1234],
+                ..Constraints::default()
+            });
+        let format = MsgFormat::new(String::from("C"), 1..2, vec![], 1, vec![section], false, 0);
+        assert_eq!(
+            msg_processor(&format, &mut rng).unwrap().data[..2],
+            [0x34, 0x12]
+        );
+    }
+
+    #[test]
+    fn it_stops_when_holes_cover_every_value() {
+        let mut rng = seeded_rng(0);
+        let full = SubSec::new(String::from("Full"), 2, vec![0, 1, 2, 3], false, 0);
+        assert!(matches!(
+            sub_sec_proc(&full, &mut rng),
+            Err(PackError::NoValues { .. })
+        ));
+        // Only one value is left, it's found without retrying at random
+        let last = SubSec::new(String::from("Last"), 8, (0..0xFF).collect(), false, 0);
+        assert_eq!(sub_sec_proc(&last, &mut rng).unwrap(), 0xFF);
+
+        let section = Section::new(
+            String::from("A"),
+            1,
+            vec![full, SubSec::new(String::from("Rest"), 6, vec![], false, 0)],
+            false,
+            0,
+        );
+        let format = MsgFormat::new(String::from("F"), 1..2, vec![], 1, vec![section], false, 0);
+        let issues = validate(&format, Path::new("f.json"));
+        assert_eq!(issues[0].path, "sections[0].sub_secs[0].holes");
+
+        // Allowed values weighted above 0 are all holes
+        let weighted = SubSec::new(String::from("Mode"), 8, vec![1], false, 0)
+            .with_allowed_values(vec![1, 2], vec![1, 0]);
+        assert!(matches!(
+            sub_sec_proc(&weighted, &mut rng),
+            Err(PackError::NoValues { .. })
+        ));
+        let section = Section::new(String::from("A"), 1, vec![weighted], false, 0);
+        let format = MsgFormat::new(String::from("W"), 1..2, vec![], 1, vec![section], false, 0);
+        let issues = validate(&format, Path::new("w.json"));
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].path, "sections[0].sub_secs[0].holes");
+    }
+
+    #[test]
+    fn it_traces_the_values_of_each_field() {
+        let sections = vec![
+            Section::new(
+                String::from("Header"),
+                1,
+                vec![
+                    SubSec::new(String::from("Command"), 3, vec![], true, 0x1),
+                    SubSec::new(String::from("Toggle"), 5, vec![], false, 0),
+                ],
+                false,
+                0,
+            ),
+            Section::new(String::from("Index"), 2, vec![], true, 0x1017),
+            Section::new(String::from("Payload"), 2, vec![], false, 0),
+        ];
+        let format = MsgFormat::new(String::from("F"), 1..2, vec![], 3, sections, false, 0);
+        let msg = pack_msg(&format, &[0x1, 0x12], &mut rand::thread_rng()).unwrap();
+        assert_eq!(msg.data[..3], [0x32, 0x10, 0x17]);
+
+        let lines: Vec<String> = msg.trace.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            lines[..3],
+            [
+                "Header.Command = 1 (0x1) (specified)",
+                "Header.Toggle = 18 (0x12)",
+                "Index = 4119 (0x1017) (specified)",
+            ]
+        );
+        assert_eq!(msg.trace[3].sub_sec, None);
+        assert_eq!(
+            msg.trace[3].value,
+            TraceValue::Bytes(msg.data[3..5].to_vec())
+        );
     }
 }
@@ -0,0 +1,223 @@
+use crate::error::FuzzerError;
+use crate::frame::{CanFrame, FrameError, CANFD_LENGTHS, CAN_MAX_DLEN, EFF_MASK, SFF_MASK};
+use crate::replay::read_frames;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// Byte values that tend to hit edge cases in parsers: zero, all ones and the signed limits
+pub const INTERESTING_BYTES: [u8; 4] = [0x00, 0xFF, 0x7F, 0x80];
+
+/// Largest step added to or subtracted from a byte by an arithmetic mutation
+const ARITH_MAX: u8 = 35;
+
+/// A single change made to a seed frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mutation {
+    /// Flip one bit of the payload
+    BitFlip,
+    /// Replace one payload byte with a different random value
+    ByteSubstitution,
+    /// Add or subtract a small amount from one payload byte
+    Arithmetic,
+    /// Replace one payload byte with one of INTERESTING_BYTES
+    InterestingValue,
+    /// Shorten or lengthen the payload, new bytes are random
+    DlcChange,
+    /// Move to the id just above or below the seed's
+    IdNeighbour,
+}
+
+impl Mutation {
+    pub const ALL: [Mutation; 6] = [
+        Mutation::BitFlip,
+        Mutation::ByteSubstitution,
+        Mutation::Arithmetic,
+        Mutation::InterestingValue,
+        Mutation::DlcChange,
+        Mutation::IdNeighbour,
+    ];
+
+    /// Mutations that only change payload bytes, they need a payload to work on
+    fn needs_data(self) -> bool {
+        !matches!(self, Mutation::DlcChange | Mutation::IdNeighbour)
+    }
+}
+
+impl fmt::Display for Mutation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Mutation::BitFlip => "bit flip",
+            Mutation::ByteSubstitution => "byte substitution",
+            Mutation::Arithmetic => "arithmetic",
+            Mutation::InterestingValue => "interesting value",
+            Mutation::DlcChange => "DLC change",
+            Mutation::IdNeighbour => "id neighbour",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Read the seed frames of a corpus, a candump -l or listen log or a directory of them
+/// Error frames are left out, they report bus state and can't be sent
+pub fn read_corpus(path: &Path) -> Result<Vec<CanFrame>, FuzzerError> {
+    let mut frames = vec![];
+    if path.is_dir() {
+        let read_error = |source| FuzzerError::LogRead {
+            path: path.to_owned(),
+            source,
+        };
+        for entry in fs::read_dir(path).map_err(read_error)? {
+            frames.append(&mut read_corpus(&entry.map_err(read_error)?.path())?);
+        }
+    } else {
+        frames = read_frames(path)?;
+        frames.retain(|frame| !frame.is_error());
+    }
+    Ok(frames)
+}
+
+/// Returns a copy of `seed` changed by one randomly chosen mutation
+/// Payload mutations are only chosen when the seed has a payload
+pub fn mutate<R: Rng + ?Sized>(
+    seed: &CanFrame,
+    rng: &mut R,
+) -> Result<(Mutation, CanFrame), FrameError> {
+    let has_data = !seed.data().is_empty() && !seed.is_rtr();
+    let choices: Vec<Mutation> = Mutation::ALL
+        .iter()
+        .copied()
+        .filter(|mutation| has_data || !mutation.needs_data())
+        .collect();
+    // ALL always has mutations that don't need data
+    let mutation = *choices.choose(rng).unwrap();
+    Ok((mutation, apply(mutation, seed, rng)?))
+}
+
+/// Returns a copy of `seed` changed by `mutation`
+pub fn apply<R: Rng + ?Sized>(
+    mutation: Mutation,
+    seed: &CanFrame,
+    rng: &mut R,
+) -> Result<CanFrame, FrameError> {
+    let mut id = seed.id();
+    let mut data = seed.data().to_vec();
+    let flags = seed.flags();
+    let byte = if data.is_empty() {
+        None
+    } else {
+        Some(rng.gen_range(0..data.len()))
+    };
+
+    match (mutation, byte) {
+        (Mutation::BitFlip, Some(i)) => data[i] ^= 1 << rng.gen_range(0..8),
+        (Mutation::ByteSubstitution, Some(i)) => data[i] ^= rng.gen_range(1..=u8::MAX),
+        (Mutation::Arithmetic, Some(i)) => {
+            let step = rng.gen_range(1..=ARITH_MAX);
+            data[i] = if rng.gen() {
+                data[i].wrapping_add(step)
+            } else {
+                data[i].wrapping_sub(step)
+            };
+        }
+        (Mutation::InterestingValue, Some(i)) => data[i] = *INTERESTING_BYTES.choose(rng).unwrap(),
+        (Mutation::DlcChange, _) => {
+            let lengths: Vec<usize> = if flags.fd {
+                CANFD_LENGTHS.to_vec()
+            } else {
+                (0..=CAN_MAX_DLEN).collect()
+            };
+            let len = *lengths
+                .iter()
+                .filter(|&&len| len != data.len())
+                .collect::<Vec<_>>()
+                .choose(rng)
+                .unwrap();
+            // Remote frames carry a length but no data
+            let extra: Vec<u8> = (data.len()..*len)
+                .map(|_| if flags.rtr { 0 } else { rng.gen() })
+                .collect();
+            data.truncate(*len);
+            data.extend(extra);
+        }
+        (Mutation::IdNeighbour, _) => {
+            let max = if flags.extended { EFF_MASK } else { SFF_MASK };
+            id = if id == 0 || (id < max && rng.gen()) {
+                id + 1
+            } else {
+                id - 1
+            };
+        }
+        // Payload mutations of an empty payload leave the frame as it is
+        (_, None) => {}
+    }
+    CanFrame::with_flags(id, &data, flags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::FrameFlags;
+    use crate::rng::seeded_rng;
+
+    #[test]
+    fn it_changes_one_thing_at_a_time() {
+        let mut rng = seeded_rng(7);
+        let seed = CanFrame::new(0x181, &[0x10, 0x20, 0x30, 0x40], false, false).unwrap();
+        for _ in 0..200 {
+            let (mutation, frame) = mutate(&seed, &mut rng).unwrap();
+            assert_eq!(frame.is_extended(), seed.is_extended());
+            match mutation {
+                Mutation::IdNeighbour => {
+                    assert!(frame.id() == 0x180 || frame.id() == 0x182);
+                    assert_eq!(frame.data(), seed.data());
+                }
+                Mutation::DlcChange => {
+                    assert_ne!(frame.data().len(), seed.data().len());
+                    let common = frame.data().len().min(seed.data().len());
+                    assert_eq!(frame.data()[..common], seed.data()[..common]);
+                }
+                _ => {
+                    assert_eq!(frame.id(), seed.id());
+                    assert_eq!(frame.data().len(), seed.data().len());
+                    let changed = (0..4).filter(|&i| frame.data()[i] != seed.data()[i]);
+                    assert!(changed.count() <= 1);
+                }
+            }
+            if mutation == Mutation::BitFlip {
+                let flipped: u32 = (0..4)
+                    .map(|i| (frame.data()[i] ^ seed.data()[i]).count_ones())
+                    .sum();
+                assert_eq!(flipped, 1);
+            }
+        }
+    }
+
+    #[test]
+    fn it_keeps_mutated_frames_valid() {
+        let mut rng = seeded_rng(1);
+        let fd = FrameFlags {
+            fd: true,
+            brs: true,
+            ..FrameFlags::default()
+        };
+        let seeds = [
+            CanFrame::new(0, &[], false, false).unwrap(),
+            CanFrame::new(SFF_MASK, &[0xFF; 8], false, false).unwrap(),
+            CanFrame::new(0x700, &[0; 1], true, false).unwrap(),
+            CanFrame::new_extended(EFF_MASK, &[0x01], false, false).unwrap(),
+            CanFrame::with_flags(0x123, &[0xAA; 12], fd).unwrap(),
+        ];
+        for seed in &seeds {
+            for _ in 0..100 {
+                let (mutation, frame) = mutate(seed, &mut rng).unwrap();
+                assert_eq!(frame.flags(), seed.flags());
+                if seed.data().is_empty() || seed.is_rtr() {
+                    assert!(!mutation.needs_data());
+                }
+            }
+        }
+    }
+}
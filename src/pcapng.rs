@@ -0,0 +1,228 @@
+use crate::frame::{CanFrame, CANFD_MAX_DLEN, CAN_MAX_DLEN};
+use crate::recorder::{Clock, Direction, FrameSink};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Link type of frames laid out like a kernel can_frame/canfd_frame
+pub const LINKTYPE_CAN_SOCKETCAN: u16 = 227;
+
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 0x0000_0001;
+const ENHANCED_PACKET_BLOCK: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const OPT_IF_NAME: u16 = 2;
+const OPT_IF_TSRESOL: u16 = 9;
+const OPT_EPB_FLAGS: u16 = 2;
+
+/// epb_flags direction bits
+const EPB_INBOUND: u32 = 0x1;
+const EPB_OUTBOUND: u32 = 0x2;
+
+/// Flag marking a canfd_frame in the LINKTYPE_CAN_SOCKETCAN header
+const CANFD_FDF: u8 = 0x04;
+
+/// Comment attached to every frame the fuzzer sent, so they can be filtered in Wireshark
+pub const INJECTED_COMMENT: &str = "injected by fuzzer";
+
+/// Capture in the pcapng format, readable by Wireshark and tshark
+/// Each session appends a new section to the file, each channel gets its own interface
+/// in it, described the first time it records a frame
+pub struct PcapngWriter {
+    file: File,
+    clock: Clock,
+    interfaces: HashMap<String, u32>,
+}
+
+impl PcapngWriter {
+    /// Open the capture file and start a new section in it
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut writer = Self {
+            file: OpenOptions::new().create(true).append(true).open(path)?,
+            clock: Clock::new(),
+            interfaces: HashMap::new(),
+        };
+        let mut body = vec![];
+        put_u32(&mut body, BYTE_ORDER_MAGIC);
+        put_u16(&mut body, 1);
+        put_u16(&mut body, 0);
+        // Section length isn't known up front
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        put_option(&mut body, OPT_END, &[]);
+        writer.write_block(SECTION_HEADER_BLOCK, &body)?;
+        Ok(writer)
+    }
+
+    /// Id of the interface for `channel`, describing it first if it's new
+    fn interface(&mut self, channel: &str) -> io::Result<u32> {
+        if let Some(&id) = self.interfaces.get(channel) {
+            return Ok(id);
+        }
+        let mut body = vec![];
+        put_u16(&mut body, LINKTYPE_CAN_SOCKETCAN);
+        put_u16(&mut body, 0);
+        // No snapshot length limit
+        put_u32(&mut body, 0);
+        put_option(&mut body, OPT_IF_NAME, channel.as_bytes());
+        // Timestamps in microseconds
+        put_option(&mut body, OPT_IF_TSRESOL, &[6]);
+        put_option(&mut body, OPT_END, &[]);
+        self.write_block(INTERFACE_DESCRIPTION_BLOCK, &body)?;
+
+        let id = self.interfaces.len() as u32;
+        self.interfaces.insert(channel.to_owned(), id);
+        Ok(id)
+    }
+
+    /// Write a block with its type and both length fields, padding the body to 4 bytes
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> io::Result<()> {
+        let total = (12 + body.len().next_multiple_of(4)) as u32;
+        let mut block = Vec::with_capacity(total as usize);
+        put_u32(&mut block, block_type);
+        put_u32(&mut block, total);
+        block.extend_from_slice(body);
+        pad(&mut block);
+        put_u32(&mut block, total);
+        // Each block is written at once, so nothing is lost if the process is stopped
+        self.file.write_all(&block)
+    }
+}
+
+impl FrameSink for PcapngWriter {
+    fn record(&mut self, direction: Direction, channel: &str, frame: &CanFrame) -> io::Result<()> {
+        let interface = self.interface(channel)?;
+        let micros = self.clock.now().as_micros() as u64;
+        let packet = socketcan_packet(frame);
+
+        let mut body = vec![];
+        put_u32(&mut body, interface);
+        put_u32(&mut body, (micros >> 32) as u32);
+        put_u32(&mut body, micros as u32);
+        put_u32(&mut body, packet.len() as u32);
+        put_u32(&mut body, packet.len() as u32);
+        body.extend_from_slice(&packet);
+        pad(&mut body);
+        let flags = match direction {
+            Direction::Tx => EPB_OUTBOUND,
+            Direction::Rx => EPB_INBOUND,
+        };
+        put_option(&mut body, OPT_EPB_FLAGS, &flags.to_le_bytes());
+        if direction == Direction::Tx {
+            put_option(&mut body, OPT_COMMENT, INJECTED_COMMENT.as_bytes());
+        }
+        put_option(&mut body, OPT_END, &[]);
+        self.write_block(ENHANCED_PACKET_BLOCK, &body)
+    }
+}
+
+/// Frame as a kernel can_frame or canfd_frame with the id in network byte order,
+/// the layout LINKTYPE_CAN_SOCKETCAN expects
+pub fn socketcan_packet(frame: &CanFrame) -> Vec<u8> {
+    let data = frame.data();
+    let mut packet = frame.raw_id().to_be_bytes().to_vec();
+    packet.push(data.len() as u8);
+    if frame.is_fd() {
+        packet.extend_from_slice(&[frame.fd_flags() | CANFD_FDF, 0, 0]);
+    } else {
+        packet.extend_from_slice(&[0, 0, 0]);
+    }
+    packet.extend_from_slice(data);
+    let mtu = if frame.is_fd() {
+        CANFD_MAX_DLEN
+    } else {
+        CAN_MAX_DLEN
+    };
+    packet.resize(8 + mtu, 0);
+    packet
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Option code, length and value padded to 4 bytes
+fn put_option(buf: &mut Vec<u8>, code: u16, value: &[u8]) {
+    put_u16(buf, code);
+    put_u16(buf, value.len() as u16);
+    buf.extend_from_slice(value);
+    pad(buf);
+}
+
+fn pad(buf: &mut Vec<u8>) {
+    buf.resize(buf.len().next_multiple_of(4), 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::FrameFlags;
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+    }
+
+    #[test]
+    fn it_lays_out_socketcan_frames() {
+        let frame = CanFrame::new_extended(0x1234, &[0xAA, 0xBB], false, false).unwrap();
+        let packet = socketcan_packet(&frame);
+        assert_eq!(packet.len(), 16);
+        assert_eq!(packet[..8], [0x80, 0x00, 0x12, 0x34, 2, 0, 0, 0]);
+        assert_eq!(packet[8..10], [0xAA, 0xBB]);
+
+        let fd = FrameFlags {
+            fd: true,
+            brs: true,
+            ..FrameFlags::default()
+        };
+        let frame = CanFrame::with_flags(0x123, &[0x11; 12], fd).unwrap();
+        let packet = socketcan_packet(&frame);
+        assert_eq!(packet.len(), 72);
+        assert_eq!(packet[..8], [0x00, 0x00, 0x01, 0x23, 12, 0x05, 0, 0]);
+    }
+
+    #[test]
+    fn it_writes_one_interface_per_channel() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("capture.pcapng");
+        let frame = CanFrame::new(0x601, &[0x40, 0x00, 0x10, 0x00], false, false).unwrap();
+        {
+            let mut writer = PcapngWriter::create(&path).unwrap();
+            writer.record(Direction::Tx, "vcan0", &frame).unwrap();
+            writer.record(Direction::Rx, "vcan0", &frame).unwrap();
+            writer.record(Direction::Rx, "vcan1", &frame).unwrap();
+        }
+
+        let bytes = fs::read(&path).unwrap();
+        let mut blocks = vec![];
+        let mut at = 0;
+        while at < bytes.len() {
+            let (block_type, len) = (u32_at(&bytes, at), u32_at(&bytes, at + 4) as usize);
+            assert_eq!(len % 4, 0);
+            assert_eq!(u32_at(&bytes, at + len - 4) as usize, len);
+            blocks.push((block_type, &bytes[at + 8..at + len - 4]));
+            at += len;
+        }
+        let types: Vec<u32> = blocks.iter().map(|(block_type, _)| *block_type).collect();
+        assert_eq!(types, [SECTION_HEADER_BLOCK, 1, 6, 6, 1, 6]);
+        assert_eq!(blocks[1].1[..2], LINKTYPE_CAN_SOCKETCAN.to_le_bytes());
+
+        // Interface ids and the injected comment
+        let comment = INJECTED_COMMENT.as_bytes();
+        let has_comment = |body: &[u8]| body.windows(comment.len()).any(|w| w == comment);
+        assert_eq!(u32_at(blocks[2].1, 0), 0);
+        assert!(has_comment(blocks[2].1));
+        assert_eq!(u32_at(blocks[3].1, 0), 0);
+        assert!(!has_comment(blocks[3].1));
+        assert_eq!(u32_at(blocks[5].1, 0), 1);
+    }
+}
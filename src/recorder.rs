@@ -0,0 +1,536 @@
+use crate::emcy::Emcy;
+use crate::frame::{CanFrame, CANFD_BRS, CANFD_ESI, CANFD_LENGTHS, ERR_FLAG};
+use crate::msg_processor::{log_frame, FieldTrace, MsgFormat, TraceValue};
+use crate::pcapng::PcapngWriter;
+use serde::Serialize;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Whether a recorded frame was sent by the fuzzer or heard on the bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Tx,
+    Rx,
+}
+
+impl Direction {
+    /// Marker written after a candump frame, `T` or `R` as can-utils does
+    fn marker(self) -> &'static str {
+        match self {
+            Direction::Tx => "T",
+            Direction::Rx => "R",
+        }
+    }
+}
+
+/// Message format and section values a sent frame was generated from
+#[derive(Debug, Clone, Copy)]
+pub struct Origin<'a> {
+    pub format: &'a MsgFormat,
+    pub trace: &'a [FieldTrace],
+}
+
+/// Destination for the frames of a session, e.g. a log file
+pub trait FrameSink {
+    /// Record a frame sent or heard on `channel`
+    fn record(&mut self, direction: Direction, channel: &str, frame: &CanFrame) -> io::Result<()>;
+
+    /// Record a frame sent on `channel` that was generated from a message format
+    /// Sinks with no place for the format and values record the frame alone
+    fn record_generated(
+        &mut self,
+        channel: &str,
+        frame: &CanFrame,
+        _origin: &Origin<'_>,
+    ) -> io::Result<()> {
+        self.record(Direction::Tx, channel, frame)
+    }
+}
+
+/// File formats sessions can be recorded in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// `candump -l` lines, readable by can-utils (canplayer, log2asc, ...)
+    Candump,
+    /// The fuzzer's original `TX [date]: channel id data` lines
+    #[default]
+    Text,
+    /// pcapng capture with the SocketCAN link type, for Wireshark
+    Pcapng,
+    /// One JSON object per line for each frame, with the format and field values it came from
+    Jsonl,
+}
+
+impl LogFormat {
+    pub const NAMES: [&'static str; 4] = ["candump", "text", "pcapng", "jsonl"];
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "candump" => Ok(LogFormat::Candump),
+            "text" => Ok(LogFormat::Text),
+            "pcapng" => Ok(LogFormat::Pcapng),
+            "jsonl" => Ok(LogFormat::Jsonl),
+            _ => Err(format!("unknown log format {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LogFormat::Candump => "candump",
+            LogFormat::Text => "text",
+            LogFormat::Pcapng => "pcapng",
+            LogFormat::Jsonl => "jsonl",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Open a log file in the given format, frames are appended to it
+pub fn open_sink(path: &Path, format: LogFormat) -> io::Result<Box<dyn FrameSink>> {
+    Ok(match format {
+        LogFormat::Candump => Box::new(CandumpLog::create(path)?),
+        LogFormat::Text => Box::new(TextLog {
+            path: path.to_owned(),
+        }),
+        LogFormat::Pcapng => Box::new(PcapngWriter::create(path)?),
+        LogFormat::Jsonl => Box::new(JsonLog::create(path)?),
+    })
+}
+
+/// Every frame goes to each of the sinks, e.g. a log and a capture
+impl FrameSink for Vec<Box<dyn FrameSink>> {
+    fn record(&mut self, direction: Direction, channel: &str, frame: &CanFrame) -> io::Result<()> {
+        for sink in self.iter_mut() {
+            sink.record(direction, channel, frame)?;
+        }
+        Ok(())
+    }
+
+    fn record_generated(
+        &mut self,
+        channel: &str,
+        frame: &CanFrame,
+        origin: &Origin<'_>,
+    ) -> io::Result<()> {
+        for sink in self.iter_mut() {
+            sink.record_generated(channel, frame, origin)?;
+        }
+        Ok(())
+    }
+}
+
+/// Wall clock time that never goes backwards
+/// Starts from the system time when created and then follows a monotonic clock,
+/// so timestamps keep their spacing if the system time is adjusted mid session
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    start: Duration,
+    started: Instant,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self {
+            start: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Time since the unix epoch
+    pub fn now(&self) -> Duration {
+        self.start + self.started.elapsed()
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Log in the `candump -l` format: `(1436509052.249713) vcan0 123#DEADBEEF T`
+pub struct CandumpLog {
+    file: File,
+    clock: Clock,
+}
+
+impl CandumpLog {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            file: OpenOptions::new().create(true).append(true).open(path)?,
+            clock: Clock::new(),
+        })
+    }
+}
+
+impl FrameSink for CandumpLog {
+    fn record(&mut self, direction: Direction, channel: &str, frame: &CanFrame) -> io::Result<()> {
+        // Each line is written at once, so nothing is lost if the process is stopped
+        self.file
+            .write_all(candump_line(self.clock.now(), channel, frame, direction).as_bytes())
+    }
+}
+
+/// Line of a `candump -l` log, `timestamp` is the time since the unix epoch
+pub fn candump_line(
+    timestamp: Duration,
+    channel: &str,
+    frame: &CanFrame,
+    direction: Direction,
+) -> String {
+    format!(
+        "({}.{:06}) {} {} {}\n",
+        timestamp.as_secs(),
+        timestamp.subsec_micros(),
+        channel,
+        candump_frame(frame),
+        direction.marker()
+    )
+}
+
+/// Log in the original text format, see msg_processor::log_frame
+/// EMCY messages heard are decoded on a `#` comment line below the frame
+pub struct TextLog {
+    path: PathBuf,
+}
+
+impl FrameSink for TextLog {
+    fn record(&mut self, direction: Direction, channel: &str, frame: &CanFrame) -> io::Result<()> {
+        let note = match direction {
+            Direction::Tx => "TX",
+            Direction::Rx => "RX",
+        };
+        log_frame(&self.path, note, channel, frame)?;
+        match Emcy::from_frame(frame) {
+            Some(emcy) if direction == Direction::Rx => {
+                let mut file = OpenOptions::new().append(true).open(&self.path)?;
+                writeln!(file, "# {}", emcy)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Log of JSON Lines events, one object per frame:
+/// `{"timestamp":1436509052.249713,"channel":"vcan0","direction":"tx","id":129,"flags":[],
+/// "dlc":2,"data":"0011","format":"EMCY","fields":[{"section":"Error Code","value":17}]}`
+/// format and fields are only present for frames generated from a message format.
+/// Fields of sub sections also name the sub section, random bytes are given as hex digits.
+/// EMCY messages heard are decoded into an `emcy` object
+pub struct JsonLog {
+    file: File,
+    clock: Clock,
+}
+
+/// A line of a JsonLog
+#[derive(Serialize)]
+struct Event<'a> {
+    /// Seconds since the unix epoch
+    timestamp: f64,
+    channel: &'a str,
+    direction: Direction,
+    id: u32,
+    flags: Vec<&'static str>,
+    dlc: usize,
+    /// Payload as hex digits
+    data: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<&'a str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<TraceField<'a>>,
+    /// Decoded EMCY message, for frames heard on 0x081 - 0x0FF
+    #[serde(skip_serializing_if = "Option::is_none")]
+    emcy: Option<serde_json::Value>,
+}
+
+/// A FieldTrace as JSON, random bytes are given as hex digits
+#[derive(Serialize)]
+pub struct TraceField<'a> {
+    section: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sub_sec: Option<&'a str>,
+    value: serde_json::Value,
+    #[serde(skip_serializing_if = "is_false")]
+    specified: bool,
+}
+
+impl<'a> From<&'a FieldTrace> for TraceField<'a> {
+    fn from(field: &'a FieldTrace) -> Self {
+        TraceField {
+            section: &field.section,
+            sub_sec: field.sub_sec.as_deref(),
+            value: match &field.value {
+                TraceValue::Value(value) => (*value).into(),
+                TraceValue::Bytes(bytes) => hex(bytes).into(),
+            },
+            specified: field.specified,
+        }
+    }
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+impl JsonLog {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            file: OpenOptions::new().create(true).append(true).open(path)?,
+            clock: Clock::new(),
+        })
+    }
+
+    fn write_event(
+        &mut self,
+        direction: Direction,
+        channel: &str,
+        frame: &CanFrame,
+        origin: Option<&Origin<'_>>,
+    ) -> io::Result<()> {
+        let flags = [
+            (frame.is_extended(), "extended"),
+            (frame.is_rtr(), "rtr"),
+            (frame.is_error(), "error"),
+            (frame.is_fd(), "fd"),
+            (frame.is_brs(), "brs"),
+            (frame.is_esi(), "esi"),
+        ];
+        let len = frame.data().len();
+        // FD payload lengths above 8 bytes are coded, 12 bytes is DLC 9 and so on
+        let dlc = if frame.is_fd() {
+            CANFD_LENGTHS.iter().position(|&l| l == len).unwrap_or(len)
+        } else {
+            len
+        };
+        let event = Event {
+            timestamp: self.clock.now().as_secs_f64(),
+            channel,
+            direction,
+            id: frame.id(),
+            flags: flags
+                .iter()
+                .filter(|(set, _)| *set)
+                .map(|(_, name)| *name)
+                .collect(),
+            dlc,
+            data: hex(frame.data()),
+            format: origin.map(|origin| origin.format.name()),
+            fields: origin
+                .map(|origin| origin.trace)
+                .unwrap_or_default()
+                .iter()
+                .map(TraceField::from)
+                .collect(),
+            emcy: match direction {
+                Direction::Rx => Emcy::from_frame(frame).map(|emcy| emcy.to_json()),
+                Direction::Tx => None,
+            },
+        };
+        let mut line = serde_json::to_string(&event)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())
+    }
+}
+
+impl FrameSink for JsonLog {
+    fn record(&mut self, direction: Direction, channel: &str, frame: &CanFrame) -> io::Result<()> {
+        self.write_event(direction, channel, frame, None)
+    }
+
+    fn record_generated(
+        &mut self,
+        channel: &str,
+        frame: &CanFrame,
+        origin: &Origin<'_>,
+    ) -> io::Result<()> {
+        self.write_event(Direction::Tx, channel, frame, Some(origin))
+    }
+}
+
+/// Bytes as uppercase hex digits
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+/// Frame in candump's compact notation: `123#DEADBEEF`, `12345678#`, `123#R` or
+/// `123##1DEADBEEF` for an FD frame with BRS set
+pub fn candump_frame(frame: &CanFrame) -> String {
+    let id = if frame.is_error() {
+        format!("{:08X}", frame.id() | ERR_FLAG)
+    } else if frame.is_extended() {
+        format!("{:08X}", frame.id())
+    } else {
+        format!("{:03X}", frame.id())
+    };
+    let data = hex(frame.data());
+    if frame.is_fd() {
+        let mut flags = 0;
+        if frame.is_brs() {
+            flags |= CANFD_BRS;
+        }
+        if frame.is_esi() {
+            flags |= CANFD_ESI;
+        }
+        format!("{}##{:X}{}", id, flags, data)
+    } else if frame.is_rtr() && !frame.data().is_empty() {
+        // Remote frames carry a length but no data, can-utils writes it after the R
+        format!("{}#R{}", id, frame.data().len())
+    } else if frame.is_rtr() {
+        format!("{}#R", id)
+    } else {
+        format!("{}#{}", id, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::{FrameFlags, ERR_BUSOFF};
+    use crate::msg_processor::{pack_msg, read_configs};
+    use crate::replay::{parse_candump_line, read_log};
+    use crate::rng::seeded_rng;
+    use serde_json::Value;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn it_writes_candump_notation() {
+        let fd = FrameFlags {
+            fd: true,
+            brs: true,
+            ..FrameFlags::default()
+        };
+        let frames = [
+            (
+                CanFrame::new(0x44, &[0x2A, 0x36, 0xBA], false, false).unwrap(),
+                "044#2A36BA",
+            ),
+            (
+                CanFrame::new_extended(0xA001, &[], false, false).unwrap(),
+                "0000A001#",
+            ),
+            (
+                CanFrame::new(0x123, &[0; 2], true, false).unwrap(),
+                "123#R2",
+            ),
+            (CanFrame::new(0x705, &[], true, false).unwrap(), "705#R"),
+            (
+                CanFrame::new(ERR_BUSOFF, &[0; 8], false, true).unwrap(),
+                "20000040#0000000000000000",
+            ),
+            (
+                CanFrame::with_flags(0x321, &[0x00, 0x11], fd).unwrap(),
+                "321##10011",
+            ),
+        ];
+        for (frame, text) in &frames {
+            assert_eq!(candump_frame(frame), *text);
+            let line = format!("(1436509052.249713) vcan0 {}", text);
+            assert_eq!(parse_candump_line(&line).unwrap().frame, *frame);
+        }
+    }
+
+    #[test]
+    fn it_records_sessions_that_read_back() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("session.log");
+        let request = CanFrame::new(0x601, &[0x40, 0x00, 0x10, 0x00], false, false).unwrap();
+        let response = CanFrame::new(0x581, &[0x43, 0x00, 0x10, 0x00], false, false).unwrap();
+        {
+            let mut sink = open_sink(&path, LogFormat::Candump).unwrap();
+            sink.record(Direction::Tx, "vcan0", &request).unwrap();
+            sink.record(Direction::Rx, "vcan1", &response).unwrap();
+        }
+
+        let logged = read_log(&path).unwrap();
+        assert_eq!(logged.len(), 2);
+        assert_eq!(logged[0].frame, request);
+        assert_eq!(logged[0].channel, "vcan0");
+        assert_eq!(logged[0].direction, Some(Direction::Tx));
+        assert_eq!(logged[1].direction, Some(Direction::Rx));
+        assert!(logged[0].timestamp.unwrap() <= logged[1].timestamp.unwrap());
+    }
+
+    #[test]
+    fn it_decodes_emcy_messages_in_text_logs() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("session.log");
+        let emcy = CanFrame::new(0x085, &[0x10, 0x81, 0x11, 0, 0, 0, 0, 0], false, false).unwrap();
+        {
+            let mut sink = open_sink(&path, LogFormat::Text).unwrap();
+            sink.record(Direction::Tx, "vcan0", &emcy).unwrap();
+            sink.record(Direction::Rx, "vcan0", &emcy).unwrap();
+        }
+
+        let text = fs::read_to_string(&path).unwrap();
+        let comments: Vec<&str> = text.lines().filter(|l| l.starts_with('#')).collect();
+        assert_eq!(
+            comments,
+            [
+                "# EMCY from node 5: 8110 monitoring, CAN overrun, objects lost, \
+              error register 0x11 (generic, communication), manufacturer data 00 00 00 00 00"
+            ]
+        );
+        let logged = read_log(&path).unwrap();
+        assert_eq!(logged.len(), 2);
+        assert!(logged.iter().all(|logged| logged.frame == emcy));
+    }
+
+    #[test]
+    fn it_logs_json_events_with_field_values() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("session.jsonl");
+        let format = &read_configs(Path::new("assets/emcy.json")).unwrap()[0];
+        let values: Vec<u64> = (1..=format.fields().len() as u64).collect();
+        let msg = pack_msg(format, &values, &mut seeded_rng(0)).unwrap();
+        let frame = CanFrame::new(0x081, &msg.data, false, false).unwrap();
+        {
+            let mut sink = open_sink(&path, LogFormat::Jsonl).unwrap();
+            let origin = Origin {
+                format,
+                trace: &msg.trace,
+            };
+            sink.record_generated("vcan0", &frame, &origin).unwrap();
+            sink.record(Direction::Rx, "vcan0", &frame).unwrap();
+        }
+
+        let log = fs::read_to_string(&path).unwrap();
+        let events: Vec<Value> = log
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["direction"], "tx");
+        assert_eq!(events[0]["id"], 0x081);
+        assert_eq!(events[0]["dlc"], 8);
+        assert_eq!(events[0]["data"], hex(&msg.data));
+        assert_eq!(events[0]["format"], format.name());
+        let fields = events[0]["fields"].as_array().unwrap();
+        assert_eq!(fields.len(), msg.trace.len());
+        let first = fields
+            .iter()
+            .find(|field| field["sub_sec"].is_string())
+            .unwrap();
+        assert_eq!(first["sub_sec"], format.fields()[0].name());
+        assert_eq!(first["value"], 1);
+        assert_eq!(events[1]["direction"], "rx");
+        assert!(events[1].get("format").is_none());
+        // Heard EMCY messages are decoded
+        assert!(events[0].get("emcy").is_none());
+        assert_eq!(events[1]["emcy"]["node"], 1);
+        assert_eq!(events[1]["emcy"]["register"], 3);
+    }
+}
@@ -0,0 +1,336 @@
+use crate::error::FuzzerError;
+use crate::frame::{
+    CanFrame, FrameFlags, CANFD_BRS, CANFD_ESI, CAN_MAX_DLEN, EFF_MASK, ERR_FLAG, SFF_MASK,
+};
+use crate::msg_processor::print_frame;
+use crate::recorder::Direction;
+use crate::transport::CanTransport;
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A frame read from a log, with what else the log says about it
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoggedFrame {
+    /// Time since the unix epoch, only candump logs are precise enough to keep it
+    pub timestamp: Option<Duration>,
+    pub channel: String,
+    /// None when the log doesn't say, e.g. candump logs of other tools
+    pub direction: Option<Direction>,
+    pub frame: CanFrame,
+}
+
+impl LoggedFrame {
+    /// Returns true unless the frame is marked as received
+    /// Frames of logs without directions count as sent, like canplayer does
+    pub fn is_sent(&self) -> bool {
+        self.direction != Some(Direction::Rx)
+    }
+}
+
+/// Read every frame of a candump -l log or a text listen log
+/// Lines starting with `(` are candump lines, the format is picked per line
+pub fn read_log(path: &Path) -> Result<Vec<LoggedFrame>, FuzzerError> {
+    let text = fs::read_to_string(path).map_err(|source| FuzzerError::LogRead {
+        path: path.to_owned(),
+        source,
+    })?;
+
+    let mut frames = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        // Comment lines, e.g. the decoded EMCY messages of text logs
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parsed = if line.starts_with('(') {
+            parse_candump_line(line)
+        } else {
+            parse_log_line(line)
+        };
+        frames.push(parsed.map_err(|reason| FuzzerError::LogParse {
+            path: path.to_owned(),
+            line: i + 1,
+            reason,
+        })?);
+    }
+    Ok(frames)
+}
+
+/// Read every frame of a log, sent and received alike
+pub fn read_frames(path: &Path) -> Result<Vec<CanFrame>, FuzzerError> {
+    Ok(read_log(path)?
+        .into_iter()
+        .map(|logged| logged.frame)
+        .collect())
+}
+
+/// Whether `logged` is sent on `transport`, frames go on the channel they were recorded
+/// on when that channel is one of the transports and on every transport otherwise
+pub fn is_sent_on(
+    logged: &LoggedFrame,
+    transport: &dyn CanTransport,
+    transports: &[Box<dyn CanTransport>],
+) -> bool {
+    transport.channel() == logged.channel
+        || transports
+            .iter()
+            .all(|other| other.channel() != logged.channel)
+}
+
+/// Time between replayed frames
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timing {
+    /// The same delay after every frame
+    Fixed(Duration),
+    /// The gaps between the recorded timestamps, divided by `speed`
+    Original { speed: f64 },
+}
+
+/// Send the frames, each on the transports picked by `is_sent_on`
+pub fn replay(
+    frames: &[LoggedFrame],
+    transports: &[Box<dyn CanTransport>],
+    timing: Timing,
+) -> Result<(), FuzzerError> {
+    let started = Instant::now();
+    let first = frames.iter().find_map(|logged| logged.timestamp);
+    for (i, logged) in frames.iter().enumerate() {
+        match timing {
+            Timing::Fixed(delay) if i > 0 => thread::sleep(delay),
+            Timing::Original { speed } => {
+                // Sleep until the frame's offset from the first, so time spent
+                // sending doesn't add up over a long session
+                let offset = match (first, logged.timestamp) {
+                    (Some(first), Some(timestamp)) => {
+                        timestamp.saturating_sub(first).div_f64(speed)
+                    }
+                    _ => Duration::ZERO,
+                };
+                thread::sleep((started + offset).saturating_duration_since(Instant::now()));
+            }
+            _ => {}
+        }
+
+        for transport in transports {
+            if !is_sent_on(logged, transport.as_ref(), transports) {
+                continue;
+            }
+            transport.send_frame(&logged.frame)?;
+            print_frame(transport.channel(), &logged.frame);
+        }
+    }
+    Ok(())
+}
+
+/// Parse a `TX [timestamp]: channel id data` log line
+fn parse_log_line(line: &str) -> Result<LoggedFrame, String> {
+    let (prefix, rest) = line
+        .split_once("]:")
+        .ok_or_else(|| String::from("missing timestamp"))?;
+    let note = prefix.split_whitespace().next().unwrap_or_default();
+    let mut fields = rest.split_whitespace();
+    let channel = fields
+        .next()
+        .ok_or_else(|| String::from("missing channel"))?;
+    let id_str = fields.next().ok_or_else(|| String::from("missing id"))?;
+    let id_hex = id_str.trim_start_matches("0x");
+    let id = u32::from_str_radix(id_hex, 16).map_err(|_| format!("invalid id {}", id_str))?;
+    let data = fields
+        .map(|byte| u8::from_str_radix(byte, 16).map_err(|_| format!("invalid byte {}", byte)))
+        .collect::<Result<Vec<u8>, String>>()?;
+
+    // Extended ids are logged with 8 hex digits, FD frames are the only ones over 8 bytes
+    let flags = FrameFlags {
+        extended: id_hex.len() > 3 || id > SFF_MASK,
+        fd: data.len() > CAN_MAX_DLEN,
+        ..FrameFlags::default()
+    };
+    Ok(LoggedFrame {
+        timestamp: None,
+        channel: channel.to_owned(),
+        direction: match note {
+            "TX" => Some(Direction::Tx),
+            "RX" => Some(Direction::Rx),
+            _ => None,
+        },
+        frame: CanFrame::with_flags(id, &data, flags).map_err(|e| e.to_string())?,
+    })
+}
+
+/// Parse a candump -l line `(1436509052.249713) vcan0 123#DEADBEEF`, optionally
+/// followed by a `T` or `R` direction marker.
+/// Extended ids have 8 hex digits, `R` marks a remote frame and `##` an FD
+/// frame whose first hex digit holds its BRS/ESI flags
+pub fn parse_candump_line(line: &str) -> Result<LoggedFrame, String> {
+    let mut fields = line.split_whitespace();
+    let timestamp = fields
+        .next()
+        .and_then(|timestamp| timestamp.strip_prefix('('))
+        .and_then(|timestamp| timestamp.strip_suffix(')'))
+        .ok_or_else(|| String::from("missing timestamp"))?;
+    let timestamp =
+        parse_timestamp(timestamp).ok_or_else(|| format!("invalid timestamp {}", timestamp))?;
+    let channel = fields
+        .next()
+        .ok_or_else(|| String::from("missing channel"))?;
+    let frame = fields.next().ok_or_else(|| String::from("missing frame"))?;
+    let direction = match fields.next() {
+        None => None,
+        Some("T") => Some(Direction::Tx),
+        Some("R") => Some(Direction::Rx),
+        Some(other) => return Err(format!("unknown direction {}", other)),
+    };
+    let (id_str, data_str) = frame
+        .split_once('#')
+        .ok_or_else(|| format!("invalid frame {}", frame))?;
+    let raw_id = u32::from_str_radix(id_str, 16).map_err(|_| format!("invalid id {}", id_str))?;
+
+    let err = raw_id & ERR_FLAG != 0;
+    // Error frames are written with 8 digits for the flag, they aren't extended
+    let mut flags = FrameFlags {
+        extended: id_str.len() > 3 && !err,
+        err,
+        ..FrameFlags::default()
+    };
+    let data = if let Some(len) = data_str.strip_prefix('R') {
+        // The length of a remote frame follows the R, without it the length is 0
+        flags.rtr = true;
+        let len = match len {
+            "" => 0,
+            len => len
+                .parse::<usize>()
+                .ok()
+                .filter(|&len| len <= CAN_MAX_DLEN)
+                .ok_or_else(|| format!("invalid remote frame length in {}", frame))?,
+        };
+        vec![0; len]
+    } else {
+        let data_str = if let Some(fd) = data_str.strip_prefix('#') {
+            let fd_flags =
+                fd.chars()
+                    .next()
+                    .and_then(|c| c.to_digit(16))
+                    .ok_or_else(|| format!("missing FD flags in {}", frame))? as u8;
+            flags.fd = true;
+            flags.brs = fd_flags & CANFD_BRS != 0;
+            flags.esi = fd_flags & CANFD_ESI != 0;
+            &fd[1..]
+        } else {
+            data_str
+        };
+        // Checked first so slicing below can't split a multi-byte character
+        if !data_str.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(format!("invalid data {}", data_str));
+        }
+        if !data_str.len().is_multiple_of(2) {
+            return Err(format!("odd number of hex digits in {}", frame));
+        }
+        (0..data_str.len())
+            .step_by(2)
+            .map(|i| {
+                u8::from_str_radix(&data_str[i..i + 2], 16)
+                    .map_err(|_| format!("invalid data {}", data_str))
+            })
+            .collect::<Result<Vec<u8>, String>>()?
+    };
+    Ok(LoggedFrame {
+        timestamp: Some(timestamp),
+        channel: channel.to_owned(),
+        direction,
+        frame: CanFrame::with_flags(raw_id & EFF_MASK, &data, flags).map_err(|e| e.to_string())?,
+    })
+}
+
+/// Parse `seconds.fraction` into the time since the unix epoch
+fn parse_timestamp(timestamp: &str) -> Option<Duration> {
+    let (secs, fraction) = timestamp.split_once('.').unwrap_or((timestamp, ""));
+    let nanos = if fraction.is_empty() {
+        0
+    } else if fraction.len() <= 9 && fraction.bytes().all(|b| b.is_ascii_digit()) {
+        fraction.parse::<u32>().ok()? * 10u32.pow(9 - fraction.len() as u32)
+    } else {
+        return None;
+    };
+    Some(Duration::new(secs.parse().ok()?, nanos))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg_processor::log_frame;
+    use tempfile::tempdir;
+
+    #[test]
+    fn it_reads_back_listen_logs() {
+        let dir = tempdir().unwrap();
+        let log = dir.path().join("log.txt");
+        let sent = [
+            CanFrame::new(0x601, &[0x40, 0x00, 0x10, 0x00], false, false).unwrap(),
+            CanFrame::new_extended(0x10, &[], false, false).unwrap(),
+        ];
+        let response = CanFrame::new(0x581, &[0x43], false, false).unwrap();
+        log_frame(&log, "TX", "vcan0", &sent[0]).unwrap();
+        log_frame(&log, "RX", "vcan0", &response).unwrap();
+        log_frame(&log, "TX", "vcan1", &sent[1]).unwrap();
+
+        let logged = read_log(&log).unwrap();
+        let frames: Vec<CanFrame> = logged.iter().map(|logged| logged.frame).collect();
+        assert_eq!(frames, [sent[0], response, sent[1]]);
+        assert_eq!(logged[1].direction, Some(Direction::Rx));
+        assert_eq!(logged[2].channel, "vcan1");
+        assert!(matches!(
+            read_log(&dir.path().join("missing.txt")),
+            Err(FuzzerError::LogRead { .. })
+        ));
+
+        fs::write(&log, "TX  [Mon Jan  1 00:00:00 2024]: vcan0 0x12G 01\n").unwrap();
+        assert!(matches!(
+            read_log(&log),
+            Err(FuzzerError::LogParse { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn it_reads_candump_logs() {
+        let dir = tempdir().unwrap();
+        let log = dir.path().join("candump.log");
+        fs::write(
+            &log,
+            "(1436509052.249713) vcan0 044#2A366C2BBA\n\
+             (1436509052.449713) vcan0 0000A001#\n\
+             (1436509052.649713) vcan0 123#R\n\
+             (1436509052.849713) vcan1 321##10011\n",
+        )
+        .unwrap();
+
+        let frames = read_frames(&log).unwrap();
+        assert_eq!(
+            frames[0],
+            CanFrame::new(0x44, &[0x2A, 0x36, 0x6C, 0x2B, 0xBA], false, false).unwrap()
+        );
+        assert_eq!(
+            frames[1],
+            CanFrame::new_extended(0xA001, &[], false, false).unwrap()
+        );
+        assert!(frames[2].is_rtr());
+        assert!(frames[3].is_fd() && frames[3].is_brs() && !frames[3].is_esi());
+        assert_eq!(frames[3].data(), &[0x00, 0x11]);
+
+        assert_eq!(
+            parse_candump_line("(0.0) vcan0 123#ABC"),
+            Err(String::from("odd number of hex digits in 123#ABC"))
+        );
+    }
+
+    #[test]
+    fn it_rejects_malformed_candump_lines() {
+        assert_eq!(
+            parse_candump_line("(0.0) vcan0 123#a\u{e9}b"),
+            Err(String::from("invalid data a\u{e9}b"))
+        );
+        assert!(parse_candump_line("(0.0) vcan0 123##1A\u{e9}").is_err());
+        assert!(parse_candump_line("(0.0) vcan0 123#R9").is_err());
+    }
+}
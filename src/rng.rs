@@ -0,0 +1,11 @@
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+
+/// RNG driving all message generation
+/// ChaCha is used because its output for a given seed is the same on every platform
+pub type FuzzRng = ChaCha8Rng;
+
+/// Returns the generation RNG for a run, the same seed always yields the same frames
+pub fn seeded_rng(seed: u64) -> FuzzRng {
+    FuzzRng::seed_from_u64(seed)
+}
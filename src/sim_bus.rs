@@ -0,0 +1,228 @@
+use crate::frame::CanFrame;
+use crate::rng::{seeded_rng, FuzzRng};
+use crate::transport::CanTransport;
+use rand::Rng;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Behaviour of a simulated bus
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SimBusConfig {
+    /// Time between a frame being sent and it becoming readable by other endpoints
+    pub latency: Duration,
+    /// Probability (0.0 - 1.0) that a sent frame is lost before delivery
+    pub drop_rate: f64,
+    /// Seed deciding which frames get dropped
+    pub seed: u64,
+}
+
+impl Default for SimBusConfig {
+    fn default() -> Self {
+        Self {
+            latency: Duration::from_millis(0),
+            drop_rate: 0.0,
+            seed: 0,
+        }
+    }
+}
+
+/// Receive queue of a single endpoint, frames are paired with their delivery time
+type RxQueue = VecDeque<(Instant, CanFrame)>;
+
+struct BusState {
+    config: SimBusConfig,
+    rng: FuzzRng,
+    // Indexed by endpoint id, None once an endpoint has been closed
+    queues: Vec<Option<RxQueue>>,
+}
+
+/// In-process CAN bus that any number of endpoints can be attached to
+///
+/// Behaves like a vcan interface without loopback: a frame sent by one
+/// endpoint is delivered to every other endpoint on the bus. Cloning a
+/// SimBus gives another handle to the same bus.
+#[derive(Clone)]
+pub struct SimBus {
+    shared: Arc<(Mutex<BusState>, Condvar)>,
+}
+
+impl SimBus {
+    /// Returns a new bus with no endpoints attached
+    pub fn new(config: SimBusConfig) -> Self {
+        Self {
+            shared: Arc::new((
+                Mutex::new(BusState {
+                    config,
+                    rng: seeded_rng(config.seed),
+                    queues: vec![],
+                }),
+                Condvar::new(),
+            )),
+        }
+    }
+
+    /// Attach a new endpoint to the bus, it will receive every frame sent
+    /// by other endpoints from now on
+    pub fn attach(&self, channel: &str) -> SimEndpoint {
+        let mut state = self.lock();
+        state.queues.push(Some(VecDeque::new()));
+        SimEndpoint {
+            bus: self.clone(),
+            id: state.queues.len() - 1,
+            channel: channel.to_owned(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BusState> {
+        // A panicking endpoint thread should not take the whole bus down with it
+        self.shared.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A single node attached to a SimBus
+pub struct SimEndpoint {
+    bus: SimBus,
+    id: usize,
+    channel: String,
+}
+
+impl SimEndpoint {
+    fn closed_error(&self) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::NotConnected,
+            format!("simulated endpoint on {} is closed", self.channel),
+        )
+    }
+}
+
+impl CanTransport for SimEndpoint {
+    fn channel(&self) -> &str {
+        &self.channel
+    }
+
+    fn send_frame(&self, frame: &CanFrame) -> io::Result<()> {
+        let mut state = self.bus.lock();
+        if state.queues[self.id].is_none() {
+            return Err(self.closed_error());
+        }
+
+        let drop_rate = state.config.drop_rate;
+        if drop_rate > 0.0 && state.rng.gen_bool(drop_rate.min(1.0)) {
+            return Ok(());
+        }
+
+        let deliver_at = Instant::now() + state.config.latency;
+        for (id, queue) in state.queues.iter_mut().enumerate() {
+            if id == self.id {
+                continue;
+            }
+            if let Some(queue) = queue {
+                queue.push_back((deliver_at, *frame));
+            }
+        }
+        self.bus.shared.1.notify_all();
+        Ok(())
+    }
+
+    fn recv_frame(&self, timeout: Duration) -> io::Result<Option<CanFrame>> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.bus.lock();
+        loop {
+            let now = Instant::now();
+            let wake_at = match &mut state.queues[self.id] {
+                None => return Err(self.closed_error()),
+                Some(queue) => match queue.front() {
+                    Some((deliver_at, _)) if *deliver_at <= now => {
+                        return Ok(queue.pop_front().map(|(_, frame)| frame));
+                    }
+                    Some((deliver_at, _)) => deadline.min(*deliver_at),
+                    None => deadline,
+                },
+            };
+            if now >= deadline {
+                return Ok(None);
+            }
+            state = self
+                .bus
+                .shared
+                .1
+                .wait_timeout(state, wake_at - now)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.bus.lock().queues[self.id] = None;
+        self.bus.shared.1.notify_all();
+        Ok(())
+    }
+}
+
+impl Drop for SimEndpoint {
+    fn drop(&mut self) {
+        // Stop queueing frames for an endpoint nobody can read from anymore
+        let _ = self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(id: u32) -> CanFrame {
+        CanFrame::new(id, &[1, 2, 3], false, false).unwrap()
+    }
+
+    #[test]
+    fn it_delivers_to_every_other_endpoint() {
+        let bus = SimBus::new(SimBusConfig::default());
+        let a = bus.attach("sim0");
+        let b = bus.attach("sim0");
+        let c = bus.attach("sim0");
+
+        a.send_frame(&frame(0x123)).unwrap();
+
+        let timeout = Duration::from_millis(10);
+        assert_eq!(b.recv_frame(timeout).unwrap().unwrap().id(), 0x123);
+        assert_eq!(c.recv_frame(timeout).unwrap().unwrap().id(), 0x123);
+        // no loopback to the sender
+        assert!(a.recv_frame(timeout).unwrap().is_none());
+    }
+
+    #[test]
+    fn it_applies_latency_and_drop() {
+        let bus = SimBus::new(SimBusConfig {
+            latency: Duration::from_millis(50),
+            drop_rate: 0.0,
+            seed: 0,
+        });
+        let a = bus.attach("sim0");
+        let b = bus.attach("sim0");
+
+        a.send_frame(&frame(0x1)).unwrap();
+        assert!(b.recv_frame(Duration::from_millis(5)).unwrap().is_none());
+        assert!(b.recv_frame(Duration::from_millis(500)).unwrap().is_some());
+
+        let lossy = SimBus::new(SimBusConfig {
+            latency: Duration::from_millis(0),
+            drop_rate: 1.0,
+            seed: 0,
+        });
+        let a = lossy.attach("sim0");
+        let b = lossy.attach("sim0");
+        a.send_frame(&frame(0x1)).unwrap();
+        assert!(b.recv_frame(Duration::from_millis(5)).unwrap().is_none());
+    }
+
+    #[test]
+    fn it_rejects_use_after_close() {
+        let bus = SimBus::new(SimBusConfig::default());
+        let mut a = bus.attach("sim0");
+        a.close().unwrap();
+        assert!(a.send_frame(&frame(0x1)).is_err());
+        assert!(a.recv_frame(Duration::from_millis(1)).is_err());
+    }
+}
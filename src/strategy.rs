@@ -0,0 +1,379 @@
+use crate::bit_writer::PackError;
+use crate::msg_processor::{sub_sec_proc, MsgFormat, SubSec};
+use rand::Rng;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::iter;
+use std::str::FromStr;
+
+/// Fields with at most this many possible values are enumerated by the exhaustive strategy
+pub const EXHAUSTIVE_MAX_VALUES: u64 = 256;
+
+/// How values are chosen for the fields of a message format
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Strategy {
+    /// Uniformly random values, the classic behaviour
+    #[default]
+    Random,
+    /// The ends of each field's range, their neighbours and the values next to holes
+    Boundary,
+    /// Every combination of the values of small fields, wider fields stay random
+    Exhaustive,
+    /// Boundary values combined so every pair of values of any two fields is sent
+    Pairwise,
+}
+
+impl Strategy {
+    pub const NAMES: [&'static str; 4] = ["random", "boundary", "exhaustive", "pairwise"];
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(Strategy::Random),
+            "boundary" => Ok(Strategy::Boundary),
+            "exhaustive" => Ok(Strategy::Exhaustive),
+            "pairwise" => Ok(Strategy::Pairwise),
+            _ => Err(format!("unknown strategy {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Strategy::Random => "random",
+            Strategy::Boundary => "boundary",
+            Strategy::Exhaustive => "exhaustive",
+            Strategy::Pairwise => "pairwise",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Smallest and largest value a field may take, both inclusive
+fn value_range(sub_sec: &SubSec) -> (u64, u64) {
+    match sub_sec.specified_val() {
+        Some(value) => (value, value),
+        None => sub_sec.constraints().range(sub_sec.num_bits() as usize),
+    }
+}
+
+/// The allowed values of a field that aren't holes or weighted 0, in ascending order
+/// None when the field doesn't list its values
+fn allowed_values(sub_sec: &SubSec) -> Option<Vec<u64>> {
+    let constraints = sub_sec.constraints();
+    let allowed = &constraints.allowed_values;
+    if sub_sec.specified_val().is_some() || allowed.is_empty() {
+        return None;
+    }
+    // Missing weights count as 1, like Constraints::pick
+    let weights = constraints.weights.iter().copied().chain(iter::repeat(1));
+    let values: BTreeSet<u64> = allowed
+        .iter()
+        .copied()
+        .zip(weights)
+        .filter(|&(value, weight)| weight > 0 && !sub_sec.holes().contains(&value))
+        .map(|(value, _)| value)
+        .collect();
+    Some(values.into_iter().collect())
+}
+
+/// Boundary values of a field, in ascending order: min, min + 1, max - 1, max
+/// and the values either side of each hole, leaving out the holes themselves.
+/// For fields listing their allowed values these are the two smallest and largest
+pub fn boundary_values(sub_sec: &SubSec) -> Vec<u64> {
+    if let Some(allowed) = allowed_values(sub_sec) {
+        let edge = allowed.len().min(2);
+        let values: BTreeSet<u64> = allowed[..edge]
+            .iter()
+            .chain(&allowed[allowed.len() - edge..])
+            .copied()
+            .collect();
+        return values.into_iter().collect();
+    }
+
+    let (min, max) = value_range(sub_sec);
+    let mut candidates = vec![min, min.saturating_add(1), max.saturating_sub(1), max];
+    for &hole in sub_sec.holes() {
+        candidates.push(hole.saturating_sub(1));
+        candidates.push(hole.saturating_add(1));
+    }
+    let values: BTreeSet<u64> = candidates
+        .into_iter()
+        .filter(|value| (min..=max).contains(value) && !sub_sec.holes().contains(value))
+        .collect();
+    values.into_iter().collect()
+}
+
+/// Every value a field can take in ascending order
+/// None when there are more than EXHAUSTIVE_MAX_VALUES of them
+pub fn all_values(sub_sec: &SubSec) -> Option<Vec<u64>> {
+    if let Some(allowed) = allowed_values(sub_sec) {
+        return Some(allowed).filter(|values| values.len() as u64 <= EXHAUSTIVE_MAX_VALUES);
+    }
+    let (min, max) = value_range(sub_sec);
+    if max < min || max - min >= EXHAUSTIVE_MAX_VALUES {
+        return None;
+    }
+    Some(
+        (min..=max)
+            .filter(|value| !sub_sec.holes().contains(value))
+            .collect(),
+    )
+}
+
+/// Rows of indices into `sets`, one per set, such that every pair of values
+/// of any two sets appears together in at least one row
+///
+/// Rows are built greedily: each starts from the first pair not yet covered
+/// and fills the other sets with the value covering the most new pairs.
+pub fn pairwise(sets: &[Vec<u64>]) -> Vec<Vec<usize>> {
+    let sizes: Vec<usize> = sets.iter().map(|set| set.len().max(1)).collect();
+    if sizes.len() < 2 {
+        return (0..sizes.first().copied().unwrap_or(1))
+            .map(|value| vec![value; sizes.len()])
+            .collect();
+    }
+
+    let mut uncovered = BTreeSet::new();
+    for i in 0..sizes.len() {
+        for j in i + 1..sizes.len() {
+            for a in 0..sizes[i] {
+                for b in 0..sizes[j] {
+                    uncovered.insert((i, a, j, b));
+                }
+            }
+        }
+    }
+
+    let mut rows = vec![];
+    while let Some(&(i, a, j, b)) = uncovered.iter().next() {
+        let mut row: Vec<Option<usize>> = vec![None; sizes.len()];
+        row[i] = Some(a);
+        row[j] = Some(b);
+        for field in 0..sizes.len() {
+            if row[field].is_some() {
+                continue;
+            }
+            let gain = |value: usize| {
+                row.iter()
+                    .enumerate()
+                    .filter_map(|(other, chosen)| chosen.map(|chosen| (other, chosen)))
+                    .filter(|&(other, chosen)| {
+                        let pair = if other < field {
+                            (other, chosen, field, value)
+                        } else {
+                            (field, value, other, chosen)
+                        };
+                        uncovered.contains(&pair)
+                    })
+                    .count()
+            };
+            // Ties go to the smallest value so the rows don't depend on anything but the sets
+            let best = (0..sizes[field])
+                .max_by_key(|&value| (gain(value), std::cmp::Reverse(value)))
+                .unwrap_or(0);
+            row[field] = Some(best);
+        }
+
+        let row: Vec<usize> = row.into_iter().map(|value| value.unwrap_or(0)).collect();
+        for i in 0..row.len() {
+            for j in i + 1..row.len() {
+                uncovered.remove(&(i, row[i], j, row[j]));
+            }
+        }
+        rows.push(row);
+    }
+    rows
+}
+
+/// Values planned for one format, and how many messages of it were generated
+struct Plan {
+    /// Values each field cycles through, None for fields left random
+    sets: Vec<Option<Vec<u64>>>,
+    /// Pairwise rows of indices into sets
+    rows: Vec<Vec<usize>>,
+    next: usize,
+}
+
+/// Chooses field values for messages of a list of formats
+///
+/// Systematic strategies walk through their values in order, every format
+/// keeps its own position so the formats are covered independently.
+pub struct Generator {
+    strategy: Strategy,
+    plans: Vec<Plan>,
+}
+
+impl Generator {
+    pub fn new(strategy: Strategy, formats: &[MsgFormat]) -> Self {
+        let plans = formats
+            .iter()
+            .map(|format| {
+                let fields = format.fields();
+                let sets: Vec<Option<Vec<u64>>> = match strategy {
+                    Strategy::Random => vec![None; fields.len()],
+                    Strategy::Boundary | Strategy::Pairwise => fields
+                        .iter()
+                        .map(|sub_sec| Some(boundary_values(sub_sec)))
+                        .collect(),
+                    Strategy::Exhaustive => {
+                        fields.iter().map(|sub_sec| all_values(sub_sec)).collect()
+                    }
+                };
+                let rows = if strategy == Strategy::Pairwise {
+                    let sets: Vec<Vec<u64>> = sets.iter().flatten().cloned().collect();
+                    pairwise(&sets)
+                } else {
+                    vec![]
+                };
+                Plan {
+                    sets,
+                    rows,
+                    next: 0,
+                }
+            })
+            .collect();
+        Self { strategy, plans }
+    }
+
+    /// Values for the next message of the `index`th format, see MsgFormat::fields
+    /// Once every planned value was sent the plan starts over
+    pub fn values<R: Rng + ?Sized>(
+        &mut self,
+        index: usize,
+        format: &MsgFormat,
+        rng: &mut R,
+    ) -> Result<Vec<u64>, PackError> {
+        let plan = &mut self.plans[index];
+        let step = plan.next;
+        plan.next = plan.next.wrapping_add(1);
+
+        let row = plan.rows.get(step % plan.rows.len().max(1));
+        // Exhaustive steps count in mixed radix, the first field changing fastest
+        let mut rest = step;
+        let mut values = Vec::with_capacity(plan.sets.len());
+        for (i, (sub_sec, set)) in format.fields().into_iter().zip(&plan.sets).enumerate() {
+            let value = match (self.strategy, set) {
+                (_, Some(set)) if set.is_empty() => sub_sec_proc(sub_sec, rng)?,
+                (Strategy::Pairwise, Some(set)) => set[row.map_or(0, |row| row[i])],
+                (Strategy::Exhaustive, Some(set)) => {
+                    let value = set[rest % set.len()];
+                    rest /= set.len();
+                    value
+                }
+                (_, Some(set)) => set[step % set.len()],
+                (_, None) => sub_sec_proc(sub_sec, rng)?,
+            };
+            values.push(value);
+        }
+        Ok(values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg_processor::Section;
+    use crate::rng::seeded_rng;
+    use std::collections::HashSet;
+
+    fn format(sub_secs: Vec<SubSec>) -> MsgFormat {
+        let bits: usize = sub_secs.iter().map(|s| s.num_bits() as usize).sum();
+        MsgFormat::new(
+            String::from("Test"),
+            0x100..0x101,
+            vec![],
+            1,
+            vec![Section::new(
+                String::from("A"),
+                (bits / 8) as u8,
+                sub_secs,
+                false,
+                0,
+            )],
+            false,
+            0,
+        )
+    }
+
+    #[test]
+    fn it_finds_boundary_values() {
+        let plain = SubSec::new(String::from("A"), 8, vec![], false, 0);
+        assert_eq!(boundary_values(&plain), vec![0, 1, 254, 255]);
+        let ranged = SubSec::new(String::from("B"), 8, vec![10, 20], false, 0)
+            .with_range(Some(10), Some(30));
+        assert_eq!(boundary_values(&ranged), vec![11, 19, 21, 29, 30]);
+        let fixed = SubSec::new(String::from("C"), 4, vec![], true, 3);
+        assert_eq!(boundary_values(&fixed), vec![3]);
+        let pair = SubSec::new(String::from("D"), 8, vec![], false, 0)
+            .with_allowed_values(vec![7, 3], vec![]);
+        assert_eq!(boundary_values(&pair), vec![3, 7]);
+        // Values weighted 0 are never picked, so they aren't boundaries either
+        let weighted = SubSec::new(String::from("E"), 8, vec![], false, 0)
+            .with_allowed_values(vec![1, 2, 3, 4, 5], vec![0, 1, 1, 1, 0]);
+        assert_eq!(boundary_values(&weighted), vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn it_enumerates_small_fields() {
+        let format = format(vec![
+            SubSec::new(String::from("A"), 2, vec![1], false, 0),
+            SubSec::new(String::from("B"), 2, vec![], false, 0),
+            SubSec::new(String::from("C"), 4, vec![], true, 9),
+        ]);
+        let mut generator = Generator::new(Strategy::Exhaustive, std::slice::from_ref(&format));
+        let mut rng = seeded_rng(0);
+        let sent: Vec<Vec<u64>> = (0..13)
+            .map(|_| generator.values(0, &format, &mut rng).unwrap())
+            .collect();
+        let seen: HashSet<&Vec<u64>> = sent[..12].iter().collect();
+        assert_eq!(seen.len(), 12);
+        // Then it starts over
+        assert_eq!(sent[12], sent[0]);
+        assert!(seen.iter().all(|values| values[0] != 1 && values[2] == 9));
+    }
+
+    #[test]
+    fn it_covers_every_pair() {
+        let sets = vec![vec![0, 1, 2], vec![0, 1, 2], vec![0, 1], vec![0, 1, 2, 3]];
+        let rows = pairwise(&sets);
+        // Far fewer than the 72 combinations, but never fewer than the largest pair product
+        assert!(rows.len() >= 12 && rows.len() < 72);
+        for i in 0..sets.len() {
+            for j in i + 1..sets.len() {
+                for a in 0..sets[i].len() {
+                    for b in 0..sets[j].len() {
+                        assert!(rows.iter().any(|row| row[i] == a && row[j] == b));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn it_cycles_through_boundary_values() {
+        let format = format(vec![
+            SubSec::new(String::from("A"), 8, vec![], false, 0),
+            SubSec::new(String::from("B"), 8, vec![], false, 0).with_range(Some(5), Some(6)),
+        ]);
+        let mut generator = Generator::new(Strategy::Boundary, std::slice::from_ref(&format));
+        let mut rng = seeded_rng(0);
+        let values: Vec<Vec<u64>> = (0..5)
+            .map(|_| generator.values(0, &format, &mut rng).unwrap())
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                vec![0, 5],
+                vec![1, 6],
+                vec![254, 5],
+                vec![255, 6],
+                vec![0, 5]
+            ]
+        );
+    }
+}
@@ -0,0 +1,208 @@
+use crate::frame::CanFrame;
+use socketcan::{CANSocket, CANSocketOpenError};
+use std::io;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+/// A CAN backend that frames can be sent on and received from
+///
+/// Message generation and listen mode only talk to the bus through this
+/// trait, so any backend (SocketCAN, a simulated bus, a test double) can be
+/// plugged in
+pub trait CanTransport {
+    /// Name of the channel this transport is attached to
+    fn channel(&self) -> &str;
+
+    /// Send a single frame on the bus
+    fn send_frame(&self, frame: &CanFrame) -> io::Result<()>;
+
+    /// Block for up to `timeout` waiting for a frame
+    /// Returns Ok(None) when the timeout expires without a frame
+    fn recv_frame(&self, timeout: Duration) -> io::Result<Option<CanFrame>>;
+
+    /// Release the underlying resources, further sends and receives will fail
+    fn close(&mut self) -> io::Result<()>;
+}
+
+// Raw socket option enabling CAN FD frames, from linux/can.h and linux/can/raw.h
+const SOL_CAN_RAW: libc::c_int = 101;
+const CAN_RAW_FD_FRAMES: libc::c_int = 5;
+/// Size of a classic kernel can_frame
+const CAN_MTU: usize = 16;
+/// Size of a kernel canfd_frame
+const CANFD_MTU: usize = 72;
+
+/// Kernel struct canfd_frame from linux/can.h
+/// The first CAN_MTU bytes have the same layout as a classic can_frame
+#[repr(C)]
+struct RawCanFdFrame {
+    can_id: u32,
+    len: u8,
+    flags: u8,
+    res0: u8,
+    res1: u8,
+    data: [u8; 64],
+}
+
+impl RawCanFdFrame {
+    fn from_frame(frame: &CanFrame) -> Self {
+        let mut raw = Self {
+            can_id: frame.raw_id(),
+            len: frame.data().len() as u8,
+            flags: frame.fd_flags(),
+            res0: 0,
+            res1: 0,
+            data: [0; 64],
+        };
+        raw.data[..frame.data().len()].copy_from_slice(frame.data());
+        raw
+    }
+
+    fn to_frame(&self, mtu: usize) -> io::Result<CanFrame> {
+        let data = &self.data[..(self.len as usize).min(self.data.len())];
+        let frame = if mtu == CANFD_MTU {
+            CanFrame::from_raw_fd(self.can_id, self.flags, data)
+        } else {
+            CanFrame::from_raw(self.can_id, data)
+        };
+        frame.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// SocketCAN backed transport using a raw CAN socket
+pub struct SocketCanTransport {
+    socket: Option<CANSocket>,
+    channel: String,
+    fd: bool,
+}
+
+impl SocketCanTransport {
+    /// Open a raw CAN socket on the given interface, e.g. "vcan0"
+    /// With `error_frames` error frames are received too, they report bus faults such
+    /// as bus-off
+    pub fn open(channel: &str, error_frames: bool) -> Result<Self, CANSocketOpenError> {
+        let socket = CANSocket::open(channel)?;
+        if error_frames {
+            socket.error_filter_accept_all()?;
+        }
+        Ok(Self {
+            socket: Some(socket),
+            channel: channel.to_owned(),
+            fd: false,
+        })
+    }
+
+    /// Open a raw CAN socket that can send and receive CAN FD frames
+    /// The interface itself needs an MTU of 72 to pass FD frames
+    pub fn open_fd(channel: &str, error_frames: bool) -> Result<Self, CANSocketOpenError> {
+        let transport = Self::open(channel, error_frames)?;
+        let enable: libc::c_int = 1;
+        let rv = unsafe {
+            libc::setsockopt(
+                transport.socket()?.as_raw_fd(),
+                SOL_CAN_RAW,
+                CAN_RAW_FD_FRAMES,
+                &enable as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if rv < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(Self {
+            fd: true,
+            ..transport
+        })
+    }
+
+    fn socket(&self) -> io::Result<&CANSocket> {
+        self.socket.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!("socket for {} is closed", self.channel),
+            )
+        })
+    }
+}
+
+impl CanTransport for SocketCanTransport {
+    fn channel(&self) -> &str {
+        &self.channel
+    }
+
+    fn send_frame(&self, frame: &CanFrame) -> io::Result<()> {
+        // Written as a raw kernel struct as socketcan::CANFrame can't mark
+        // ids below 0x800 as extended or carry FD payloads
+        if frame.is_fd() && !self.fd {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("socket for {} was not opened for CAN FD", self.channel),
+            ));
+        }
+        let mtu = if frame.is_fd() { CANFD_MTU } else { CAN_MTU };
+        let raw = RawCanFdFrame::from_frame(frame);
+        let written = unsafe {
+            libc::write(
+                self.socket()?.as_raw_fd(),
+                &raw as *const RawCanFdFrame as *const libc::c_void,
+                mtu,
+            )
+        };
+        if written < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if written as usize != mtu {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                format!(
+                    "short write of {} of {} bytes to {}",
+                    written, mtu, self.channel
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    fn recv_frame(&self, timeout: Duration) -> io::Result<Option<CanFrame>> {
+        let socket = self.socket()?;
+        socket.set_read_timeout(timeout)?;
+
+        let mut raw = RawCanFdFrame {
+            can_id: 0,
+            len: 0,
+            flags: 0,
+            res0: 0,
+            res1: 0,
+            data: [0; 64],
+        };
+        let read = unsafe {
+            libc::read(
+                socket.as_raw_fd(),
+                &mut raw as *mut RawCanFdFrame as *mut libc::c_void,
+                mem::size_of::<RawCanFdFrame>(),
+            )
+        };
+        if read < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut {
+                return Ok(None);
+            }
+            return Err(e);
+        }
+        // An FD socket hands out both frame types, told apart by size
+        match read as usize {
+            CAN_MTU | CANFD_MTU => raw.to_frame(read as usize).map(Some),
+            n => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("short read of {} bytes from {}", n, self.channel),
+            )),
+        }
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        // CANSocket closes its file descriptor on drop
+        self.socket.take();
+        Ok(())
+    }
+}
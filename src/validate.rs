@@ -0,0 +1,499 @@
+use crate::frame::{CANFD_MAX_DLEN, CAN_MAX_DLEN, EFF_MASK, SFF_MASK};
+use crate::msg_processor::{
+    read_format_file, ByteOrder, Constraints, ImportOptions, MsgFormat, SubSec,
+};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::iter;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// A single problem found in a message format file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Issue {
+    /// File the format was read from
+    pub file: PathBuf,
+    /// JSON path of the offending value, e.g. sections[1].sub_secs[0].holes[2]
+    /// Empty when the whole file is affected
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}: {}", self.file.display(), self.message)
+        } else {
+            write!(
+                f,
+                "{}: {}: {}",
+                self.file.display(),
+                self.path,
+                self.message
+            )
+        }
+    }
+}
+
+/// Returned when loading formats that failed validation, holds every issue found
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{} problem(s) in message formats{}", .issues.len(), issue_lines(.issues))]
+pub struct ValidationError {
+    pub issues: Vec<Issue>,
+}
+
+/// Each issue on its own indented line
+fn issue_lines(issues: &[Issue]) -> String {
+    issues
+        .iter()
+        .map(|issue| format!("\n  {}", issue))
+        .collect()
+}
+
+/// Check a message format for values that can't be generated or packed
+/// Returns every issue found, an empty list means the format is valid
+pub fn validate(format: &MsgFormat, file: &Path) -> Vec<Issue> {
+    let mut issues = vec![];
+    let mut report = |path: String, message: String| {
+        issues.push(Issue {
+            file: file.to_owned(),
+            path,
+            message,
+        })
+    };
+
+    let flags = format.frame_flags();
+    let sections = format.sections();
+    if format.num_sections() as usize != sections.len() {
+        report(
+            String::from("num_sections"),
+            format!(
+                "is {} but {} sections are defined",
+                format.num_sections(),
+                sections.len()
+            ),
+        );
+    }
+
+    // Ids
+    let max_id = if flags.extended { EFF_MASK } else { SFF_MASK };
+    let range = format.cob_id_range();
+    if format.cob_id_values().is_empty() && range.start >= range.end {
+        report(
+            String::from("cob_id_range"),
+            format!(
+                "{}..{} is empty and no cob_id_values are given",
+                range.start, range.end
+            ),
+        );
+    } else if format.cob_id_values().is_empty() && range.end - 1 > max_id {
+        report(
+            String::from("cob_id_range"),
+            format!(
+                "ends above the largest {} id 0x{:X}",
+                id_kind(flags.extended),
+                max_id
+            ),
+        );
+    }
+    for (i, &id) in format.cob_id_values().iter().enumerate() {
+        if id > max_id {
+            report(
+                format!("cob_id_values[{}]", i),
+                format!(
+                    "0x{:X} is above the largest {} id 0x{:X}",
+                    id,
+                    id_kind(flags.extended),
+                    max_id
+                ),
+            );
+        }
+    }
+
+    // Frame
+    if !flags.fd && (flags.brs || flags.esi) {
+        report(
+            String::from(if flags.brs { "brs" } else { "esi" }),
+            String::from("needs \"fd\": true"),
+        );
+    }
+    let max_len = if flags.fd {
+        CANFD_MAX_DLEN
+    } else {
+        CAN_MAX_DLEN
+    };
+    let total_bytes: usize = sections.iter().map(|s| s.num_bytes() as usize).sum();
+    if total_bytes > max_len {
+        report(
+            String::from("sections"),
+            format!(
+                "add up to {} bytes but a {} frame holds {}",
+                total_bytes,
+                if flags.fd { "CAN FD" } else { "classic CAN" },
+                max_len
+            ),
+        );
+    }
+
+    // Sections
+    for (i, section) in sections.iter().enumerate() {
+        let path = format!("sections[{}]", i);
+        let width = section.num_bytes() as usize * 8;
+        if section.num_bytes() == 0 {
+            report(format!("{}.num_bytes", path), String::from("is 0"));
+        }
+        if let Some(value) = section.specified_val() {
+            if !fits(value, width) {
+                report(
+                    format!("{}.specified_val", path),
+                    format!("{} does not fit in {} bits", value, width),
+                );
+            }
+        }
+
+        let constraints = section.constraints();
+        if *constraints != Constraints::default() && section.specified_val().is_none() {
+            if !section.sub_secs().is_empty() {
+                report(
+                    path.clone(),
+                    String::from(
+                        "min, max, allowed_values and weights only apply to sections \
+                         without sub_secs",
+                    ),
+                );
+            } else if width > 64 {
+                report(
+                    format!("{}.num_bytes", path),
+                    format!("{} bits is too wide for min, max or allowed_values", width),
+                );
+            } else {
+                check_constraints(&mut report, &path, constraints, width);
+            }
+        }
+
+        let sub_sec_bits: usize = section
+            .sub_secs()
+            .iter()
+            .map(|sub_sec| sub_sec.num_bits() as usize)
+            .sum();
+        if !section.sub_secs().is_empty() && sub_sec_bits != width {
+            report(
+                format!("{}.sub_secs", path),
+                format!(
+                    "add up to {} bits but num_bytes {} is {} bits",
+                    sub_sec_bits,
+                    section.num_bytes(),
+                    width
+                ),
+            );
+        }
+
+        let offsets = section.sub_sec_offsets();
+        for (j, sub_sec) in section.sub_secs().iter().enumerate() {
+            let path = format!("{}.sub_secs[{}]", path, j);
+            let bits = sub_sec.num_bits() as usize;
+            if bits > 64 {
+                report(
+                    format!("{}.num_bits", path),
+                    format!("{} bits is wider than the 64 bit limit", bits),
+                );
+            }
+            if let Some(value) = sub_sec.specified_val() {
+                if !fits(value, bits) {
+                    report(
+                        format!("{}.specified_val", path),
+                        format!("{} does not fit in {} bits", value, bits),
+                    );
+                }
+            }
+            if sub_sec.specified_val().is_none() {
+                check_constraints(&mut report, &path, sub_sec.constraints(), bits);
+            }
+            for (k, &hole) in sub_sec.holes().iter().enumerate() {
+                if !fits(hole, bits) {
+                    report(
+                        format!("{}.holes[{}]", path, k),
+                        format!("{} is out of range for {} bits", hole, bits),
+                    );
+                }
+            }
+            if sub_sec.specified_val().is_none() && bits > 0 && no_values_left(sub_sec, bits) {
+                report(
+                    format!("{}.holes", path),
+                    String::from("leave no value that can be generated"),
+                );
+            }
+            if let Some(order) = sub_sec.byte_order() {
+                if order != section.byte_order() && bits > 8 && !bits.is_multiple_of(8) {
+                    report(
+                        format!("{}.byte_order", path),
+                        format!(
+                            "{} differs from the section but {} bits isn't whole bytes",
+                            order_name(order),
+                            bits
+                        ),
+                    );
+                } else if order != section.byte_order() && bits > 8 && !offsets[j].is_multiple_of(8)
+                {
+                    report(
+                        format!("{}.byte_order", path),
+                        format!(
+                            "{} differs from the section but the sub section starts at \
+                             bit {}, not on a byte boundary",
+                            order_name(order),
+                            offsets[j]
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    issues
+}
+
+/// Validate every format read from a single file
+/// Files describing several formats (e.g. DBC) prefix each path with the format name
+pub fn validate_file(formats: &[MsgFormat], file: &Path) -> Vec<Issue> {
+    let mut issues = vec![];
+    for format in formats {
+        let mut format_issues = validate(format, file);
+        if formats.len() > 1 {
+            for issue in &mut format_issues {
+                issue.path = if issue.path.is_empty() {
+                    format.name().to_owned()
+                } else {
+                    format!("{}.{}", format.name(), issue.path)
+                };
+            }
+        }
+        issues.append(&mut format_issues);
+    }
+    issues
+}
+
+/// Read and validate every format file in a file or directory
+/// Files that can't be read or parsed are reported as issues too
+pub fn validate_path(path: &Path, options: &ImportOptions) -> io::Result<Vec<Issue>> {
+    if !path.is_dir() {
+        return Ok(match read_format_file(path, options) {
+            Ok(formats) => validate_file(&formats, path),
+            Err(e) => vec![Issue {
+                file: path.to_owned(),
+                path: String::new(),
+                message: e.to_string(),
+            }],
+        });
+    }
+
+    let mut issues = vec![];
+    for entry in fs::read_dir(path)? {
+        issues.append(&mut validate_path(&entry?.path(), options)?);
+    }
+    Ok(issues)
+}
+
+/// Check the min, max, allowed_values and weights of a `bits` wide field at `path`
+fn check_constraints(
+    report: &mut impl FnMut(String, String),
+    path: &str,
+    constraints: &Constraints,
+    bits: usize,
+) {
+    if let Some(max) = constraints.max {
+        if !fits(max, bits) {
+            report(
+                format!("{}.max", path),
+                format!("{} does not fit in {} bits", max, bits),
+            );
+        }
+    }
+    if let (Some(min), Some(max)) = (constraints.min, constraints.max) {
+        if min > max {
+            report(
+                format!("{}.min", path),
+                format!("{} is larger than max {}", min, max),
+            );
+        }
+    } else if let Some(min) = constraints.min {
+        if !fits(min, bits) {
+            report(
+                format!("{}.min", path),
+                format!("{} does not fit in {} bits", min, bits),
+            );
+        }
+    }
+    for (i, &value) in constraints.allowed_values.iter().enumerate() {
+        if !fits(value, bits) {
+            report(
+                format!("{}.allowed_values[{}]", path, i),
+                format!("{} does not fit in {} bits", value, bits),
+            );
+        }
+    }
+    if constraints.weights.is_empty() {
+        return;
+    }
+    if constraints.weights.len() != constraints.allowed_values.len() {
+        report(
+            format!("{}.weights", path),
+            format!(
+                "has {} entries but allowed_values has {}",
+                constraints.weights.len(),
+                constraints.allowed_values.len()
+            ),
+        );
+    } else if constraints.weights.iter().all(|&weight| weight == 0) {
+        report(format!("{}.weights", path), String::from("are all 0"));
+    }
+}
+
+/// Returns true when the holes of a sub section cover every value it may take
+fn no_values_left(sub_sec: &SubSec, bits: usize) -> bool {
+    let constraints = sub_sec.constraints();
+    if !constraints.allowed_values.is_empty() {
+        // Values weighted 0 are never picked, all weights being 0 is reported on its own
+        if constraints.weights.iter().all(|&weight| weight == 0) && !constraints.weights.is_empty()
+        {
+            return false;
+        }
+        let weights = constraints.weights.iter().copied().chain(iter::repeat(1));
+        return constraints
+            .allowed_values
+            .iter()
+            .zip(weights)
+            .all(|(value, weight)| weight == 0 || sub_sec.holes().contains(value));
+    }
+    let (min, max) = constraints.range(bits);
+    let holes: BTreeSet<u64> = sub_sec
+        .holes()
+        .iter()
+        .copied()
+        .filter(|hole| (min..=max).contains(hole))
+        .collect();
+    min <= max && holes.len() as u128 > (max - min) as u128
+}
+
+fn fits(value: u64, width: usize) -> bool {
+    width >= 64 || value >> width == 0
+}
+
+fn id_kind(extended: bool) -> &'static str {
+    if extended {
+        "29-bit"
+    } else {
+        "11-bit"
+    }
+}
+
+fn order_name(order: ByteOrder) -> &'static str {
+    match order {
+        ByteOrder::BigEndian => "big_endian",
+        ByteOrder::LittleEndian => "little_endian",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg_processor::Section;
+    use std::ops::Range;
+    use tempfile::tempdir;
+
+    fn paths(issues: &[Issue]) -> Vec<&str> {
+        issues.iter().map(|issue| issue.path.as_str()).collect()
+    }
+
+    #[test]
+    fn it_accepts_the_bundled_assets() {
+        assert_eq!(
+            validate_path(Path::new("assets"), &ImportOptions::default()).unwrap(),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn it_reports_every_problem_with_its_path() {
+        let format = MsgFormat::new(
+            String::from("Broken"),
+            Range { start: 0, end: 0 },
+            vec![],
+            3,
+            vec![
+                Section::new(
+                    String::from("A"),
+                    2,
+                    vec![
+                        SubSec::new(String::from("A1"), 4, vec![3, 16], true, 0x1F),
+                        SubSec::new(String::from("A2"), 4, vec![], false, 0),
+                    ],
+                    false,
+                    0,
+                ),
+                Section::new(String::from("B"), 7, vec![], true, 1 << 56),
+            ],
+            false,
+            0,
+        );
+        let issues = validate(&format, Path::new("broken.json"));
+        assert_eq!(
+            paths(&issues),
+            vec![
+                "num_sections",
+                "cob_id_range",
+                "sections",
+                "sections[0].sub_secs",
+                "sections[0].sub_secs[0].specified_val",
+                "sections[0].sub_secs[0].holes[1]",
+                "sections[1].specified_val",
+            ]
+        );
+        assert_eq!(
+            issues[0].to_string(),
+            "broken.json: num_sections: is 3 but 2 sections are defined"
+        );
+    }
+
+    #[test]
+    fn it_reports_byte_orders_off_byte_boundaries() {
+        let format = MsgFormat::new(
+            String::from("Misaligned"),
+            Range {
+                start: 0x80,
+                end: 0x81,
+            },
+            vec![],
+            1,
+            vec![Section::new(
+                String::from("Signals"),
+                3,
+                vec![
+                    SubSec::new(String::from("A"), 4, vec![], true, 0),
+                    SubSec::new(String::from("B"), 16, vec![], true, 0x1234)
+                        .with_byte_order(ByteOrder::BigEndian),
+                    SubSec::new(String::from("C"), 4, vec![], true, 0),
+                ],
+                false,
+                0,
+            )
+            .with_byte_order(ByteOrder::LittleEndian)],
+            false,
+            0,
+        );
+        let issues = validate(&format, Path::new("misaligned.json"));
+        assert_eq!(paths(&issues), vec!["sections[0].sub_secs[1].byte_order"]);
+        assert!(issues[0].to_string().contains("starts at bit 4"));
+    }
+
+    #[test]
+    fn it_reports_unparsable_files() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("bad.json"), "{ \"name\": ").unwrap();
+        let issues = validate_path(dir.path(), &ImportOptions::default()).unwrap();
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].path, "");
+        assert!(issues[0].file.ends_with("bad.json"));
+    }
+}